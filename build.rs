@@ -2,6 +2,20 @@ use clap_complete::{generate_to, shells::Zsh};
 use std::env;
 use std::io::Error;
 
+// cli.rs parses page ranges and name templates so the modules have to come along with it
+#[allow(dead_code)]
+#[path = "src/name_template.rs"]
+mod name_template;
+#[allow(dead_code)]
+#[path = "src/page_range.rs"]
+mod page_range;
+
+// Page labels also read and write pdf number trees, which needs lopdf. Completions only
+// need the argument, so a plain string stands in for them here
+mod page_label {
+    pub type PageLabels = String;
+}
+
 include!("src/cli.rs");
 
 fn main() -> Result<(), Error> {
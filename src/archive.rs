@@ -0,0 +1,436 @@
+use crate::error::PDFConError;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use lopdf::{Dictionary, Object};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::Path;
+
+/// Archive types pack can read images from and unpack can write them to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// .zip and .cbz
+    Zip,
+    /// .tar and .cbt
+    Tar,
+    /// .tar.gz and .tgz
+    TarGz,
+}
+
+impl ArchiveFormat {
+    /// Pick the format from a file name. Paths that aren't archives give None
+    pub fn from_path(path: &Path) -> Result<Option<Self>, PDFConError> {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        let format = match name.rsplit_once('.').map(|(_, ext)| ext) {
+            Some("zip" | "cbz") => Self::Zip,
+            Some("tar" | "cbt") => Self::Tar,
+            Some("tgz") => Self::TarGz,
+            Some("gz") if name.ends_with(".tar.gz") => Self::TarGz,
+            Some(ext @ ("rar" | "cbr" | "7z" | "cb7")) => {
+                return Err(PDFConError::UnsupportedArchive(ext.to_string()));
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(format))
+    }
+}
+
+/// A file read out of an archive
+pub struct ArchiveEntry {
+    /// Path of the entry inside the archive
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+/// Names of formats that are already compressed and gain nothing from deflating again
+const COMPRESSED_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// Leftovers from macOS and hidden files are never pages
+fn is_junk(name: &str) -> bool {
+    name.split('/')
+        .any(|part| part == "__MACOSX" || part.starts_with('.'))
+}
+
+/// Read every file in an archive that `wanted` accepts by name, sorted in natural order
+/// so 2.jpg comes before 10.jpg. Entries are kept in memory and never written to disk
+pub fn read_entries(
+    path: &Path,
+    format: ArchiveFormat,
+    wanted: impl Fn(&str) -> bool,
+) -> Result<Vec<ArchiveEntry>, PDFConError> {
+    read_entries_from(std::fs::File::open(path)?, format, wanted)
+}
+
+/// [`read_entries`] for an archive that is already open
+fn read_entries_from(
+    reader: impl Read + Seek,
+    format: ArchiveFormat,
+    wanted: impl Fn(&str) -> bool,
+) -> Result<Vec<ArchiveEntry>, PDFConError> {
+    let mut entries = Vec::new();
+
+    match format {
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(reader)?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i)?;
+                let name = entry.name().to_string();
+                if !entry.is_file() || is_junk(&name) || !wanted(&name) {
+                    continue;
+                }
+                let mut contents = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut contents)?;
+                entries.push(ArchiveEntry { name, contents });
+            }
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let reader: Box<dyn Read + '_> = match format {
+                ArchiveFormat::TarGz => Box::new(GzDecoder::new(reader)),
+                _ => Box::new(reader),
+            };
+            let mut archive = tar::Archive::new(reader);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                let name = entry.path()?.to_string_lossy().into_owned();
+                if !entry.header().entry_type().is_file() || is_junk(&name) || !wanted(&name) {
+                    continue;
+                }
+                let mut contents = Vec::with_capacity(entry.size() as usize);
+                entry.read_to_end(&mut contents)?;
+                entries.push(ArchiveEntry { name, contents });
+            }
+        }
+    }
+
+    entries.sort_by(|a, b| natord::compare(&a.name, &b.name));
+    Ok(entries)
+}
+
+enum ArchiveWriterInner {
+    Zip(zip::ZipWriter<BufWriter<File>>),
+    Tar(tar::Builder<BufWriter<File>>),
+    TarGz(tar::Builder<GzEncoder<BufWriter<File>>>),
+}
+
+/// Adds files to a new archive one at a time in the order they should be read
+pub struct ArchiveWriter {
+    inner: ArchiveWriterInner,
+    count: usize,
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &Path, format: ArchiveFormat) -> Result<Self, PDFConError> {
+        let file = BufWriter::new(File::create(path)?);
+        let inner = match format {
+            ArchiveFormat::Zip => ArchiveWriterInner::Zip(zip::ZipWriter::new(file)),
+            ArchiveFormat::Tar => ArchiveWriterInner::Tar(tar::Builder::new(file)),
+            ArchiveFormat::TarGz => ArchiveWriterInner::TarGz(tar::Builder::new(GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            ))),
+        };
+        Ok(Self { inner, count: 0 })
+    }
+
+    /// Number of files added so far
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn add(&mut self, name: &str, contents: &[u8]) -> Result<(), PDFConError> {
+        match &mut self.inner {
+            ArchiveWriterInner::Zip(zip) => {
+                let extension = name
+                    .rsplit_once('.')
+                    .map(|(_, ext)| ext.to_ascii_lowercase());
+                // Images are stored as they are. Only text like ComicInfo.xml is deflated
+                let method = match extension {
+                    Some(ext) if COMPRESSED_EXTENSIONS.contains(&ext.as_str()) => {
+                        zip::CompressionMethod::Stored
+                    }
+                    _ => zip::CompressionMethod::Deflated,
+                };
+                let options = zip::write::SimpleFileOptions::default().compression_method(method);
+                zip.start_file(name, options)?;
+                zip.write_all(contents)?;
+            }
+            ArchiveWriterInner::Tar(tar) => append_tar(tar, name, contents)?,
+            ArchiveWriterInner::TarGz(tar) => append_tar(tar, name, contents)?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), PDFConError> {
+        match self.inner {
+            ArchiveWriterInner::Zip(zip) => {
+                zip.finish()?.flush()?;
+            }
+            ArchiveWriterInner::Tar(tar) => {
+                tar.into_inner()?.flush()?;
+            }
+            ArchiveWriterInner::TarGz(tar) => {
+                tar.into_inner()?.finish()?.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn append_tar(
+    tar: &mut tar::Builder<impl Write>,
+    name: &str,
+    contents: &[u8],
+) -> Result<(), PDFConError> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    );
+    header.set_cksum();
+    tar.append_data(&mut header, name, contents)?;
+    Ok(())
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The parts of a ComicInfo.xml that have somewhere to go in a pdf
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ComicInfo {
+    pub title: Option<String>,
+    pub series: Option<String>,
+    pub number: Option<String>,
+    pub writer: Option<String>,
+    pub summary: Option<String>,
+    pub genre: Option<String>,
+}
+
+impl ComicInfo {
+    pub fn parse(xml: &str) -> Option<Self> {
+        let document = roxmltree::Document::parse(xml).ok()?;
+        let root = document.root_element();
+        let field = |name: &str| {
+            root.children()
+                .find(|n| n.has_tag_name(name))
+                .and_then(|n| n.text())
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+        };
+
+        Some(Self {
+            title: field("Title"),
+            series: field("Series"),
+            number: field("Number"),
+            writer: field("Writer"),
+            summary: field("Summary"),
+            genre: field("Genre"),
+        })
+    }
+
+    /// Read the document information dictionary of a pdf. None when it has nothing
+    /// ComicInfo.xml has a field for
+    pub fn from_document(doc: &lopdf::Document) -> Option<Self> {
+        let info = doc
+            .trailer
+            .get(b"Info")
+            .and_then(|info| doc.dereference(info))
+            .and_then(|(_, info)| info.as_dict())
+            .ok()?;
+        let field = |key: &[u8]| {
+            info.get(key)
+                .ok()
+                .filter(|value| matches!(value, Object::String(..)))
+                .and_then(|value| lopdf::decode_text_string(value).ok())
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+
+        let comic_info = Self {
+            title: field(b"Title"),
+            writer: field(b"Author"),
+            summary: field(b"Subject"),
+            genre: field(b"Keywords"),
+            ..Default::default()
+        };
+        (comic_info != Self::default()).then_some(comic_info)
+    }
+
+    pub fn to_xml(&self, page_count: usize) -> String {
+        let mut xml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<ComicInfo xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" ",
+            "xmlns:xsd=\"http://www.w3.org/2001/XMLSchema\">\n",
+        ));
+        let fields = [
+            ("Title", &self.title),
+            ("Series", &self.series),
+            ("Number", &self.number),
+            ("Summary", &self.summary),
+            ("Writer", &self.writer),
+            ("Genre", &self.genre),
+        ];
+        for (tag, value) in fields {
+            if let Some(value) = value {
+                xml.push_str(&format!("  <{0}>{1}</{0}>\n", tag, escape_xml(value)));
+            }
+        }
+        xml.push_str(&format!("  <PageCount>{}</PageCount>\n", page_count));
+        xml.push_str("</ComicInfo>\n");
+        xml
+    }
+
+    /// Title for the pdf. Falls back to the series and issue number
+    pub fn display_title(&self) -> Option<String> {
+        match (&self.title, &self.series, &self.number) {
+            (Some(title), _, _) => Some(title.clone()),
+            (None, Some(series), Some(number)) => Some(format!("{} #{}", series, number)),
+            (None, Some(series), None) => Some(series.clone()),
+            _ => None,
+        }
+    }
+
+    /// Document information dictionary entries
+    pub fn info_dictionary(&self) -> Dictionary {
+        let mut info = Dictionary::new();
+        let fields = [
+            ("Title", self.display_title()),
+            ("Author", self.writer.clone()),
+            ("Subject", self.summary.clone()),
+            ("Keywords", self.genre.clone()),
+        ];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                info.set(key, lopdf::text_string(&value));
+            }
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const NAMES: [&str; 7] = [
+        "10.jpg",
+        "2.jpg",
+        "notes.txt",
+        ".hidden.jpg",
+        "__MACOSX/._1.jpg",
+        "sub/1.png",
+        "ComicInfo.xml",
+    ];
+
+    fn is_page(name: &str) -> bool {
+        name.ends_with(".jpg") || name.ends_with(".png")
+    }
+
+    fn names(entries: &[ArchiveEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    fn zip_archive() -> Cursor<Vec<u8>> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_directory("sub/", options).unwrap();
+        for name in NAMES {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(name.as_bytes()).unwrap();
+        }
+        let mut archive = zip.finish().unwrap();
+        archive.set_position(0);
+        archive
+    }
+
+    fn tar_archive(tar: &mut tar::Builder<impl Write>) {
+        for name in NAMES {
+            append_tar(tar, name, name.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn format_comes_from_the_extension() {
+        let format = |name: &str| ArchiveFormat::from_path(Path::new(name)).unwrap();
+        assert_eq!(format("book.CBZ"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("book.cbt"), Some(ArchiveFormat::Tar));
+        assert_eq!(format("book.tar.gz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("book.gz"), None);
+        assert_eq!(format("scans"), None);
+    }
+
+    #[test]
+    fn rar_and_7z_are_rejected() {
+        for name in ["book.cbr", "book.rar", "book.cb7", "book.7z"] {
+            assert!(matches!(
+                ArchiveFormat::from_path(Path::new(name)),
+                Err(PDFConError::UnsupportedArchive(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn zip_entries_skip_junk_and_sort_naturally() {
+        let entries = read_entries_from(zip_archive(), ArchiveFormat::Zip, is_page).unwrap();
+        assert_eq!(names(&entries), ["2.jpg", "10.jpg", "sub/1.png"]);
+        assert_eq!(entries[0].contents, b"2.jpg");
+    }
+
+    #[test]
+    fn tar_entries_skip_junk_and_sort_naturally() {
+        let mut tar = tar::Builder::new(Vec::new());
+        tar_archive(&mut tar);
+        let archive = Cursor::new(tar.into_inner().unwrap());
+        let entries = read_entries_from(archive, ArchiveFormat::Tar, is_page).unwrap();
+        assert_eq!(names(&entries), ["2.jpg", "10.jpg", "sub/1.png"]);
+
+        let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Default::default()));
+        tar_archive(&mut tar);
+        let archive = Cursor::new(tar.into_inner().unwrap().finish().unwrap());
+        let entries = read_entries_from(archive, ArchiveFormat::TarGz, |name| {
+            name == "ComicInfo.xml"
+        })
+        .unwrap();
+        assert_eq!(names(&entries), ["ComicInfo.xml"]);
+    }
+
+    #[test]
+    fn comic_info_reads_the_fields_it_knows() {
+        let xml = "<ComicInfo><Series> Hero </Series><Number>3</Number><Title></Title>\
+                   <Penciller>Someone</Penciller></ComicInfo>";
+        let info = ComicInfo::parse(xml).unwrap();
+        assert_eq!(info.series.as_deref(), Some("Hero"));
+        assert_eq!(info.title, None);
+        assert_eq!(info.display_title().as_deref(), Some("Hero #3"));
+        assert_eq!(ComicInfo::parse("<ComicInfo>"), None);
+    }
+
+    #[test]
+    fn comic_info_xml_is_escaped() {
+        let info = ComicInfo {
+            title: Some(String::from("Cats & <Dogs> \"1\"")),
+            writer: Some(String::from("A & B")),
+            ..Default::default()
+        };
+        let xml = info.to_xml(12);
+        assert!(xml.contains("<Title>Cats &amp; &lt;Dogs&gt; &quot;1&quot;</Title>"));
+        assert!(xml.contains("<PageCount>12</PageCount>"));
+        assert_eq!(ComicInfo::parse(&xml), Some(info));
+    }
+}
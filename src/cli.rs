@@ -1,6 +1,107 @@
-use clap::{ArgAction, Command, arg, command, value_parser};
+use crate::name_template::NameTemplate;
+use crate::page_label::PageLabels;
+use crate::page_range::PageRanges;
+use clap::{Arg, ArgAction, ArgGroup, Command, arg, command, value_parser};
 use std::path::PathBuf;
 
+fn jpeg_args() -> [Arg; 5] {
+    [
+        arg!([QUALITY])
+            .short('q')
+            .long("quality")
+            .help("JPEG quality used by --optimize and when converting to JPEG")
+            .value_parser(value_parser!(u8).range(1..=100))
+            .required(false),
+        arg!([BASELINE])
+            .long("baseline")
+            .help("Write baseline instead of progressive JPEGs")
+            .action(ArgAction::SetTrue),
+        arg!([SUBSAMPLING])
+            .long("subsampling")
+            .help("Chroma subsampling for colour JPEGs")
+            .value_parser(["444", "422", "420"])
+            .required(false),
+        arg!([TRELLIS])
+            .long("trellis")
+            .help("Trellis quantization mode")
+            .value_parser(["off", "on", "scans"])
+            .required(false),
+        arg!([LOSSLESS])
+            .long("lossless")
+            .help("Only optimize JPEG Huffman tables without re-encoding")
+            .action(ArgAction::SetTrue),
+    ]
+}
+
+fn parse_threshold(s: &str) -> Result<String, String> {
+    if s == "otsu" || s.parse::<u8>().is_ok() {
+        Ok(s.to_string())
+    } else {
+        Err(String::from("expected \"otsu\" or a value from 0 to 255"))
+    }
+}
+
+/// Degrees and the pages they apply to. No pages means every page
+fn parse_rotation(s: &str) -> Result<(i64, Option<PageRanges>), String> {
+    let (degrees, pages) = match s.split_once(':') {
+        Some((degrees, pages)) => (degrees, Some(pages.parse::<PageRanges>()?)),
+        None => (s, None),
+    };
+    match degrees.parse::<i64>() {
+        Ok(d) if d % 90 == 0 => Ok((d, pages)),
+        _ => Err(String::from(
+            "expected a multiple of 90 optionally followed by pages, e.g. 90 or 180:2-4",
+        )),
+    }
+}
+
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(String::from("expected a percentage from 0 to 100")),
+    }
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, multiplier) = match s.to_ascii_uppercase().trim_end_matches('B') {
+        n if n.ends_with('K') => (n[..n.len() - 1].to_string(), 1 << 10),
+        n if n.ends_with('M') => (n[..n.len() - 1].to_string(), 1 << 20),
+        n if n.ends_with('G') => (n[..n.len() - 1].to_string(), 1 << 30),
+        n => (n.to_string(), 1),
+    };
+    match number.trim().parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("{} is too large", s)),
+        _ => Err(String::from("expected a size like 500K, 20M or 1G")),
+    }
+}
+
+fn resample_args() -> [Arg; 4] {
+    [
+        arg!([MAX_WIDTH])
+            .long("max-width")
+            .help("Downsample images wider than this")
+            .value_parser(value_parser!(u32).range(1..))
+            .required(false),
+        arg!([MAX_HEIGHT])
+            .long("max-height")
+            .help("Downsample images taller than this")
+            .value_parser(value_parser!(u32).range(1..))
+            .required(false),
+        arg!([SCALE])
+            .long("scale")
+            .help("Downsample images to this percentage of their size")
+            .value_parser(value_parser!(u32).range(1..=100))
+            .required(false),
+        arg!([FILTER])
+            .long("filter")
+            .help("Filter used when downsampling")
+            .value_parser(["nearest", "triangle", "catmullrom", "gaussian", "lanczos3"])
+            .required(false),
+    ]
+}
+
 pub fn build_command() -> clap::Command {
     let command: clap::Command = command!()
         .propagate_version(true)
@@ -30,9 +131,145 @@ pub fn build_command() -> clap::Command {
                         .required(false),
                 )
                 .arg(
-                    arg!([IN_DIRECTORY])
+                    arg!([INPUT])
+                        .help("Directory or zip, cbz or tar archive of images")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MAX_PAGES])
+                        .long("max-pages")
+                        .help("Start a new numbered pdf after this many pages")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MAX_SIZE])
+                        .long("max-size")
+                        .help("Start a new numbered pdf before one would grow past this size, e.g. 50MB")
+                        .value_parser(parse_size)
+                        .required(false),
+                )
+                .arg(
+                    arg!([TOC])
+                        .long("toc")
+                        .help("Build the outline from a file of page<TAB>title lines, indented to nest, or json")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PAGE_LABELS])
+                        .long("page-labels")
+                        .help("Page numbers viewers show as range:style[:start[:prefix]], e.g. 1-4:r,5-:D:1. Styles are D, r, R, a, A or nothing")
+                        .value_parser(value_parser!(PageLabels))
+                        .required(false),
+                )
+                .arg(
+                    arg!([AUTO_CROP])
+                        .long("auto-crop")
+                        .help("Trim flat borders that differ from the page, like the black edges of a scan")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([DESKEW])
+                        .long("deskew")
+                        .help("Straighten pages scanned up to 5 degrees off")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([CROP_TO_CONTENT])
+                        .long("crop-to-content")
+                        .help("Crop pages to what is on them, keeping this many pixels of margin. Defaults to 0")
+                        .value_parser(value_parser!(u32))
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("0")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BLANK_PAGES])
+                        .long("blank-pages")
+                        .help("Look for nearly blank pages such as duplex scan backs. drop leaves them out and log only logs them")
+                        .value_parser(["drop", "log"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([BLANK_THRESHOLD])
+                        .long("blank-threshold")
+                        .help("Pages with less ink than this percentage of their area are blank. Defaults to 0.5")
+                        .value_parser(parse_percentage)
+                        .required(false),
+                )
+                .arg(
+                    arg!([BLANK_REPORT])
+                        .long("blank-report")
+                        .help("Write a json list of the blank pages found to this file")
                         .value_parser(value_parser!(PathBuf))
                         .required(false),
+                )
+                .arg(
+                    arg!([MAX_DPI])
+                        .long("max-dpi")
+                        .help("Downsample images whose recorded resolution is above this")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    arg!([GRAY])
+                        .long("gray")
+                        .help("Store RGB images as gray when no channel differs by more than this")
+                        .value_parser(value_parser!(u8))
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("8")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BILEVEL])
+                        .long("bilevel")
+                        .help("Threshold black and white images to 1 bit at a value or with otsu. RGB images must be within the --gray tolerance, 8 if not given")
+                        .value_parser(parse_threshold)
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("otsu")
+                        .required(false),
+                )
+                .arg(
+                    arg!([USER_PASSWORD])
+                        .long("user-password")
+                        .help("Encrypt the pdf with a password needed to open it")
+                        .required(false),
+                )
+                .arg(
+                    arg!([OWNER_PASSWORD])
+                        .long("owner-password")
+                        .help("Encrypt the pdf with a password that lifts the permissions")
+                        .required(false),
+                )
+                .arg(
+                    arg!([ENCRYPTION])
+                        .long("encryption")
+                        .help("Encryption used when a password is set")
+                        .value_parser(["aes128", "aes256"])
+                        .requires("PASSWORDS")
+                        .required(false),
+                )
+                .arg(
+                    arg!([PERMISSIONS])
+                        .long("permissions")
+                        .help("Comma separated list of what an encrypted pdf allows without the owner password")
+                        .value_parser(["print", "copy", "modify", "annotate", "none"])
+                        .value_delimiter(',')
+                        .num_args(1..)
+                        .requires("PASSWORDS")
+                        .required(false),
+                )
+                .args(jpeg_args())
+                .args(resample_args())
+                .group(
+                    ArgGroup::new("PASSWORDS")
+                        .args(["USER_PASSWORD", "OWNER_PASSWORD"])
+                        .multiple(true),
                 ),
         )
         .subcommand(
@@ -62,8 +299,550 @@ pub fn build_command() -> clap::Command {
                         .short('o')
                         .long("optimize")
                         .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                )
+                .arg(
+                    arg!([PAGES])
+                        .long("pages")
+                        .help("Only unpack these pages, e.g. 1-10,15,20-")
+                        .value_parser(value_parser!(PageRanges))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MIN_WIDTH])
+                        .long("min-width")
+                        .help("Skip images narrower than this")
+                        .value_parser(value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MIN_HEIGHT])
+                        .long("min-height")
+                        .help("Skip images shorter than this")
+                        .value_parser(value_parser!(u32))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MIN_BYTES])
+                        .long("min-bytes")
+                        .help("Skip images stored in fewer bytes than this")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([ONLY])
+                        .long("only")
+                        .help("Comma separated list of image encodings to unpack. JBIG2 and JPEG 2000 images are never unpacked")
+                        .value_parser(["jpeg", "png", "ccitt"])
+                        .value_delimiter(',')
+                        .num_args(1..)
+                        .required(false),
+                )
+                .arg(
+                    arg!([FORMAT])
+                        .long("format")
+                        .help("Image format to write. keep leaves jpegs as jpegs and the rest as pngs")
+                        .value_parser(["keep", "png", "jpeg", "webp", "tiff"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([NAME_TEMPLATE])
+                        .long("name-template")
+                        .help("Output file names using {stem}, {page}, {label}, {index}, {objnum}, {chapter} and {ext}")
+                        .value_parser(value_parser!(NameTemplate))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MANIFEST])
+                        .long("manifest")
+                        .help("Write a json record of every extracted image. Defaults to manifest.json in the output directory")
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .required(false),
+                )
+                .arg(
+                    arg!([JSONL])
+                        .long("jsonl")
+                        .help("Print the record of every extracted image to stdout as json lines")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("MANIFEST"),
+                )
+                .arg(
+                    arg!([ARCHIVE])
+                        .long("archive")
+                        .help("Write the images in page order to a <stem>.cbz, .zip or .tar in the output directory")
+                        .value_parser(["cbz", "zip", "tar"])
+                        .required(false),
+                )
+                .arg(
+                    arg!([CMYK_TO_RGB])
+                        .long("cmyk-to-rgb")
+                        .help("Write CMYK jpegs as RGB for viewers that can't show CMYK")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([DEDUPE])
+                        .long("dedupe")
+                        .help("Write each repeated image once. skip leaves copies out and link hard links them to the first")
+                        .value_parser(["skip", "link"])
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("skip")
+                        .required(false),
+                )
+                .args(jpeg_args())
+                .args(resample_args()),
+        )
+        .subcommand(
+            Command::new("info")
+                .visible_alias("inspect")
+                .about("Report what is inside a pdf")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                )
+                .arg(
+                    arg!([JSON])
+                        .long("json")
+                        .help("Print the report as json")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("list")
+                .about("List the images in a pdf without extracting them")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                ),
+        )
+        .subcommand(
+            Command::new("optimize")
+                .about("Recompress the images of an existing pdf")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_FILE])
+                        .short('f')
+                        .long("file")
+                        .help("Where to write the result. Defaults to <name>-optimized.pdf next to the input")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([THREADS])
+                        .short('t')
+                        .long("threads")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                )
+                .arg(
+                    arg!([GRAY])
+                        .long("gray")
+                        .help("Store RGB images as gray when no channel differs by more than this")
+                        .value_parser(value_parser!(u8))
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("8")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BILEVEL])
+                        .long("bilevel")
+                        .help("Threshold black and white images to 1 bit at a value or with otsu. RGB images must be within the --gray tolerance, 8 if not given")
+                        .value_parser(parse_threshold)
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("otsu")
+                        .required(false),
+                )
+                .args(jpeg_args())
+                .args(resample_args()),
+        )
+        .subcommand(
+            Command::new("merge")
+                .about("Combine pdfs and image directories into one pdf")
+                .arg(
+                    arg!([SOURCES])
+                        .help("Pdfs and image directories in the order their pages should appear")
+                        .value_parser(value_parser!(PathBuf))
+                        .num_args(1..)
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_FILE])
+                        .short('f')
+                        .long("file")
+                        .help("Where to write the result. Defaults to merged.pdf")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([THREADS])
+                        .short('t')
+                        .long("threads")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("Password used to open encrypted source pdfs")
+                        .required(false),
+                )
+                .arg(
+                    arg!([OPTIMIZE])
+                        .short('o')
+                        .long("optimize")
+                        .help("Optimize images packed from directories")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([MAX_DPI])
+                        .long("max-dpi")
+                        .help("Downsample images whose recorded resolution is above this")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    arg!([GRAY])
+                        .long("gray")
+                        .help("Store RGB images as gray when no channel differs by more than this")
+                        .value_parser(value_parser!(u8))
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("8")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BILEVEL])
+                        .long("bilevel")
+                        .help("Threshold black and white images to 1 bit at a value or with otsu. RGB images must be within the --gray tolerance, 8 if not given")
+                        .value_parser(parse_threshold)
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("otsu")
+                        .required(false),
+                )
+                .args(jpeg_args())
+                .args(resample_args()),
+        )
+        .subcommand(
+            Command::new("split")
+                .about("Break a pdf into several smaller pdfs")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_DIRECTORY])
+                        .short('d')
+                        .long("directory")
+                        .help("Where to write the parts. Defaults to the input's directory")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                )
+                .arg(
+                    arg!([EVERY])
+                        .long("every")
+                        .help("Start a new part every this many pages")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    arg!([OUTLINE])
+                        .long("outline")
+                        .help("Start a new part at every top level bookmark")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([RANGES])
+                        .long("ranges")
+                        .help("Write one part per range, e.g. 1-10,11-25,26-")
+                        .value_parser(value_parser!(PageRanges))
+                        .required(false),
+                )
+                .arg(
+                    arg!([MAX_SIZE])
+                        .long("max-size")
+                        .help("Fit as many pages as possible in parts of about this size, e.g. 20M")
+                        .value_parser(parse_size)
+                        .required(false),
+                )
+                .group(
+                    ArgGroup::new("MODE")
+                        .args(["EVERY", "OUTLINE", "RANGES", "MAX_SIZE"])
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("pages")
+                .about("Reorder, delete, rotate and duplicate the pages of a pdf")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_FILE])
+                        .short('f')
+                        .long("file")
+                        .help("Where to write the result. Defaults to <name>-edited.pdf next to the input")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                )
+                .arg(
+                    arg!([ORDER])
+                        .long("order")
+                        .help("New page order, e.g. 3,1,2,4-. Pages left out are dropped")
+                        .value_parser(value_parser!(PageRanges))
+                        .required(false),
+                )
+                .arg(
+                    arg!([DELETE])
+                        .long("delete")
+                        .help("Pages to remove, e.g. 2,7-9")
+                        .value_parser(value_parser!(PageRanges))
+                        .required(false),
+                )
+                .arg(
+                    arg!([ROTATE])
+                        .long("rotate")
+                        .help("Turn pages clockwise by degrees, e.g. 180:3 or 90 for every page. Can be repeated")
+                        .value_parser(parse_rotation)
+                        .action(ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    arg!([DUPLICATE])
+                        .long("duplicate")
+                        .help("Pages to repeat right after themselves")
+                        .value_parser(value_parser!(PageRanges))
+                        .required(false),
+                )
+                .group(
+                    ArgGroup::new("EDITS")
+                        .args(["ORDER", "DELETE", "ROTATE", "DUPLICATE"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert a cbz, zip or tar of images to a pdf or a pdf to one")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([OUT_FILE])
+                        .help("Pdf or archive to write. Its extension picks the direction")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([THREADS])
+                        .short('t')
+                        .long("threads")
+                        .value_parser(value_parser!(usize))
+                        .required(false),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
+                )
+                .arg(
+                    arg!([OPTIMIZE])
+                        .short('o')
+                        .long("optimize")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!([MAX_DPI])
+                        .long("max-dpi")
+                        .help("Downsample images whose recorded resolution is above this")
+                        .value_parser(value_parser!(u32).range(1..))
+                        .required(false),
+                )
+                .arg(
+                    arg!([GRAY])
+                        .long("gray")
+                        .help("Store RGB images as gray when no channel differs by more than this")
+                        .value_parser(value_parser!(u8))
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("8")
+                        .required(false),
+                )
+                .arg(
+                    arg!([BILEVEL])
+                        .long("bilevel")
+                        .help("Threshold black and white images to 1 bit at a value or with otsu. RGB images must be within the --gray tolerance, 8 if not given")
+                        .value_parser(parse_threshold)
+                        .num_args(0..=1)
+                        .require_equals(true)
+                        .default_missing_value("otsu")
+                        .required(false),
+                )
+                .args(jpeg_args())
+                .args(resample_args()),
+        )
+        .subcommand(
+            Command::new("outline")
+                .about("Print the outline of a pdf or replace it from a table of contents file")
+                .arg(
+                    arg!([IN_FILE])
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true),
+                )
+                .arg(
+                    arg!([SET])
+                        .long("set")
+                        .help("Table of contents to replace the outline with, in the format this prints")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(false),
+                )
+                .arg(
+                    arg!([OUT_FILE])
+                        .short('f')
+                        .long("file")
+                        .help("Where to write the pdf with the new outline. Defaults to <stem>-outlined.pdf")
+                        .value_parser(value_parser!(PathBuf))
+                        .requires("SET")
+                        .required(false),
+                )
+                .arg(
+                    arg!([JSON])
+                        .long("json")
+                        .help("Print the outline as json instead of page<TAB>title lines")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("SET"),
+                )
+                .arg(
+                    arg!([PASSWORD])
+                        .short('p')
+                        .long("password")
+                        .help("User or owner password of an encrypted pdf")
+                        .required(false),
                 ),
         );
 
     command
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rotation_reads_degrees_and_pages() {
+        assert_eq!(parse_rotation("90"), Ok((90, None)));
+        assert_eq!(parse_rotation("-180"), Ok((-180, None)));
+        assert_eq!(
+            parse_rotation("270:2-4"),
+            Ok((270, Some("2-4".parse().unwrap())))
+        );
+    }
+
+    #[test]
+    fn parse_rotation_rejects_odd_angles_and_pages() {
+        assert!(parse_rotation("45").is_err());
+        assert!(parse_rotation("90:0").is_err());
+        assert!(parse_rotation("90:").is_err());
+        assert!(parse_rotation("left").is_err());
+    }
+
+    #[test]
+    fn parse_size_reads_units() {
+        assert_eq!(parse_size("500"), Ok(500));
+        assert_eq!(parse_size("500K"), Ok(500 << 10));
+        assert_eq!(parse_size("20MB"), Ok(20 << 20));
+        assert_eq!(parse_size("1g"), Ok(1 << 30));
+    }
+
+    #[test]
+    fn parse_size_rejects_nonsense() {
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-5M").is_err());
+        assert!(parse_size("5T").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+        assert!(parse_size("18446744073709551615").is_ok());
+    }
+
+    #[test]
+    fn parse_percentage_stays_in_range() {
+        assert_eq!(parse_percentage("0"), Ok(0.0));
+        assert_eq!(parse_percentage("0.5"), Ok(0.5));
+        assert_eq!(parse_percentage("100"), Ok(100.0));
+        assert!(parse_percentage("-1").is_err());
+        assert!(parse_percentage("100.1").is_err());
+        assert!(parse_percentage("NaN").is_err());
+        assert!(parse_percentage("half").is_err());
+    }
+
+    #[test]
+    fn pack_encryption_needs_a_password() {
+        let pack = |extra: &[&str]| {
+            let args = ["pdfcon", "pack", "in", "-f", "out.pdf"];
+            build_command().try_get_matches_from(args.iter().chain(extra))
+        };
+        assert!(pack(&["--encryption", "aes128"]).is_err());
+        assert!(pack(&["--permissions", "print"]).is_err());
+        assert!(pack(&["--encryption", "aes128", "--user-password", "u"]).is_ok());
+        assert!(pack(&["--permissions", "print", "--owner-password", "o"]).is_ok());
+    }
+}
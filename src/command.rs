@@ -1,14 +1,177 @@
 use crate::cli::build_command;
 use crate::constants::physical_cores;
-use crate::pack::Pack;
-use crate::unpack::Unpack;
+use crate::convert::Convert;
+use crate::info::Info;
+use crate::list::List;
+use crate::manifest::ManifestOutput;
+use crate::merge::Merge;
+use crate::name_template::NameTemplate;
+use crate::optimize::Optimize;
+use crate::outline::Outline;
+use crate::pack::{BlankPages, Pack};
+use crate::page_label::PageLabels;
+use crate::page_range::PageRanges;
+use crate::pages::Pages;
+use crate::pdf_image::optimize::{
+    ChromaSubsampling, ColorAnalysis, JpegOptions, ScanCleanup, Threshold, Trellis,
+};
+use crate::pdf_image::resample::{Resample, ResampleFilter};
+use crate::pdf_image::{ImageEncoding, OutputFormat};
+use crate::security::{Encryption, EncryptionMethod, permissions_from_names, random_password};
+use crate::split::{Split, SplitMode};
+use crate::unpack::{Dedupe, ImageSelection, Unpack};
+use clap::ArgMatches;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum PDFCon {
     UNPACK(Unpack),
     PACK(Pack),
+    INFO(Info),
+    LIST(List),
+    OPTIMIZE(Optimize),
+    MERGE(Merge),
+    SPLIT(Split),
+    PAGES(Pages),
+    CONVERT(Convert),
+    OUTLINE(Outline),
+}
+
+fn jpeg_options(sub_matches: &ArgMatches) -> JpegOptions {
+    let defaults = JpegOptions::default();
+    JpegOptions {
+        quality: sub_matches
+            .get_one::<u8>("QUALITY")
+            .copied()
+            .unwrap_or(defaults.quality),
+        progressive: !sub_matches.get_flag("BASELINE"),
+        subsampling: match sub_matches
+            .get_one::<String>("SUBSAMPLING")
+            .map(|s| s.as_str())
+        {
+            Some("444") => ChromaSubsampling::S444,
+            Some("422") => ChromaSubsampling::S422,
+            Some("420") => ChromaSubsampling::S420,
+            _ => defaults.subsampling,
+        },
+        trellis: match sub_matches.get_one::<String>("TRELLIS").map(|s| s.as_str()) {
+            Some("off") => Trellis::Off,
+            Some("on") => Trellis::On,
+            Some("scans") => Trellis::Scans,
+            _ => defaults.trellis,
+        },
+        lossless: sub_matches.get_flag("LOSSLESS"),
+    }
+}
+
+fn resample(sub_matches: &ArgMatches, with_dpi: bool) -> Resample {
+    let defaults = Resample::default();
+    Resample {
+        max_dpi: if with_dpi {
+            sub_matches.get_one::<u32>("MAX_DPI").copied()
+        } else {
+            None
+        },
+        max_width: sub_matches.get_one::<u32>("MAX_WIDTH").copied(),
+        max_height: sub_matches.get_one::<u32>("MAX_HEIGHT").copied(),
+        scale: sub_matches.get_one::<u32>("SCALE").copied(),
+        filter: match sub_matches.get_one::<String>("FILTER").map(|s| s.as_str()) {
+            Some("nearest") => ResampleFilter::Nearest,
+            Some("triangle") => ResampleFilter::Triangle,
+            Some("catmullrom") => ResampleFilter::CatmullRom,
+            Some("gaussian") => ResampleFilter::Gaussian,
+            Some("lanczos3") => ResampleFilter::Lanczos3,
+            _ => defaults.filter,
+        },
+    }
+}
+
+fn color_analysis(sub_matches: &ArgMatches) -> ColorAnalysis {
+    ColorAnalysis {
+        gray_tolerance: sub_matches.get_one::<u8>("GRAY").copied(),
+        bilevel: sub_matches
+            .get_one::<String>("BILEVEL")
+            .map(|s| match s.parse::<u8>() {
+                Ok(t) => Threshold::Fixed(t),
+                Err(_) => Threshold::Otsu,
+            }),
+    }
+}
+
+fn blank_pages(sub_matches: &ArgMatches) -> Option<BlankPages> {
+    let action = sub_matches.get_one::<String>("BLANK_PAGES");
+    let report = sub_matches.get_one::<PathBuf>("BLANK_REPORT").cloned();
+    if action.is_none() && report.is_none() {
+        return None;
+    }
+
+    let threshold = sub_matches
+        .get_one::<f64>("BLANK_THRESHOLD")
+        .copied()
+        .unwrap_or(0.5);
+    Some(BlankPages {
+        max_ink: (threshold * 100.0).round() as u32,
+        drop: action.is_some_and(|a| a == "drop"),
+        report,
+    })
+}
+
+fn encryption(sub_matches: &ArgMatches) -> Option<Encryption> {
+    let user_password = sub_matches.get_one::<String>("USER_PASSWORD");
+    let owner_password = sub_matches.get_one::<String>("OWNER_PASSWORD");
+    if user_password.is_none() && owner_password.is_none() {
+        return None;
+    }
+
+    Some(Encryption {
+        method: match sub_matches
+            .get_one::<String>("ENCRYPTION")
+            .map(|s| s.as_str())
+        {
+            Some("aes128") => EncryptionMethod::Aes128,
+            _ => EncryptionMethod::Aes256,
+        },
+        user_password: user_password.cloned().unwrap_or_default(),
+        owner_password: owner_password.cloned().unwrap_or_else(random_password),
+        permissions: match sub_matches.get_many::<String>("PERMISSIONS") {
+            Some(names) => permissions_from_names(names.map(|s| s.as_str())),
+            None => lopdf::Permissions::all(),
+        },
+    })
+}
+
+fn selection(sub_matches: &ArgMatches) -> ImageSelection {
+    ImageSelection {
+        pages: sub_matches.get_one::<PageRanges>("PAGES").cloned(),
+        min_width: sub_matches.get_one::<u32>("MIN_WIDTH").copied(),
+        min_height: sub_matches.get_one::<u32>("MIN_HEIGHT").copied(),
+        min_bytes: sub_matches.get_one::<usize>("MIN_BYTES").copied(),
+        only: sub_matches.get_many::<String>("ONLY").map(|names| {
+            names
+                .map(|name| match name.as_str() {
+                    "jpeg" => ImageEncoding::Jpeg,
+                    "ccitt" => ImageEncoding::Ccitt,
+                    _ => ImageEncoding::Png,
+                })
+                .collect()
+        }),
+    }
+}
+
+fn manifest(sub_matches: &ArgMatches, out_directory: &Path) -> Option<ManifestOutput> {
+    if sub_matches.get_flag("JSONL") {
+        return Some(ManifestOutput::JsonLines);
+    }
+    if !sub_matches.contains_id("MANIFEST") {
+        return None;
+    }
+    Some(ManifestOutput::File(
+        sub_matches
+            .get_one::<PathBuf>("MANIFEST")
+            .cloned()
+            .unwrap_or_else(|| out_directory.join("manifest.json")),
+    ))
 }
 
 pub fn get_command() -> PDFCon {
@@ -19,41 +182,249 @@ pub fn get_command() -> PDFCon {
     let default_name = c_dir.join(dir_name).with_extension("pdf");
 
     match matches.subcommand() {
-        Some(("pack", sub_matches)) => PDFCon::PACK(Pack {
-            optimize: sub_matches.get_flag("OPTIMIZE"),
-            in_directory: sub_matches
-                .get_one::<PathBuf>("IN_DIRECTORY")
+        Some(("pack", sub_matches)) => {
+            let input = sub_matches
+                .get_one::<PathBuf>("INPUT")
                 .unwrap_or(&c_dir)
+                .to_owned();
+            // Archives are named after themselves rather than the current directory
+            let default_name = if input.is_file() {
+                input.with_extension("pdf")
+            } else {
+                default_name
+            };
+            PDFCon::PACK(Pack {
+                optimize: sub_matches.get_flag("OPTIMIZE"),
+                jpeg_options: jpeg_options(sub_matches),
+                resample: resample(sub_matches, true),
+                color_analysis: color_analysis(sub_matches),
+                cleanup: ScanCleanup {
+                    auto_crop: sub_matches.get_flag("AUTO_CROP"),
+                    deskew: sub_matches.get_flag("DESKEW"),
+                    crop_to_content: sub_matches.get_one::<u32>("CROP_TO_CONTENT").copied(),
+                },
+                encryption: encryption(sub_matches),
+                input,
+                out_file: sub_matches
+                    .get_one::<PathBuf>("OUT_FILE")
+                    .unwrap_or(&default_name)
+                    .to_owned(),
+                max_pages: sub_matches.get_one::<u32>("MAX_PAGES").map(|n| *n as usize),
+                max_size: sub_matches.get_one::<u64>("MAX_SIZE").copied(),
+                toc: sub_matches.get_one::<PathBuf>("TOC").cloned(),
+                page_labels: sub_matches.get_one::<PageLabels>("PAGE_LABELS").cloned(),
+                blank_pages: blank_pages(sub_matches),
+                threads: sub_matches
+                    .get_one::<usize>("THREADS")
+                    .copied()
+                    .unwrap_or(total_physical / 2)
+                    .clamp(1usize, total_physical * 2),
+            })
+        }
+        Some(("unpack", sub_matches)) => {
+            let out_directory = sub_matches
+                .get_one::<PathBuf>("OUT_DIRECTORY")
+                .unwrap_or(&PathBuf::from("output/"))
+                .to_owned();
+            let in_file = sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned();
+            PDFCon::UNPACK(Unpack {
+                threads: sub_matches
+                    .get_one::<usize>("THREADS")
+                    .copied()
+                    .unwrap_or(total_physical / 2)
+                    .clamp(1usize, total_physical * 2),
+                manifest: manifest(sub_matches, &out_directory),
+                archive: sub_matches.get_one::<String>("ARCHIVE").map(|extension| {
+                    out_directory.join(format!(
+                        "{}.{}",
+                        in_file.file_stem().unwrap_or_default().to_string_lossy(),
+                        extension
+                    ))
+                }),
+                cmyk_to_rgb: sub_matches.get_flag("CMYK_TO_RGB"),
+                dedupe: match sub_matches.get_one::<String>("DEDUPE").map(|s| s.as_str()) {
+                    Some("link") => Some(Dedupe::Link),
+                    Some(_) => Some(Dedupe::Skip),
+                    None => None,
+                },
+                out_directory,
+                in_file,
+                optimize: sub_matches
+                    .get_one::<bool>("OPTIMIZE")
+                    .copied()
+                    .unwrap_or(false),
+                jpeg_options: jpeg_options(sub_matches),
+                resample: resample(sub_matches, false),
+                password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+                selection: selection(sub_matches),
+                format: match sub_matches.get_one::<String>("FORMAT").map(|s| s.as_str()) {
+                    Some("png") => OutputFormat::Png,
+                    Some("jpeg") => OutputFormat::Jpeg,
+                    Some("webp") => OutputFormat::Webp,
+                    Some("tiff") => OutputFormat::Tiff,
+                    _ => OutputFormat::Keep,
+                },
+                name_template: sub_matches
+                    .get_one::<NameTemplate>("NAME_TEMPLATE")
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+        }
+        Some(("info", sub_matches)) => PDFCon::INFO(Info {
+            in_file: sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
                 .to_owned(),
-            out_file: sub_matches
-                .get_one::<PathBuf>("OUT_FILE")
-                .unwrap_or(&default_name)
+            password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+            json: sub_matches.get_flag("JSON"),
+        }),
+        Some(("list", sub_matches)) => PDFCon::LIST(List {
+            in_file: sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
                 .to_owned(),
+            password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+        }),
+        Some(("optimize", sub_matches)) => {
+            let in_file = sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned();
+            let default_out = in_file.with_file_name(format!(
+                "{}-optimized.pdf",
+                in_file.file_stem().unwrap_or_default().to_string_lossy()
+            ));
+            PDFCon::OPTIMIZE(Optimize {
+                threads: sub_matches
+                    .get_one::<usize>("THREADS")
+                    .copied()
+                    .unwrap_or(total_physical / 2)
+                    .clamp(1usize, total_physical * 2),
+                out_file: sub_matches
+                    .get_one::<PathBuf>("OUT_FILE")
+                    .cloned()
+                    .unwrap_or(default_out),
+                in_file,
+                password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+                jpeg_options: jpeg_options(sub_matches),
+                resample: resample(sub_matches, false),
+                color_analysis: color_analysis(sub_matches),
+            })
+        }
+        Some(("convert", sub_matches)) => PDFCon::CONVERT(Convert {
             threads: sub_matches
                 .get_one::<usize>("THREADS")
                 .copied()
                 .unwrap_or(total_physical / 2)
                 .clamp(1usize, total_physical * 2),
+            in_file: sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned(),
+            out_file: sub_matches
+                .get_one::<PathBuf>("OUT_FILE")
+                .unwrap()
+                .to_owned(),
+            password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+            optimize: sub_matches.get_flag("OPTIMIZE"),
+            jpeg_options: jpeg_options(sub_matches),
+            resample: resample(sub_matches, true),
+            color_analysis: color_analysis(sub_matches),
         }),
-        Some(("unpack", sub_matches)) => PDFCon::UNPACK(Unpack {
+        Some(("merge", sub_matches)) => PDFCon::MERGE(Merge {
             threads: sub_matches
                 .get_one::<usize>("THREADS")
                 .copied()
                 .unwrap_or(total_physical / 2)
                 .clamp(1usize, total_physical * 2),
-            out_directory: sub_matches
-                .get_one::<PathBuf>("OUT_DIRECTORY")
-                .unwrap_or(&PathBuf::from("output/"))
-                .to_owned(),
-            in_file: sub_matches
-                .get_one::<PathBuf>("IN_FILE")
+            sources: sub_matches
+                .get_many::<PathBuf>("SOURCES")
                 .unwrap()
-                .to_owned(),
-            optimize: sub_matches
-                .get_one::<bool>("OPTIMIZE")
-                .copied()
-                .unwrap_or(false),
+                .cloned()
+                .collect(),
+            out_file: sub_matches
+                .get_one::<PathBuf>("OUT_FILE")
+                .cloned()
+                .unwrap_or_else(|| c_dir.join("merged.pdf")),
+            password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+            optimize: sub_matches.get_flag("OPTIMIZE"),
+            jpeg_options: jpeg_options(sub_matches),
+            resample: resample(sub_matches, true),
+            color_analysis: color_analysis(sub_matches),
         }),
+        Some(("split", sub_matches)) => {
+            let in_file = sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned();
+            let mode = if let Some(n) = sub_matches.get_one::<u32>("EVERY") {
+                SplitMode::Every(*n)
+            } else if let Some(size) = sub_matches.get_one::<u64>("MAX_SIZE") {
+                SplitMode::MaxSize(*size)
+            } else if let Some(ranges) = sub_matches.get_one::<PageRanges>("RANGES") {
+                SplitMode::Ranges(ranges.clone())
+            } else {
+                SplitMode::Outline
+            };
+            PDFCon::SPLIT(Split {
+                out_directory: sub_matches
+                    .get_one::<PathBuf>("OUT_DIRECTORY")
+                    .cloned()
+                    .unwrap_or_else(|| in_file.parent().unwrap_or(Path::new("")).to_path_buf()),
+                in_file,
+                password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+                mode,
+            })
+        }
+        Some(("outline", sub_matches)) => {
+            let in_file = sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned();
+            let default_out = in_file.with_file_name(format!(
+                "{}-outlined.pdf",
+                in_file.file_stem().unwrap_or_default().to_string_lossy()
+            ));
+            PDFCon::OUTLINE(Outline {
+                out_file: sub_matches
+                    .get_one::<PathBuf>("OUT_FILE")
+                    .cloned()
+                    .unwrap_or(default_out),
+                in_file,
+                password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+                set: sub_matches.get_one::<PathBuf>("SET").cloned(),
+                json: sub_matches.get_flag("JSON"),
+            })
+        }
+        Some(("pages", sub_matches)) => {
+            let in_file = sub_matches
+                .get_one::<PathBuf>("IN_FILE")
+                .unwrap()
+                .to_owned();
+            let default_out = in_file.with_file_name(format!(
+                "{}-edited.pdf",
+                in_file.file_stem().unwrap_or_default().to_string_lossy()
+            ));
+            PDFCon::PAGES(Pages {
+                out_file: sub_matches
+                    .get_one::<PathBuf>("OUT_FILE")
+                    .cloned()
+                    .unwrap_or(default_out),
+                in_file,
+                password: sub_matches.get_one::<String>("PASSWORD").cloned(),
+                order: sub_matches.get_one::<PageRanges>("ORDER").cloned(),
+                delete: sub_matches.get_one::<PageRanges>("DELETE").cloned(),
+                duplicate: sub_matches.get_one::<PageRanges>("DUPLICATE").cloned(),
+                rotate: sub_matches
+                    .get_many::<(i64, Option<PageRanges>)>("ROTATE")
+                    .unwrap_or_default()
+                    .cloned()
+                    .collect(),
+            })
+        }
         _ => unreachable!(
             "Subcommands are mandatory. It should not be possible to reach this branch"
         ),
@@ -1,5 +1,4 @@
 use console::Style;
-use num_cpus;
 use std::path::PathBuf;
 use std::sync::OnceLock;
 
@@ -20,8 +19,8 @@ pub static IGNORE_LIST: &[&[u8]] = &[
     b"Annot",
 ];
 
-const THREADS: OnceLock<usize> = OnceLock::new();
-const TICK_SPEED: OnceLock<u64> = OnceLock::new();
+static THREADS: OnceLock<usize> = OnceLock::new();
+static TICK_SPEED: OnceLock<u64> = OnceLock::new();
 static CURRENT_DIR: OnceLock<PathBuf> = OnceLock::new();
 static BOLD: OnceLock<Style> = OnceLock::new();
 static C_GRAY: OnceLock<Style> = OnceLock::new();
@@ -31,7 +30,7 @@ static BC_GREEN: OnceLock<Style> = OnceLock::new();
 static BC_DRK_GREEN: OnceLock<Style> = OnceLock::new();
 
 pub fn physical_cores() -> usize {
-    *THREADS.get_or_init(|| num_cpus::get_physical())
+    *THREADS.get_or_init(num_cpus::get_physical)
 }
 
 pub fn tick_speed() -> u64 {
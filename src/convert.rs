@@ -0,0 +1,97 @@
+use crate::Run;
+use crate::archive::ArchiveFormat;
+use crate::error::PDFConError;
+use crate::name_template::NameTemplate;
+use crate::pack::Pack;
+use crate::pdf_image::OutputFormat;
+use crate::pdf_image::optimize::{ColorAnalysis, JpegOptions, ScanCleanup};
+use crate::pdf_image::resample::Resample;
+use crate::unpack::{ImageSelection, Unpack};
+use std::path::{Path, PathBuf};
+
+/// Turn a comic archive into a pdf or a pdf into a comic archive. Which way to go comes
+/// from the file extensions. Metadata moves between the Info dictionary and
+/// ComicInfo.xml and top level outline items become chapter folders and back
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Convert {
+    pub threads: usize,
+    pub in_file: PathBuf,
+    pub out_file: PathBuf,
+    /// Used when the input is an encrypted pdf
+    pub password: Option<String>,
+    pub optimize: bool,
+    pub jpeg_options: JpegOptions,
+    pub resample: Resample,
+    pub color_analysis: ColorAnalysis,
+}
+
+fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("pdf"))
+}
+
+impl Convert {
+    fn to_pdf(&self) -> Pack {
+        Pack {
+            optimize: self.optimize,
+            jpeg_options: self.jpeg_options,
+            resample: self.resample,
+            color_analysis: self.color_analysis,
+            cleanup: ScanCleanup::default(),
+            encryption: None,
+            threads: self.threads,
+            input: self.in_file.clone(),
+            out_file: self.out_file.clone(),
+            max_pages: None,
+            max_size: None,
+            toc: None,
+            page_labels: None,
+            blank_pages: None,
+        }
+    }
+
+    fn to_archive(&self) -> Unpack {
+        let out_directory = self
+            .out_file
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        Unpack {
+            threads: self.threads,
+            out_directory,
+            in_file: self.in_file.clone(),
+            optimize: self.optimize,
+            jpeg_options: self.jpeg_options,
+            resample: self.resample,
+            password: self.password.clone(),
+            selection: ImageSelection::default(),
+            format: OutputFormat::Keep,
+            name_template: "{chapter}/{page}.{ext}"
+                .parse::<NameTemplate>()
+                .unwrap_or_default(),
+            manifest: None,
+            archive: Some(self.out_file.clone()),
+            dedupe: None,
+            cmyk_to_rgb: false,
+        }
+    }
+}
+
+impl Run for Convert {
+    fn run(&self) -> Result<(), PDFConError> {
+        let from_archive = ArchiveFormat::from_path(&self.in_file)?.is_some();
+        let to_archive = ArchiveFormat::from_path(&self.out_file)?.is_some();
+
+        if from_archive && is_pdf(&self.out_file) {
+            self.to_pdf().run()
+        } else if is_pdf(&self.in_file) && to_archive {
+            self.to_archive().run()
+        } else {
+            Err(PDFConError::UnsupportedConversion(
+                self.in_file.display().to_string(),
+                self.out_file.display().to_string(),
+            ))
+        }
+    }
+}
@@ -19,12 +19,32 @@ pub enum PDFConError {
     OxiPngOptimizeError(#[from] oxipng::PngError),
     #[error("Image error {0}")]
     ImageError(#[from] image::ImageError),
+    #[error("Failed to decode CCITT fax data")]
+    CcittDecodeError,
+    #[error("Image data doesn't match its predictor {0}")]
+    PredictorError(i64),
     #[error("Failed to get buffer innner components")]
     BufferInnerError,
     #[error("Rayon threadpool creation error {0}")]
     ThreadPoolCreationError(#[from] rayon::ThreadPoolBuildError),
     #[error("lopdf error {0}")]
     LopdfError(#[from] lopdf::Error),
+    #[error("Zip error {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("{0} archives aren't supported. Repack them as zip or tar")]
+    UnsupportedArchive(String),
+    #[error("Can't convert {0} to {1}. One has to be a pdf and the other a zip, cbz or tar")]
+    UnsupportedConversion(String, String),
+    #[error("JSON error {0}")]
+    JsonError(#[from] serde_json::Error),
     #[error("Error encountered when unpacking pdf")]
     UnpackError,
+    #[error("The password does not open this pdf")]
+    InvalidPassword,
+    #[error("Every page would be removed")]
+    NoPagesLeft,
+    #[error("Table of contents error, {0}")]
+    TocError(String),
+    #[error("Unsupported image, {0}")]
+    UnsupportedImage(String),
 }
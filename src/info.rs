@@ -0,0 +1,382 @@
+use crate::Run;
+use crate::error::PDFConError;
+use crate::manifest::{color_space_name, filter_names};
+use crate::security;
+use crate::unpack::page_images;
+use lopdf::{Dictionary, Document, Object, ObjectId, Permissions};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Info {
+    pub in_file: PathBuf,
+    pub password: Option<String>,
+    pub json: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct EncryptionReport {
+    pub version: i64,
+    pub revision: i64,
+    /// Crypt filter method of streams, e.g. AESV2. Older handlers always use RC4
+    pub method: String,
+    pub key_bits: Option<usize>,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageGroup {
+    pub filters: Vec<String>,
+    pub color_space: Option<String>,
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Report {
+    pub file: PathBuf,
+    pub file_size: u64,
+    pub version: String,
+    pub pages: usize,
+    pub encryption: Option<EncryptionReport>,
+    pub info: BTreeMap<String, String>,
+    pub xmp: Option<String>,
+    /// None when the pdf is encrypted and wasn't given its password
+    #[serde(flatten)]
+    pub image_stats: Option<ImageStats>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageStats {
+    pub images: usize,
+    /// Stored size of every image stream used by a page
+    pub image_bytes: usize,
+    pub image_share: f64,
+    pub image_groups: Vec<ImageGroup>,
+    pub pages_without_images: Vec<u32>,
+}
+
+fn encryption_report(doc: &Document) -> Option<EncryptionReport> {
+    let Some(state) = doc.encryption_state.as_ref() else {
+        return locked_encryption_report(doc.get_encrypted().ok()?);
+    };
+    let method = match state.version() {
+        4 | 5 => state
+            .crypt_filters()
+            .get(state.default_stream_filter())
+            .map(|filter| String::from_utf8_lossy(filter.method()).into_owned())
+            .unwrap_or_else(|| String::from("Identity")),
+        _ => String::from("RC4"),
+    };
+    let key_bits = match state.version() {
+        1 => Some(40),
+        5 => Some(256),
+        _ => state.key_length(),
+    };
+
+    Some(EncryptionReport {
+        version: state.version(),
+        revision: state.revision(),
+        method,
+        key_bits,
+        permissions: state
+            .permissions()
+            .iter_names()
+            .map(|(name, _)| name.to_lowercase())
+            .collect(),
+    })
+}
+
+/// Describe the encryption of a pdf that couldn't be decrypted from its Encrypt
+/// dictionary, which is never encrypted itself
+fn locked_encryption_report(encrypt: &Dictionary) -> Option<EncryptionReport> {
+    let version = encrypt.get(b"V").and_then(Object::as_i64).unwrap_or(0);
+    let method = match version {
+        4 | 5 => {
+            let filter = encrypt
+                .get(b"StmF")
+                .and_then(Object::as_name)
+                .unwrap_or(b"Identity");
+            encrypt
+                .get(b"CF")
+                .and_then(Object::as_dict)
+                .and_then(|filters| filters.get(filter))
+                .and_then(Object::as_dict)
+                .and_then(|filter| filter.get(b"CFM"))
+                .and_then(Object::as_name)
+                .map_or_else(
+                    |_| String::from("Identity"),
+                    |method| String::from_utf8_lossy(method).into_owned(),
+                )
+        }
+        _ => String::from("RC4"),
+    };
+    let key_bits = match version {
+        1 => Some(40),
+        4 => Some(128),
+        5 => Some(256),
+        _ => Some(
+            encrypt
+                .get(b"Length")
+                .and_then(Object::as_i64)
+                .map_or(40, |l| l as usize),
+        ),
+    };
+    // P is a signed 32 bit integer holding the permission bits
+    let permissions = encrypt.get(b"P").and_then(Object::as_i64).ok()? as u32;
+
+    Some(EncryptionReport {
+        version,
+        revision: encrypt.get(b"R").and_then(Object::as_i64).unwrap_or(0),
+        method,
+        key_bits,
+        permissions: Permissions::from_bits_truncate(permissions as u64)
+            .iter_names()
+            .map(|(name, _)| name.to_lowercase())
+            .collect(),
+    })
+}
+
+fn info_dictionary(doc: &Document) -> BTreeMap<String, String> {
+    let Ok(info) = doc
+        .trailer
+        .get(b"Info")
+        .and_then(|info| doc.dereference(info))
+        .and_then(|(_, info)| info.as_dict())
+    else {
+        return BTreeMap::new();
+    };
+
+    info.iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Object::String(..) => lopdf::decode_text_string(value).unwrap_or_default(),
+                Object::Name(name) => String::from_utf8_lossy(name).into_owned(),
+                other => format!("{:?}", other),
+            };
+            (String::from_utf8_lossy(key).into_owned(), value)
+        })
+        .collect()
+}
+
+fn xmp_metadata(doc: &Document) -> Option<String> {
+    let metadata = doc.catalog().ok()?.get(b"Metadata").ok()?;
+    let stream = doc.dereference(metadata).ok()?.1.as_stream().ok()?;
+    let content = stream
+        .decompressed_content()
+        .unwrap_or_else(|_| stream.content.clone());
+    Some(String::from_utf8_lossy(&content).into_owned())
+}
+
+pub fn report(doc: &Document, file: PathBuf, file_size: u64) -> Result<Report, PDFConError> {
+    let pages = doc.get_pages();
+
+    // Strings and streams of a pdf that is still encrypted can't be read
+    let locked = doc.is_encrypted();
+    Ok(Report {
+        file,
+        file_size,
+        version: doc.version.clone(),
+        pages: pages.len(),
+        encryption: encryption_report(doc),
+        info: if locked {
+            BTreeMap::new()
+        } else {
+            info_dictionary(doc)
+        },
+        xmp: if locked { None } else { xmp_metadata(doc) },
+        image_stats: if locked {
+            None
+        } else {
+            Some(image_stats(doc, &pages, file_size)?)
+        },
+    })
+}
+
+fn image_stats(
+    doc: &Document,
+    pages: &BTreeMap<u32, ObjectId>,
+    file_size: u64,
+) -> Result<ImageStats, PDFConError> {
+    let mut seen = BTreeSet::new();
+    let mut groups: BTreeMap<(Vec<String>, Option<String>), (usize, usize)> = BTreeMap::new();
+    let mut pages_without_images = Vec::new();
+    for (page_num, page_id) in pages {
+        let images = page_images(doc, *page_id)?;
+        if images.is_empty() {
+            pages_without_images.push(*page_num);
+        }
+
+        // Images shared between pages are only counted once
+        for (_name, id) in images {
+            if !seen.insert(id) {
+                continue;
+            }
+            let stream = doc.get_object(id)?.as_stream()?;
+            let group = groups
+                .entry((filter_names(&stream.dict), color_space_name(&stream.dict)))
+                .or_default();
+            group.0 += 1;
+            group.1 += stream.content.len();
+        }
+    }
+
+    let image_groups: Vec<ImageGroup> = groups
+        .into_iter()
+        .map(|((filters, color_space), (count, bytes))| ImageGroup {
+            filters,
+            color_space,
+            count,
+            bytes,
+        })
+        .collect();
+    let image_bytes = image_groups.iter().map(|g| g.bytes).sum();
+
+    Ok(ImageStats {
+        images: seen.len(),
+        image_bytes,
+        image_share: if file_size == 0 {
+            0.0
+        } else {
+            image_bytes as f64 / file_size as f64
+        },
+        image_groups,
+        pages_without_images,
+    })
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Collapse sorted page numbers into ranges like 3, 7-9
+fn page_list(pages: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &page in pages {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == page => *end = page,
+            _ => ranges.push((page, page)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_report(report: &Report) {
+    let row = |label: &str, value: &str| println!("{:<22}{}", label, value);
+
+    row("File", &report.file.display().to_string());
+    row("File size", &human_bytes(report.file_size));
+    row("PDF version", &report.version);
+    row("Pages", &report.pages.to_string());
+    match &report.encryption {
+        Some(e) => row(
+            "Encryption",
+            &format!(
+                "{} {} bit (V{} R{}), allows: {}",
+                e.method,
+                e.key_bits.map_or(String::from("?"), |b| b.to_string()),
+                e.version,
+                e.revision,
+                e.permissions.join(", ")
+            ),
+        ),
+        None => row("Encryption", "none"),
+    }
+    for (key, value) in &report.info {
+        row(key, value);
+    }
+    let Some(stats) = &report.image_stats else {
+        row("Images", "encrypted, pass --password to count them");
+        return;
+    };
+    row(
+        "Images",
+        &format!(
+            "{} using {} ({:.1}% of the file)",
+            stats.images,
+            human_bytes(stats.image_bytes as u64),
+            stats.image_share * 100.0
+        ),
+    );
+    if !stats.pages_without_images.is_empty() {
+        row(
+            "Pages without images",
+            &page_list(&stats.pages_without_images),
+        );
+    }
+
+    if !stats.image_groups.is_empty() {
+        println!();
+        println!(
+            "{:<30}{:<16}{:>8}{:>12}",
+            "Filters", "Colour space", "Count", "Bytes"
+        );
+        for group in &stats.image_groups {
+            let filters = if group.filters.is_empty() {
+                String::from("none")
+            } else {
+                group.filters.join(" ")
+            };
+            println!(
+                "{:<30}{:<16}{:>8}{:>12}",
+                filters,
+                group.color_space.as_deref().unwrap_or("none"),
+                group.count,
+                human_bytes(group.bytes as u64)
+            );
+        }
+    }
+
+    if let Some(xmp) = &report.xmp {
+        println!();
+        println!("XMP metadata");
+        println!("{}", xmp.trim());
+    }
+}
+
+impl Run for Info {
+    fn run(&self) -> Result<(), PDFConError> {
+        let file_size = std::fs::metadata(&self.in_file)?.len();
+        let mut document = Document::load(&self.in_file)?;
+
+        // lopdf already opens documents with an empty user password. Without a password
+        // the rest are still described as far as their unencrypted parts allow
+        if document.is_encrypted() {
+            match security::decrypt(&mut document, self.password.as_deref().unwrap_or("")) {
+                Err(PDFConError::InvalidPassword) if self.password.is_none() => {}
+                result => result?,
+            }
+        }
+
+        let report = report(&document, self.in_file.clone(), file_size)?;
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_report(&report);
+        }
+
+        Ok(())
+    }
+}
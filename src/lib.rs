@@ -1,10 +1,24 @@
+pub mod archive;
 pub mod cli;
 pub mod command;
 pub mod constants;
+pub mod convert;
 pub mod error;
+pub mod info;
+pub mod list;
+pub mod manifest;
+pub mod merge;
+pub mod name_template;
+pub mod optimize;
+pub mod outline;
 pub mod pack;
+pub mod page_label;
+pub mod page_range;
+pub mod pages;
 pub mod pdf_image;
 pub mod progress;
+pub mod security;
+pub mod split;
 pub mod unpack;
 
 pub trait Run {
@@ -0,0 +1,342 @@
+use crate::Run;
+use crate::error::PDFConError;
+use crate::info::human_bytes;
+use crate::manifest::{color_space_name, filter_names};
+use crate::security;
+use crate::unpack::page_images;
+use lopdf::content::Content;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct List {
+    pub in_file: PathBuf,
+    pub password: Option<String>,
+}
+
+/// An image drawn with BI ... ID ... EI inside a content stream
+pub struct InlineImage {
+    pub dict: Dictionary,
+    pub data_len: usize,
+}
+
+fn is_whitespace(c: u8) -> bool {
+    b" \t\r\n\x0c\0".contains(&c)
+}
+
+fn is_delimiter(c: u8) -> bool {
+    b"()<>[]{}/%".contains(&c)
+}
+
+// Inline image dictionaries may use abbreviated keys
+fn get_abbr<'a>(dict: &'a Dictionary, abbr: &[u8], key: &[u8]) -> Option<&'a Object> {
+    dict.get(abbr).or_else(|_| dict.get(key)).ok()
+}
+
+/// Find inline images in a content stream without decoding their data. lopdf refuses
+/// whole content streams that contain filtered inline images, so this walks the tokens
+/// itself and only skips over strings, comments and image data.
+pub fn inline_images(content: &[u8]) -> Vec<InlineImage> {
+    let mut images = Vec::new();
+    let mut i = 0;
+    while i < content.len() {
+        match content[i] {
+            c if is_whitespace(c) => i += 1,
+            b'%' => {
+                while i < content.len() && !b"\r\n".contains(&content[i]) {
+                    i += 1;
+                }
+            }
+            b'(' => {
+                let mut depth = 0;
+                while i < content.len() {
+                    match content[i] {
+                        b'\\' => i += 1,
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            b'<' if content.get(i + 1) != Some(&b'<') => {
+                while i < content.len() && content[i] != b'>' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            c if is_delimiter(c) => i += 1,
+            _ => {
+                let start = i;
+                while i < content.len() && !is_whitespace(content[i]) && !is_delimiter(content[i]) {
+                    i += 1;
+                }
+                if &content[start..i] != b"BI" {
+                    continue;
+                }
+
+                // The dictionary runs up to the ID operator
+                let Some(id) = content[i..]
+                    .windows(4)
+                    .position(|w| is_whitespace(w[0]) && &w[1..3] == b"ID" && is_whitespace(w[3]))
+                else {
+                    break;
+                };
+                let dict_end = i + id;
+                let data_start = dict_end + 4;
+                let dict = Content::decode(&[b"<<", &content[i..dict_end], b">> ID"].concat())
+                    .ok()
+                    .and_then(|c| c.operations.into_iter().next())
+                    .and_then(|op| op.operands.into_iter().next())
+                    .and_then(|dict| dict.as_dict().ok().cloned())
+                    .unwrap_or_default();
+
+                // Use the length when it's given, otherwise look for EI between whitespace.
+                // Negative lengths are ignored and ones past the end of the stream cut short
+                let remaining = content.len() - data_start;
+                let data_len = match get_abbr(&dict, b"L", b"Length")
+                    .and_then(|l| l.as_i64().ok())
+                    .and_then(|l| usize::try_from(l).ok())
+                {
+                    Some(len) => len.min(remaining),
+                    None => content[data_start..]
+                        .windows(4)
+                        .position(|w| {
+                            is_whitespace(w[0]) && &w[1..3] == b"EI" && is_whitespace(w[3])
+                        })
+                        .or_else(|| {
+                            content[data_start..]
+                                .ends_with(b"EI")
+                                .then(|| remaining.saturating_sub(3))
+                        })
+                        .unwrap_or(remaining),
+                };
+
+                images.push(InlineImage { dict, data_len });
+                i = data_start
+                    .saturating_add(data_len)
+                    .saturating_add(3)
+                    .min(content.len());
+            }
+        }
+    }
+    images
+}
+
+struct Row {
+    page: u32,
+    kind: &'static str,
+    name: String,
+    object_id: Option<ObjectId>,
+    width: Option<i64>,
+    height: Option<i64>,
+    bits: Option<i64>,
+    color_space: String,
+    filters: String,
+    size: usize,
+}
+
+impl Row {
+    fn from_stream(
+        page: u32,
+        kind: &'static str,
+        name: String,
+        id: ObjectId,
+        dict: &Dictionary,
+        size: usize,
+    ) -> Self {
+        let int = |key: &[u8]| dict.get(key).and_then(|v| v.as_i64()).ok();
+        let filters = filter_names(dict);
+        Self {
+            page,
+            kind,
+            name,
+            object_id: Some(id),
+            width: int(b"Width"),
+            height: int(b"Height"),
+            bits: int(b"BitsPerComponent"),
+            color_space: color_space_name(dict).unwrap_or_else(|| String::from("-")),
+            filters: if filters.is_empty() {
+                String::from("none")
+            } else {
+                filters.join(" ")
+            },
+            size,
+        }
+    }
+
+    fn from_inline(page: u32, image: &InlineImage) -> Self {
+        let dict = &image.dict;
+        let int = |abbr: &[u8], key: &[u8]| get_abbr(dict, abbr, key).and_then(|v| v.as_i64().ok());
+        let name = |object: &Object| {
+            object
+                .as_name()
+                .ok()
+                .map(|n| String::from_utf8_lossy(n).into_owned())
+        };
+        let filters: Vec<String> = match get_abbr(dict, b"F", b"Filter") {
+            Some(Object::Array(filters)) => filters.iter().filter_map(name).collect(),
+            Some(filter) => name(filter).into_iter().collect(),
+            None => Vec::new(),
+        };
+        let color_space = match get_abbr(dict, b"CS", b"ColorSpace") {
+            Some(Object::Array(space)) => space.first().and_then(name),
+            Some(space) => name(space),
+            None => None,
+        };
+        Self {
+            page,
+            kind: "inline",
+            name: String::from("-"),
+            object_id: None,
+            width: int(b"W", b"Width"),
+            height: int(b"H", b"Height"),
+            bits: int(b"BPC", b"BitsPerComponent"),
+            color_space: color_space.unwrap_or_else(|| String::from("-")),
+            filters: if filters.is_empty() {
+                String::from("none")
+            } else {
+                filters.join(" ")
+            },
+            size: image.data_len,
+        }
+    }
+}
+
+fn rows(doc: &Document) -> Result<Vec<Row>, PDFConError> {
+    let mut rows = Vec::new();
+    for (page_num, page_id) in doc.get_pages() {
+        for (name, id) in page_images(doc, page_id)? {
+            let stream = doc.get_object(id)?.as_stream()?;
+            let is_mask = stream
+                .dict
+                .get(b"ImageMask")
+                .and_then(|m| m.as_bool())
+                .unwrap_or(false);
+            rows.push(Row::from_stream(
+                page_num,
+                if is_mask { "stencil" } else { "image" },
+                String::from_utf8_lossy(name).into_owned(),
+                id,
+                &stream.dict,
+                stream.content.len(),
+            ));
+
+            // Soft masks are separate images that are only reachable through their parent
+            if let Ok(smask_id) = stream.dict.get(b"SMask").and_then(|s| s.as_reference()) {
+                let smask = doc.get_object(smask_id)?.as_stream()?;
+                rows.push(Row::from_stream(
+                    page_num,
+                    "smask",
+                    String::from("-"),
+                    smask_id,
+                    &smask.dict,
+                    smask.content.len(),
+                ));
+            }
+        }
+
+        let content = doc.get_page_content(page_id).unwrap_or_default();
+        for image in inline_images(&content) {
+            rows.push(Row::from_inline(page_num, &image));
+        }
+    }
+    Ok(rows)
+}
+
+fn print_rows(rows: &[Row]) {
+    let optional = |v: Option<i64>| v.map_or(String::from("-"), |v| v.to_string());
+
+    println!(
+        "{:>5} {:>5} {:<8} {:<8} {:>7} {:>3} {:>6} {:>6} {:>4} {:<14} {:<24} {:>10}",
+        "page",
+        "num",
+        "type",
+        "name",
+        "object",
+        "ID",
+        "width",
+        "height",
+        "bpc",
+        "color",
+        "filters",
+        "size"
+    );
+    println!("{}", "-".repeat(112));
+    for (num, row) in rows.iter().enumerate() {
+        let (object, generation) = match row.object_id {
+            Some((object, generation)) => (object.to_string(), generation.to_string()),
+            None => (String::from("-"), String::from("-")),
+        };
+        println!(
+            "{:>5} {:>5} {:<8} {:<8} {:>7} {:>3} {:>6} {:>6} {:>4} {:<14} {:<24} {:>10}",
+            row.page,
+            num,
+            row.kind,
+            row.name,
+            object,
+            generation,
+            optional(row.width),
+            optional(row.height),
+            optional(row.bits),
+            row.color_space,
+            row.filters,
+            human_bytes(row.size as u64)
+        );
+    }
+}
+
+impl Run for List {
+    fn run(&self) -> Result<(), PDFConError> {
+        let mut document = Document::load(&self.in_file)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
+
+        print_rows(&rows(&document)?);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_inline_images_by_their_end_marker() {
+        let content = b"q 10 0 0 10 0 0 cm BI /W 2 /H 1 /BPC 8 /CS /G ID \x00EI\xff EI Q";
+        let images = inline_images(content);
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].data_len, 4);
+        assert_eq!(images[0].dict.get(b"W").unwrap().as_i64().unwrap(), 2);
+    }
+
+    #[test]
+    fn uses_the_length_when_given() {
+        let content = b"BI /W 1 /H 1 /L 3 ID EI\x00 EI BI /W 2 /H 2 ID abcd EI";
+        let images = inline_images(content);
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[0].data_len, 3);
+        assert_eq!(images[1].data_len, 4);
+    }
+
+    #[test]
+    fn skips_strings_and_comments() {
+        let content = b"(BI in a string) Tj % BI in a comment\nBI /W 1 /H 1 ID x EI";
+        assert_eq!(inline_images(content).len(), 1);
+    }
+
+    #[test]
+    fn survives_bad_lengths_and_truncated_data() {
+        assert_eq!(inline_images(b"BI /L -1 ID abc EI")[0].data_len, 3);
+        assert_eq!(inline_images(b"BI /L 1000 ID abc")[0].data_len, 3);
+        assert_eq!(inline_images(b"BI ID EI")[0].data_len, 0);
+        assert!(inline_images(b"BI /W 1").is_empty());
+    }
+}
@@ -1,4 +1,3 @@
-use env_logger;
 use pdfcon::Run;
 use pdfcon::command;
 use pdfcon::error::PDFConError;
@@ -22,5 +21,13 @@ fn main() -> Result<(), PDFConError> {
             p.run()
         }
         command::PDFCon::UNPACK(up) => up.run(),
+        command::PDFCon::INFO(info) => info.run(),
+        command::PDFCon::LIST(list) => list.run(),
+        command::PDFCon::OPTIMIZE(optimize) => optimize.run(),
+        command::PDFCon::MERGE(merge) => merge.run(),
+        command::PDFCon::SPLIT(split) => split.run(),
+        command::PDFCon::PAGES(pages) => pages.run(),
+        command::PDFCon::CONVERT(convert) => convert.run(),
+        command::PDFCon::OUTLINE(outline) => outline.run(),
     }
 }
@@ -0,0 +1,193 @@
+use crate::error::PDFConError;
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// Where unpack writes the record of extracted images
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ManifestOutput {
+    /// One json array written once every image is extracted
+    File(PathBuf),
+    /// One json object per line on stdout
+    JsonLines,
+}
+
+/// A transformation matrix `[a b c d e f]` as used by the `cm` operator
+pub type Matrix = [f32; 6];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+fn multiply(m: &Matrix, ctm: &Matrix) -> Matrix {
+    [
+        m[0] * ctm[0] + m[1] * ctm[2],
+        m[0] * ctm[1] + m[1] * ctm[3],
+        m[2] * ctm[0] + m[3] * ctm[2],
+        m[2] * ctm[1] + m[3] * ctm[3],
+        m[4] * ctm[0] + m[5] * ctm[2] + ctm[4],
+        m[4] * ctm[1] + m[5] * ctm[3] + ctm[5],
+    ]
+}
+
+/// Follow `q`, `Q` and `cm` through a page's content stream and record the matrix in
+/// effect the first time each XObject is drawn. Images map the unit square through it
+pub fn placements(doc: &Document, page_id: ObjectId) -> HashMap<Vec<u8>, Matrix> {
+    let mut placements = HashMap::new();
+    let Ok(content) = doc.get_and_decode_page_content(page_id) else {
+        return placements;
+    };
+
+    let mut stack = Vec::new();
+    let mut ctm = IDENTITY;
+    for operation in content.operations {
+        match operation.operator.as_str() {
+            "q" => stack.push(ctm),
+            "Q" => ctm = stack.pop().unwrap_or(IDENTITY),
+            "cm" => {
+                let values: Vec<f32> = operation
+                    .operands
+                    .iter()
+                    .filter_map(|o| o.as_float().ok())
+                    .collect();
+                if let Ok(m) = <Matrix>::try_from(values) {
+                    ctm = multiply(&m, &ctm);
+                }
+            }
+            "Do" => {
+                if let Some(Ok(name)) = operation.operands.first().map(|o| o.as_name()) {
+                    placements.entry(name.to_vec()).or_insert(ctm);
+                }
+            }
+            _ => {}
+        }
+    }
+    placements
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ManifestEntry {
+    pub page: u32,
+    /// Position of the image among the images on its page, starting at 1
+    pub index: usize,
+    pub object_id: (u32, u16),
+    pub name: String,
+    pub filters: Vec<String>,
+    pub color_space: Option<String>,
+    pub bits_per_component: Option<i64>,
+    pub width: u32,
+    pub height: u32,
+    pub placement: Option<Matrix>,
+    /// Horizontal and vertical resolution the image is shown at on the page
+    pub dpi: Option<(f32, f32)>,
+    pub path: PathBuf,
+    pub sha256: String,
+    /// The first copy of an image that was left out as a duplicate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<PathBuf>,
+}
+
+fn name_string(object: &Object) -> Option<String> {
+    object
+        .as_name()
+        .ok()
+        .map(|n| String::from_utf8_lossy(n).into_owned())
+}
+
+/// Names of the filters applied to a stream, in the order they appear
+pub fn filter_names(dict: &Dictionary) -> Vec<String> {
+    match dict.get(b"Filter") {
+        Ok(Object::Array(filters)) => filters.iter().filter_map(name_string).collect(),
+        Ok(filter) => name_string(filter).into_iter().collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Name of an image's colour space. Array colour spaces like ICCBased and Indexed are
+/// named by their family
+pub fn color_space_name(dict: &Dictionary) -> Option<String> {
+    match dict.get(b"ColorSpace") {
+        Ok(Object::Array(space)) => space.first().and_then(name_string),
+        Ok(space) => name_string(space),
+        Err(_) => None,
+    }
+}
+
+impl ManifestEntry {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        page: u32,
+        index: usize,
+        object_id: ObjectId,
+        name: &[u8],
+        dict: &Dictionary,
+        placement: Option<Matrix>,
+        path: PathBuf,
+        contents: &[u8],
+    ) -> Result<Self, PDFConError> {
+        let width = dict.get(b"Width")?.as_i64()? as u32;
+        let height = dict.get(b"Height")?.as_i64()? as u32;
+
+        let dpi = placement.and_then(|m| {
+            let shown_width = m[0].hypot(m[1]);
+            let shown_height = m[2].hypot(m[3]);
+            if shown_width == 0.0 || shown_height == 0.0 {
+                return None;
+            }
+            Some((
+                width as f32 * 72.0 / shown_width,
+                height as f32 * 72.0 / shown_height,
+            ))
+        });
+
+        Ok(Self {
+            page,
+            index,
+            object_id,
+            name: String::from_utf8_lossy(name).into_owned(),
+            filters: filter_names(dict),
+            color_space: color_space_name(dict),
+            bits_per_component: dict.get(b"BitsPerComponent").and_then(|b| b.as_i64()).ok(),
+            width,
+            height,
+            placement,
+            dpi,
+            sha256: sha256_hex(contents),
+            path,
+            duplicate_of: None,
+        })
+    }
+}
+
+fn sha256_hex(contents: &[u8]) -> String {
+    Sha256::digest(contents)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn write(entries: &[ManifestEntry], output: &ManifestOutput) -> Result<(), PDFConError> {
+    match output {
+        ManifestOutput::File(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path)?;
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer_pretty(&mut writer, entries)?;
+            writer.write_all(b"\n")?;
+            writer.flush()?;
+        }
+        ManifestOutput::JsonLines => {
+            let mut writer = BufWriter::new(std::io::stdout().lock());
+            for entry in entries {
+                serde_json::to_writer(&mut writer, entry)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
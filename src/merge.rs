@@ -0,0 +1,261 @@
+use crate::Run;
+use crate::archive::ArchiveFormat;
+use crate::constants::tick_speed;
+use crate::error::PDFConError;
+use crate::pack::Pack;
+use crate::pdf_image::optimize::{ColorAnalysis, JpegOptions, ScanCleanup};
+use crate::pdf_image::resample::Resample;
+use crate::progress::{close_bar, spinner};
+use crate::security;
+use log::warn;
+use lopdf::{Dictionary, Document, Object, ObjectId, dictionary};
+use std::collections::HashSet;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+/// Page attributes that a page takes from the page tree when it doesn't set them itself
+const INHERITABLE: [&[u8]; 4] = [b"Resources", b"MediaBox", b"CropBox", b"Rotate"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Merge {
+    pub threads: usize,
+    /// Pdfs, image directories and archives in the order their pages should appear
+    pub sources: Vec<PathBuf>,
+    pub out_file: PathBuf,
+    /// Used for every encrypted source pdf
+    pub password: Option<String>,
+    // Settings for packing image directories
+    pub optimize: bool,
+    pub jpeg_options: JpegOptions,
+    pub resample: Resample,
+    pub color_analysis: ColorAnalysis,
+}
+
+/// The pages one source adds to the merged document
+struct Section {
+    title: String,
+    first_page: ObjectId,
+    /// First and last top level items of the source's own outline and how many there are
+    outline: Option<(ObjectId, ObjectId, i64)>,
+}
+
+/// Copy what a page inherits from its ancestors onto the page itself. The page tree
+/// nodes it came from are replaced when the documents are joined
+pub fn flatten_inherited(doc: &mut Document, page_id: ObjectId) -> Result<(), PDFConError> {
+    let page = doc.get_dictionary(page_id)?;
+    let mut missing: Vec<&[u8]> = INHERITABLE
+        .into_iter()
+        .filter(|key| !page.has(key))
+        .collect();
+    let mut inherited = Vec::new();
+    let mut parent = page.get(b"Parent").and_then(Object::as_reference).ok();
+
+    // Guard against page trees that loop back on themselves
+    let mut seen = HashSet::new();
+    while let Some(id) = parent.filter(|id| !missing.is_empty() && seen.insert(*id)) {
+        let Ok(node) = doc.get_dictionary(id) else {
+            break;
+        };
+        missing.retain(|key| match node.get(key) {
+            Ok(value) => {
+                inherited.push((key.to_vec(), value.clone()));
+                false
+            }
+            Err(_) => true,
+        });
+        parent = node.get(b"Parent").and_then(Object::as_reference).ok();
+    }
+
+    let page = doc.get_dictionary_mut(page_id)?;
+    for (key, value) in inherited {
+        page.set(key, value);
+    }
+    Ok(())
+}
+
+/// Top level items of a document's outline. None when it has no outline
+fn outline_items(doc: &Document) -> Option<(ObjectId, ObjectId, i64)> {
+    let outlines = doc.catalog().ok()?.get(b"Outlines").ok()?;
+    let outlines = doc.dereference(outlines).ok()?.1.as_dict().ok()?;
+    let first = outlines.get(b"First").ok()?.as_reference().ok()?;
+    let last = outlines.get(b"Last").ok()?.as_reference().ok()?;
+    let count = outlines
+        .get(b"Count")
+        .and_then(Object::as_i64)
+        .map_or(1, i64::abs);
+    Some((first, last, count))
+}
+
+/// Point every item in a chain of outline siblings at a new parent
+fn reparent_outline(doc: &mut Document, first: ObjectId, parent: ObjectId) {
+    let mut seen = HashSet::new();
+    let mut next = Some(first);
+    while let Some(id) = next.filter(|id| seen.insert(*id)) {
+        let Ok(item) = doc.get_dictionary_mut(id) else {
+            break;
+        };
+        item.set("Parent", parent);
+        next = item.get(b"Next").and_then(Object::as_reference).ok();
+    }
+}
+
+impl Merge {
+    fn load_source(&self, source: &Path) -> Result<Document, PDFConError> {
+        if source.is_dir() || ArchiveFormat::from_path(source)?.is_some() {
+            let pack = Pack {
+                optimize: self.optimize,
+                jpeg_options: self.jpeg_options,
+                resample: self.resample,
+                color_analysis: self.color_analysis,
+                cleanup: ScanCleanup::default(),
+                encryption: None,
+                threads: self.threads,
+                input: source.to_path_buf(),
+                out_file: self.out_file.clone(),
+                max_pages: None,
+                max_size: None,
+                toc: None,
+                page_labels: None,
+                blank_pages: None,
+            };
+            return pack.build_document();
+        }
+
+        // Add spinner to show program is doing something
+        let spnr = spinner(&format!("Parsing {}", source.display()), tick_speed());
+        let mut document = Document::load(source)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
+
+        // Finish bar and display message
+        close_bar(spnr, " ● Parsing Complete! ");
+        Ok(document)
+    }
+
+    fn merge(&self) -> Result<Document, PDFConError> {
+        let mut merged = Document::with_version("1.7");
+        let mut kids: Vec<ObjectId> = Vec::new();
+        let mut sections = Vec::new();
+
+        for source in &self.sources {
+            let mut doc = self.load_source(source)?;
+
+            // Give every source its own range of object ids
+            doc.renumber_objects_with(merged.max_id + 1);
+            merged.max_id = doc.max_id;
+
+            let pages: Vec<ObjectId> = doc.get_pages().into_values().collect();
+            let Some(&first_page) = pages.first() else {
+                warn!("{} has no pages. Skipping it", source.display());
+                continue;
+            };
+            for page_id in &pages {
+                flatten_inherited(&mut doc, *page_id)?;
+            }
+
+            sections.push(Section {
+                title: source
+                    .file_stem()
+                    .or(source.file_name())
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                first_page,
+                outline: outline_items(&doc),
+            });
+            kids.extend(pages);
+
+            // The old catalogs and page tree nodes stay behind until they're pruned
+            merged.objects.extend(doc.objects);
+        }
+
+        let pages_id = merged.new_object_id();
+        for page_id in &kids {
+            merged.get_dictionary_mut(*page_id)?.set("Parent", pages_id);
+        }
+        let pages_dict = dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as u32,
+            "Kids" => kids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+        };
+        merged
+            .objects
+            .insert(pages_id, Object::Dictionary(pages_dict));
+
+        // Every source gets an outline item for its first page. Outlines the source
+        // already had are kept underneath it
+        let outlines_id = merged.new_object_id();
+        let item_ids: Vec<ObjectId> = sections.iter().map(|_| merged.new_object_id()).collect();
+        for (i, (section, item_id)) in sections.iter().zip(&item_ids).enumerate() {
+            let mut item = dictionary! {
+                "Title" => lopdf::text_string(&section.title),
+                "Parent" => outlines_id,
+                "Dest" => vec![section.first_page.into(), "Fit".into()],
+            };
+            if i > 0 {
+                item.set("Prev", item_ids[i - 1]);
+            }
+            if let Some(next) = item_ids.get(i + 1) {
+                item.set("Next", *next);
+            }
+            if let Some((first, last, count)) = section.outline {
+                reparent_outline(&mut merged, first, *item_id);
+                item.set("First", first);
+                item.set("Last", last);
+                // Negative counts leave the item closed
+                item.set("Count", -count);
+            }
+            merged.objects.insert(*item_id, Object::Dictionary(item));
+        }
+
+        let mut catalog = dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        };
+        if let (Some(first), Some(last)) = (item_ids.first(), item_ids.last()) {
+            let outlines: Dictionary = dictionary! {
+                "Type" => "Outlines",
+                "First" => *first,
+                "Last" => *last,
+                "Count" => item_ids.len() as i64,
+            };
+            merged
+                .objects
+                .insert(outlines_id, Object::Dictionary(outlines));
+            catalog.set("Outlines", outlines_id);
+            catalog.set("PageMode", "UseOutlines");
+        }
+
+        let catalog_id = merged.add_object(catalog);
+        merged.trailer.set("Root", catalog_id);
+
+        // Drop the old catalogs, page trees and anything else only they referenced
+        merged.prune_objects();
+
+        Ok(merged)
+    }
+}
+
+impl Run for Merge {
+    fn run(&self) -> Result<(), PDFConError> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build_global()?;
+
+        let mut document = self.merge()?;
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.out_file)?;
+        let mut writer = BufWriter::new(file);
+
+        document.save_to(&mut writer)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use std::str::FromStr;
+
+/// Output file names built from `{stem}`, `{page}`, `{label}`, `{index}`, `{objnum}`,
+/// `{chapter}` and `{ext}`. `{page}` is zero padded so names sort in page order and
+/// `{index}` counts the images on a page from 1. `{label}` is the page label a viewer
+/// shows, or the page number when the pdf has none. `{chapter}` is a numbered folder
+/// name made from the top level outline item the page falls under. Pages before the
+/// first item have none
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameTemplate(String);
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        Self(String::from("{page}.{ext}"))
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if rest[..start].contains('}') {
+                return Err(String::from("unopened \"}\""));
+            }
+            let Some(len) = rest[start..].find('}') else {
+                return Err(String::from("unclosed \"{\""));
+            };
+            let placeholder = &rest[start + 1..start + len];
+            if !["stem", "page", "label", "index", "objnum", "chapter", "ext"]
+                .contains(&placeholder)
+            {
+                return Err(format!("unknown placeholder \"{{{}}}\"", placeholder));
+            }
+            rest = &rest[start + len + 1..];
+        }
+        if rest.contains('}') {
+            return Err(String::from("unopened \"}\""));
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl NameTemplate {
+    pub fn uses(&self, placeholder: &str) -> bool {
+        self.0.contains(&format!("{{{}}}", placeholder))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn render(
+        &self,
+        stem: &str,
+        page_num: u32,
+        total_pages: usize,
+        label: &str,
+        index: usize,
+        objnum: u32,
+        chapter: &str,
+        ext: &str,
+    ) -> String {
+        // Calculate needed zero padding for page names
+        let padding_width = (total_pages.ilog10() + 1) as usize;
+        let name = self
+            .0
+            .replace("{stem}", stem)
+            .replace(
+                "{page}",
+                &format!("{:0width$}", page_num, width = padding_width),
+            )
+            .replace("{label}", label)
+            .replace("{index}", &index.to_string())
+            .replace("{objnum}", &objnum.to_string())
+            .replace("{chapter}", chapter)
+            .replace("{ext}", ext);
+
+        // An empty chapter leaves the file at the level above instead of in a blank folder
+        name.split('/')
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_template_rejects_unknown_and_unbalanced_placeholders() {
+        assert!("{page}_{index}.{ext}".parse::<NameTemplate>().is_ok());
+        assert!("{page}_{size}.{ext}".parse::<NameTemplate>().is_err());
+        assert!("{page.{ext}".parse::<NameTemplate>().is_err());
+        assert!("page}.{ext}".parse::<NameTemplate>().is_err());
+    }
+
+    #[test]
+    fn name_template_fills_every_placeholder() {
+        let template: NameTemplate = "{stem}_{page}_{label}_{index}_{objnum}.{ext}"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            template.render("scan", 7, 120, "vii", 2, 43, "", "png"),
+            "scan_007_vii_2_43.png"
+        );
+    }
+
+    #[test]
+    fn name_template_leaves_out_an_empty_chapter() {
+        let template: NameTemplate = "{chapter}/{page}.{ext}".parse().unwrap();
+        assert_eq!(template.render("scan", 3, 9, "3", 1, 5, "", "jpg"), "3.jpg");
+        assert_eq!(
+            template.render("scan", 3, 9, "3", 1, 5, "1 Intro", "jpg"),
+            "1 Intro/3.jpg"
+        );
+    }
+
+    #[test]
+    fn name_template_default_is_the_padded_page() {
+        assert_eq!(
+            NameTemplate::default().render("scan", 4, 10, "4", 1, 5, "", "png"),
+            "04.png"
+        );
+        assert!(NameTemplate::default().uses("page"));
+        assert!(!NameTemplate::default().uses("chapter"));
+    }
+}
@@ -0,0 +1,417 @@
+use crate::Run;
+use crate::constants::tick_speed;
+use crate::error::PDFConError;
+use crate::info::human_bytes;
+use crate::manifest::{color_space_name, filter_names};
+use crate::pdf_image::optimize::{
+    ColorAnalysis, ImageData, JpegOptions, ReducedImage, ScanCleanup, deflate_predicted,
+    optimize_jpeg_mem, reduce_colors, transform_jpeg_mem,
+};
+use crate::pdf_image::resample::Resample;
+use crate::pdf_image::{self, PDFConColorSpace, compress_zlib};
+use crate::progress::{bar, close_bar, spinner, update_end_cap};
+use crate::security;
+use flate2::Compression;
+use indicatif::ParallelProgressIterator;
+use log::{debug, error};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Optimize {
+    pub threads: usize,
+    pub in_file: PathBuf,
+    pub out_file: PathBuf,
+    pub password: Option<String>,
+    pub jpeg_options: JpegOptions,
+    pub resample: Resample,
+    pub color_analysis: ColorAnalysis,
+}
+
+/// New data for an image stream and the dictionary entries that describe it
+struct Replacement {
+    content: Vec<u8>,
+    filter: &'static str,
+    decode_parms: Option<Dictionary>,
+    /// Only set when the samples were converted. Otherwise the old colour space stays
+    color_space: Option<PDFConColorSpace>,
+    width: u32,
+    height: u32,
+}
+
+impl Replacement {
+    fn from_image_data(data: ImageData) -> Self {
+        let (content, width, height, color_space, filter) = match data {
+            ImageData::JPEG(content, width, height, color_space) => {
+                (content, width, height, color_space, "DCTDecode")
+            }
+            ImageData::PNG(content, width, height, color_space) => {
+                (content, width, height, color_space, "FlateDecode")
+            }
+        };
+        Self {
+            content,
+            filter,
+            decode_parms: None,
+            color_space: Some(color_space),
+            width,
+            height,
+        }
+    }
+
+    fn apply(self, stream: &mut Stream) {
+        stream
+            .dict
+            .set("Filter", Object::Name(self.filter.as_bytes().to_vec()));
+        match self.decode_parms {
+            Some(parms) => stream.dict.set("DecodeParms", parms),
+            None => {
+                stream.dict.remove(b"DecodeParms");
+            }
+        }
+        if let Some(color_space) = self.color_space {
+            let (name, bits) = color_space.to_pdf_format();
+            stream.dict.set("ColorSpace", Object::Name(name));
+            stream.dict.set("BitsPerComponent", bits as i64);
+        }
+        stream.dict.set("Width", self.width as i64);
+        stream.dict.set("Height", self.height as i64);
+        stream.set_content(self.content);
+    }
+}
+
+/// Number of components per sample of colour spaces whose samples can be repacked as is
+fn components(doc: &Document, dict: &Dictionary) -> Option<u8> {
+    match dict.get(b"ColorSpace").ok()? {
+        Object::Name(name) => match name.as_slice() {
+            b"DeviceGray" => Some(1),
+            b"DeviceRGB" => Some(3),
+            b"DeviceCMYK" => Some(4),
+            _ => None,
+        },
+        Object::Array(space) => match space.first()?.as_name().ok()? {
+            b"ICCBased" => {
+                let profile = doc.dereference(space.get(1)?).ok()?.1.as_stream().ok()?;
+                Some(profile.dict.get(b"N").ok()?.as_i64().ok()? as u8)
+            }
+            b"Indexed" => Some(1),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Images that other images use as a mask. Their size and samples have to stay as they are
+fn mask_ids(doc: &Document) -> HashSet<ObjectId> {
+    doc.objects
+        .values()
+        .filter_map(|object| object.as_stream().ok())
+        .flat_map(|stream| {
+            [b"SMask".as_slice(), b"Mask".as_slice()]
+                .into_iter()
+                .filter_map(|key| stream.dict.get(key).and_then(|m| m.as_reference()).ok())
+        })
+        .collect()
+}
+
+impl Optimize {
+    fn transforms_active(&self) -> bool {
+        self.resample.is_active() || self.color_analysis.is_active()
+    }
+
+    fn recompress_jpeg(
+        &self,
+        stream: &Stream,
+        transform: bool,
+    ) -> Result<Option<Replacement>, PDFConError> {
+        let device = matches!(
+            color_space_name(&stream.dict).as_deref(),
+            Some("DeviceRGB" | "DeviceGray")
+        );
+        if transform
+            && device
+            && self.transforms_active()
+            && let Some(data) = transform_jpeg_mem(
+                &stream.content,
+                &self.jpeg_options,
+                &ScanCleanup::default(),
+                &self.resample,
+                &self.color_analysis,
+            )?
+        {
+            return Ok(Some(Replacement::from_image_data(data.image)));
+        }
+
+        // Re-encoding would lose the Adobe marker that says how CMYK is stored so those
+        // and anything with a Decode array only get their Huffman tables rebuilt
+        let mut options = self.jpeg_options;
+        options.lossless |= !device || stream.dict.has(b"Decode");
+
+        Ok(Some(Replacement {
+            content: optimize_jpeg_mem(&stream.content, &options)?,
+            filter: "DCTDecode",
+            decode_parms: None,
+            color_space: None,
+            width: stream.dict.get(b"Width")?.as_i64()? as u32,
+            height: stream.dict.get(b"Height")?.as_i64()? as u32,
+        }))
+    }
+
+    fn recompress_flate(
+        &self,
+        doc: &Document,
+        stream: &Stream,
+        transform: bool,
+    ) -> Result<Option<Replacement>, PDFConError> {
+        let bits = stream.dict.get(b"BitsPerComponent")?.as_i64()?;
+        let Some(colors) = components(doc, &stream.dict).filter(|_| bits == 8) else {
+            return Ok(None);
+        };
+
+        let width = stream.dict.get(b"Width")?.as_i64()? as u32;
+        let height = stream.dict.get(b"Height")?.as_i64()? as u32;
+        let pixels = if stream.dict.has(b"Filter") {
+            let parms = stream
+                .dict
+                .get(b"DecodeParms")
+                .and_then(|p| p.as_dict())
+                .ok();
+            pdf_image::unpredict(pdf_image::decompress(&stream.content)?, parms)?
+        } else {
+            stream.content.clone()
+        };
+        if pixels.len() != width as usize * height as usize * colors as usize {
+            debug!("Image data doesn't match its dimensions. Leaving it alone");
+            return Ok(None);
+        }
+
+        let device = match color_space_name(&stream.dict).as_deref() {
+            Some("DeviceGray") => Some(PDFConColorSpace::L8),
+            Some("DeviceRGB") => Some(PDFConColorSpace::RGB8),
+            _ => None,
+        };
+        let Some(device) = device.filter(|_| transform && self.transforms_active()) else {
+            return Ok(Some(deflate(pixels, width, height, colors, None)?));
+        };
+
+        let mut image = pdf_image::to_dynamic(&pixels, width, height, &device)
+            .ok_or(PDFConError::BufferInnerError)?;
+        if let Some(resized) = self.resample.resize(&image, None) {
+            image = resized;
+        }
+        let (width, height) = (image.width(), image.height());
+
+        let replacement = match reduce_colors(&image, &self.color_analysis) {
+            Some(ReducedImage::Bilevel(packed)) => Replacement {
+                content: compress_zlib(packed, Compression::best())?,
+                filter: "FlateDecode",
+                decode_parms: None,
+                color_space: Some(PDFConColorSpace::L1),
+                width,
+                height,
+            },
+            Some(ReducedImage::Gray(gray)) => deflate(
+                gray.into_raw(),
+                width,
+                height,
+                1,
+                Some(PDFConColorSpace::L8),
+            )?,
+            None => match device {
+                PDFConColorSpace::L8 => deflate(
+                    image.into_luma8().into_raw(),
+                    width,
+                    height,
+                    1,
+                    Some(device),
+                )?,
+                _ => deflate(image.into_rgb8().into_raw(), width, height, 3, Some(device))?,
+            },
+        };
+        Ok(Some(replacement))
+    }
+
+    /// Work out new data for an image. None means it's left alone
+    fn recompress(
+        &self,
+        doc: &Document,
+        stream: &Stream,
+        transform: bool,
+    ) -> Result<Option<Replacement>, PDFConError> {
+        let filters = filter_names(&stream.dict);
+        let filters: Vec<&str> = filters.iter().map(|f| f.as_str()).collect();
+        let replacement = match filters.as_slice() {
+            ["DCTDecode"] => self.recompress_jpeg(stream, transform)?,
+            ["FlateDecode"] | [] => self.recompress_flate(doc, stream, transform)?,
+            // JPEG 2000, JBIG2, fax data and filter chains are copied as they are
+            _ => None,
+        };
+
+        // Keep the smaller of the old and new data
+        Ok(replacement.filter(|r| r.content.len() < stream.content.len()))
+    }
+
+    fn recompress_images(&self, doc: &mut Document) -> Result<(usize, usize, usize), PDFConError> {
+        let masks = mask_ids(doc);
+        let image_ids: Vec<ObjectId> = doc
+            .objects
+            .iter()
+            .filter(|(_, object)| {
+                object
+                    .as_stream()
+                    .and_then(|s| s.dict.get(b"Subtype"))
+                    .and_then(|s| s.as_name())
+                    .is_ok_and(|s| s == b"Image")
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        // Initialize the progress bar
+        let pb = bar("Optimizing Images", image_ids.len() as u64, tick_speed());
+
+        let results: Vec<Result<(ObjectId, Option<Replacement>), PDFConError>> = image_ids
+            .par_iter()
+            .progress_with(pb.clone())
+            .map(|id| {
+                let pos = pb.position();
+                let total = pb.length().unwrap();
+
+                // Update bars end cap based on current progress
+                update_end_cap(&pb, pos, total);
+
+                let stream = doc.get_object(*id)?.as_stream()?;
+                // Stencils and masks have to keep their exact size and samples
+                let is_stencil = stream
+                    .dict
+                    .get(b"ImageMask")
+                    .and_then(|m| m.as_bool())
+                    .unwrap_or(false);
+                let transform = !is_stencil && !masks.contains(id) && !stream.dict.has(b"Decode");
+                Ok((*id, self.recompress(doc, stream, transform)?))
+            })
+            .collect();
+
+        // Finish bar and display message
+        close_bar(pb, " ● Optimizing Complete! ");
+
+        let mut before = 0;
+        let mut after = 0;
+        let mut replaced = 0;
+        for result in results {
+            match result {
+                Ok((id, replacement)) => {
+                    let stream = doc.get_object_mut(id)?.as_stream_mut()?;
+                    before += stream.content.len();
+                    if let Some(replacement) = replacement {
+                        replacement.apply(stream);
+                        replaced += 1;
+                    }
+                    after += stream.content.len();
+                }
+                // A broken image is copied as it is rather than failing the whole file
+                Err(e) => error!("Failed to optimize image: {{{}}}", e),
+            }
+        }
+
+        Ok((replaced, before, after))
+    }
+}
+
+fn deflate(
+    pixels: Vec<u8>,
+    width: u32,
+    height: u32,
+    colors: u8,
+    color_space: Option<PDFConColorSpace>,
+) -> Result<Replacement, PDFConError> {
+    let (content, decode_parms) = match deflate_predicted(&pixels, width, height, colors)? {
+        Some(content) => {
+            let mut parms = Dictionary::new();
+            parms.set("Predictor", 15);
+            parms.set("Colors", colors as i64);
+            parms.set("BitsPerComponent", 8);
+            parms.set("Columns", width as i64);
+            (content, Some(parms))
+        }
+        None => (compress_zlib(pixels, Compression::best())?, None),
+    };
+
+    Ok(Replacement {
+        content,
+        filter: "FlateDecode",
+        decode_parms,
+        color_space,
+        width,
+        height,
+    })
+}
+
+fn percent_change(before: u64, after: u64) -> f64 {
+    if before == 0 {
+        0.0
+    } else {
+        (after as f64 - before as f64) / before as f64 * 100.0
+    }
+}
+
+impl Run for Optimize {
+    fn run(&self) -> Result<(), PDFConError> {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(self.threads)
+            .build_global()?;
+
+        // Add spinner to show program is doing something
+        let spnr = spinner("Parsing PDF", tick_speed());
+
+        let file_size = std::fs::metadata(&self.in_file)?.len();
+        let mut document = Document::load(&self.in_file)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
+        // Encrypted input is written back with the same keys and permissions
+        let encryption_state = document.encryption_state.clone();
+
+        // Finish bar and display message
+        close_bar(spnr, " ● Parsing Complete! ");
+
+        let (replaced, before, after) = self.recompress_images(&mut document)?;
+
+        if let Some(state) = encryption_state {
+            document.encrypt(&state)?;
+        }
+        document.save(&self.out_file)?;
+        let out_size = std::fs::metadata(&self.out_file)?.len();
+
+        println!("Images recompressed   {}", replaced);
+        println!(
+            "Image data            {} -> {} ({:+.1}%)",
+            human_bytes(before as u64),
+            human_bytes(after as u64),
+            percent_change(before as u64, after as u64)
+        );
+        println!(
+            "File size             {} -> {} ({:+.1}%)",
+            human_bytes(file_size),
+            human_bytes(out_size),
+            percent_change(file_size, out_size)
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percent_change_is_relative_to_the_original() {
+        assert_eq!(percent_change(200, 150), -25.0);
+        assert!((percent_change(300, 400) - 33.333).abs() < 0.001);
+        assert_eq!(percent_change(0, 10), 0.0);
+    }
+}
@@ -0,0 +1,511 @@
+use crate::Run;
+use crate::error::PDFConError;
+use crate::security;
+use lopdf::{Dictionary, Document, Object, ObjectId, dictionary};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// Print the outline of a pdf as a table of contents or replace it with one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Outline {
+    pub in_file: PathBuf,
+    pub out_file: PathBuf,
+    pub password: Option<String>,
+    /// Table of contents file to replace the outline with
+    pub set: Option<PathBuf>,
+    /// Print json instead of text
+    pub json: bool,
+}
+
+/// Page an outline item points at, following named destinations and GoTo actions
+pub fn outline_page(doc: &Document, item: &Dictionary) -> Option<ObjectId> {
+    let dest = match item.get(b"Dest") {
+        Ok(dest) => dest,
+        Err(_) => item
+            .get(b"A")
+            .and_then(|a| doc.dereference(a))
+            .and_then(|(_, a)| a.as_dict())
+            .and_then(|a| a.get(b"D"))
+            .ok()?,
+    };
+    let dest = doc.dereference(dest).ok()?.1;
+    let dest = match dest {
+        Object::Name(name) | Object::String(name, _) => named_destination(doc, name)?,
+        other => other.clone(),
+    };
+    // Named destinations may be wrapped in a dictionary with a D entry
+    let dest = match &dest {
+        Object::Dictionary(d) => d.get(b"D").ok()?.clone(),
+        _ => dest,
+    };
+    dest.as_array().ok()?.first()?.as_reference().ok()
+}
+
+fn named_destination(doc: &Document, name: &[u8]) -> Option<Object> {
+    let catalog = doc.catalog().ok()?;
+
+    // PDF 1.1 keeps them in a dictionary on the catalog
+    if let Ok(dests) = catalog
+        .get(b"Dests")
+        .and_then(|d| doc.dereference(d))
+        .and_then(|(_, d)| d.as_dict())
+        && let Ok(dest) = dests.get(name)
+    {
+        return Some(doc.dereference(dest).ok()?.1.clone());
+    }
+
+    let names = doc
+        .dereference(catalog.get(b"Names").ok()?)
+        .ok()?
+        .1
+        .as_dict()
+        .ok()?;
+    let mut stack = vec![names.get(b"Dests").ok()?.clone()];
+    let mut seen = HashSet::new();
+    while let Some(node) = stack.pop() {
+        if let Ok(id) = node.as_reference()
+            && !seen.insert(id)
+        {
+            continue;
+        }
+        let Ok(node) = doc.dereference(&node).and_then(|(_, n)| n.as_dict()) else {
+            continue;
+        };
+        if let Ok(pairs) = node.get(b"Names").and_then(Object::as_array) {
+            for pair in pairs.chunks(2) {
+                if let [key, value] = pair
+                    && key.as_str().is_ok_and(|k| k == name)
+                {
+                    return Some(doc.dereference(value).ok()?.1.clone());
+                }
+            }
+        }
+        if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+            stack.extend(kids.iter().cloned());
+        }
+    }
+    None
+}
+
+/// Title and starting page number of every top level outline item that points at a
+/// page, in page order. Items sharing a page keep the first title
+pub fn chapters(doc: &Document) -> Vec<(u32, String)> {
+    let numbers: HashMap<ObjectId, u32> = doc
+        .get_pages()
+        .into_iter()
+        .map(|(num, id)| (id, num))
+        .collect();
+
+    let mut chapters = Vec::new();
+    let mut seen = HashSet::new();
+    let mut next = doc
+        .catalog()
+        .and_then(|c| c.get(b"Outlines"))
+        .and_then(|o| doc.dereference(o))
+        .and_then(|(_, o)| o.as_dict())
+        .and_then(|o| o.get(b"First"))
+        .and_then(Object::as_reference)
+        .ok();
+    while let Some(id) = next.filter(|id| seen.insert(*id)) {
+        let Ok(item) = doc.get_dictionary(id) else {
+            break;
+        };
+        if let Some(num) = outline_page(doc, item).and_then(|p| numbers.get(&p)) {
+            let title = item
+                .get(b"Title")
+                .and_then(lopdf::decode_text_string)
+                .unwrap_or_default();
+            chapters.push((*num, title));
+        }
+        next = item.get(b"Next").and_then(Object::as_reference).ok();
+    }
+
+    chapters.sort_by_key(|(num, _)| *num);
+    chapters.dedup_by_key(|(num, _)| *num);
+    chapters
+}
+
+/// One outline item and the items nested under it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TocEntry {
+    /// Page number counted from 1
+    pub page: u32,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<TocEntry>,
+}
+
+fn toc_error(line: usize, message: &str) -> PDFConError {
+    PDFConError::TocError(format!("line {}: {}", line, message))
+}
+
+/// Read a table of contents. Json is an array of `{"page", "title", "children"}`
+/// objects. Anything else is read as text with one `page<TAB>title` item per line,
+/// nested by indenting. Blank lines and lines starting with `#` are skipped
+pub fn parse_toc(text: &str) -> Result<Vec<TocEntry>, PDFConError> {
+    if text.trim_start().starts_with('[') {
+        return serde_json::from_str(text).map_err(|e| PDFConError::TocError(e.to_string()));
+    }
+
+    let mut toc: Vec<TocEntry> = Vec::new();
+    // Indent widths of the open levels, outermost first
+    let mut indents: Vec<usize> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let content = line.trim_start();
+        if content.is_empty() || content.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - content.len();
+        let mut dedented = false;
+        while indents.last().is_some_and(|last| *last > indent) {
+            indents.pop();
+            dedented = true;
+        }
+        match indents.last() {
+            Some(last) if *last == indent => {}
+            // Stepping back out has to land on a level that's already open
+            Some(_) if dedented => {
+                return Err(toc_error(i + 1, "indentation doesn't match an outer level"));
+            }
+            _ => indents.push(indent),
+        }
+
+        let (page, title) = content
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| toc_error(i + 1, "expected a page number and a title"))?;
+        let page = page
+            .parse::<u32>()
+            .ok()
+            .filter(|page| *page > 0)
+            .ok_or_else(|| toc_error(i + 1, &format!("\"{}\" isn't a page number", page)))?;
+
+        let mut level = &mut toc;
+        for _ in 1..indents.len() {
+            level = &mut level
+                .last_mut()
+                .ok_or_else(|| toc_error(i + 1, "nested item without a parent"))?
+                .children;
+        }
+        level.push(TocEntry {
+            page,
+            title: title.trim().to_string(),
+            children: Vec::new(),
+        });
+    }
+    Ok(toc)
+}
+
+/// Write a table of contents in the format [`parse_toc`] reads
+pub fn format_toc(toc: &[TocEntry], json: bool) -> Result<String, PDFConError> {
+    if json {
+        return Ok(serde_json::to_string_pretty(toc)? + "\n");
+    }
+
+    fn write_level(entries: &[TocEntry], depth: usize, out: &mut String) {
+        for entry in entries {
+            out.push_str(&format!(
+                "{}{}\t{}\n",
+                "\t".repeat(depth),
+                entry.page,
+                entry.title
+            ));
+            write_level(&entry.children, depth + 1, out);
+        }
+    }
+    let mut out = String::new();
+    write_level(toc, 0, &mut out);
+    Ok(out)
+}
+
+/// The whole outline of a document. Items that don't lead to a page are left out and
+/// their children take their place
+pub fn read_toc(doc: &Document) -> Vec<TocEntry> {
+    let numbers: HashMap<ObjectId, u32> = doc
+        .get_pages()
+        .into_iter()
+        .map(|(num, id)| (id, num))
+        .collect();
+    let first = doc
+        .catalog()
+        .and_then(|c| c.get(b"Outlines"))
+        .and_then(|o| doc.dereference(o))
+        .and_then(|(_, o)| o.as_dict())
+        .and_then(|o| o.get(b"First"))
+        .and_then(Object::as_reference)
+        .ok();
+
+    fn read_level(
+        doc: &Document,
+        numbers: &HashMap<ObjectId, u32>,
+        first: Option<ObjectId>,
+        seen: &mut HashSet<ObjectId>,
+    ) -> Vec<TocEntry> {
+        let mut entries = Vec::new();
+        let mut next = first;
+        while let Some(id) = next.filter(|id| seen.insert(*id)) {
+            let Ok(item) = doc.get_dictionary(id) else {
+                break;
+            };
+            let children_first = item.get(b"First").and_then(Object::as_reference).ok();
+            let children = read_level(doc, numbers, children_first, seen);
+            match outline_page(doc, item).and_then(|p| numbers.get(&p)) {
+                Some(page) => entries.push(TocEntry {
+                    page: *page,
+                    title: item
+                        .get(b"Title")
+                        .and_then(lopdf::decode_text_string)
+                        .unwrap_or_default(),
+                    children,
+                }),
+                None => entries.extend(children),
+            }
+            next = item.get(b"Next").and_then(Object::as_reference).ok();
+        }
+        entries
+    }
+
+    read_level(doc, &numbers, first, &mut HashSet::new())
+}
+
+/// The part of a table of contents that falls on `count` pages starting at `first`,
+/// renumbered so `first` becomes page 1. Items outside the range are dropped and any
+/// of their children inside it move up a level
+pub fn toc_range(toc: &[TocEntry], first: u32, count: u32) -> Vec<TocEntry> {
+    let mut entries = Vec::new();
+    for entry in toc {
+        let children = toc_range(&entry.children, first, count);
+        if (first..first + count).contains(&entry.page) {
+            entries.push(TocEntry {
+                page: entry.page - first + 1,
+                title: entry.title.clone(),
+                children,
+            });
+        } else {
+            entries.extend(children);
+        }
+    }
+    entries
+}
+
+/// Replace a document's outline with a table of contents. Items with children start
+/// closed
+pub fn set_toc(doc: &mut Document, toc: &[TocEntry]) -> Result<(), PDFConError> {
+    if toc.is_empty() {
+        return Ok(());
+    }
+    let pages = doc.get_pages();
+
+    fn add_level(
+        doc: &mut Document,
+        pages: &BTreeMap<u32, ObjectId>,
+        entries: &[TocEntry],
+        parent: ObjectId,
+    ) -> Result<(ObjectId, ObjectId), PDFConError> {
+        let item_ids: Vec<ObjectId> = entries.iter().map(|_| doc.new_object_id()).collect();
+        for (i, (entry, item_id)) in entries.iter().zip(&item_ids).enumerate() {
+            let page_id = pages.get(&entry.page).ok_or_else(|| {
+                PDFConError::TocError(format!(
+                    "\"{}\" points at page {} but there are {} pages",
+                    entry.title,
+                    entry.page,
+                    pages.len()
+                ))
+            })?;
+            let mut item = dictionary! {
+                "Title" => lopdf::text_string(&entry.title),
+                "Parent" => parent,
+                "Dest" => vec![(*page_id).into(), "Fit".into()],
+            };
+            if i > 0 {
+                item.set("Prev", item_ids[i - 1]);
+            }
+            if let Some(next) = item_ids.get(i + 1) {
+                item.set("Next", *next);
+            }
+            if !entry.children.is_empty() {
+                let (first, last) = add_level(doc, pages, &entry.children, *item_id)?;
+                item.set("First", first);
+                item.set("Last", last);
+                // Negative counts leave the item closed
+                item.set("Count", -(entry.children.len() as i64));
+            }
+            doc.objects.insert(*item_id, Object::Dictionary(item));
+        }
+        Ok((item_ids[0], item_ids[item_ids.len() - 1]))
+    }
+
+    let outlines_id = doc.new_object_id();
+    let (first, last) = add_level(doc, &pages, toc, outlines_id)?;
+    let outlines = dictionary! {
+        "Type" => "Outlines",
+        "First" => first,
+        "Last" => last,
+        "Count" => toc.len() as i64,
+    };
+    doc.objects
+        .insert(outlines_id, Object::Dictionary(outlines));
+
+    let catalog = doc.catalog_mut()?;
+    catalog.set("Outlines", outlines_id);
+    catalog.set("PageMode", "UseOutlines");
+    Ok(())
+}
+
+impl Run for Outline {
+    fn run(&self) -> Result<(), PDFConError> {
+        // Read the table of contents first so a bad one fails before the pdf is parsed
+        let toc = match &self.set {
+            Some(path) => Some(parse_toc(&std::fs::read_to_string(path)?)?),
+            None => None,
+        };
+
+        let mut document = Document::load(&self.in_file)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
+
+        let Some(toc) = toc else {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(format_toc(&read_toc(&document), self.json)?.as_bytes())?;
+            stdout.flush()?;
+            return Ok(());
+        };
+
+        // Encrypted input is written back with the same keys and permissions
+        let encryption_state = document.encryption_state.clone();
+
+        let catalog = document.catalog_mut()?;
+        catalog.remove(b"Outlines");
+        if catalog
+            .get(b"PageMode")
+            .and_then(Object::as_name)
+            .is_ok_and(|mode| mode == b"UseOutlines")
+        {
+            catalog.remove(b"PageMode");
+        }
+        set_toc(&mut document, &toc)?;
+        // The old outline items are no longer referenced
+        document.prune_objects();
+
+        if let Some(state) = encryption_state {
+            document.encrypt(&state)?;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.out_file)?;
+        let mut writer = BufWriter::new(file);
+
+        document.save_to(&mut writer)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(page: u32, title: &str, children: Vec<TocEntry>) -> TocEntry {
+        TocEntry {
+            page,
+            title: title.to_string(),
+            children,
+        }
+    }
+
+    fn error_line(text: &str) -> String {
+        match parse_toc(text) {
+            Err(PDFConError::TocError(message)) => message,
+            other => panic!("expected a toc error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_toc_nests_by_indent() {
+        let text = "# contents\n1\tIntro\n\t2\tPart one\n\t\t3\tScene\n\n\t5\tPart two\n9\tEnd\n";
+        assert_eq!(
+            parse_toc(text).unwrap(),
+            vec![
+                entry(
+                    1,
+                    "Intro",
+                    vec![
+                        entry(2, "Part one", vec![entry(3, "Scene", vec![])]),
+                        entry(5, "Part two", vec![]),
+                    ]
+                ),
+                entry(9, "End", vec![]),
+            ]
+        );
+        // Any deeper indent is one level down, spaces work as well as tabs
+        assert_eq!(
+            parse_toc("1 A\n      2 B\n").unwrap(),
+            vec![entry(1, "A", vec![entry(2, "B", vec![])])]
+        );
+    }
+
+    #[test]
+    fn parse_toc_rejects_a_dedent_to_an_unknown_level() {
+        let message = error_line("1\tA\n\t\t2\tB\n\t3\tC\n");
+        assert!(message.starts_with("line 3"), "{}", message);
+    }
+
+    #[test]
+    fn parse_toc_rejects_bad_page_numbers() {
+        for text in ["0\tCover", "x\tCover", "-1\tCover"] {
+            assert!(error_line(text).starts_with("line 1"));
+        }
+        assert!(error_line("1\tA\n7").starts_with("line 2"));
+    }
+
+    #[test]
+    fn parse_toc_reads_json() {
+        let json = r#"[{"page": 1, "title": "A", "children": [{"page": 2, "title": "B"}]}]"#;
+        assert_eq!(
+            parse_toc(json).unwrap(),
+            vec![entry(1, "A", vec![entry(2, "B", vec![])])]
+        );
+        assert!(parse_toc(r#"[{"page": 1}]"#).is_err());
+    }
+
+    #[test]
+    fn format_toc_reads_back_the_same() {
+        let toc = vec![
+            entry(
+                1,
+                "Intro",
+                vec![entry(2, "Part one", vec![entry(3, "Scene", vec![])])],
+            ),
+            entry(9, "End", vec![]),
+        ];
+        for json in [false, true] {
+            assert_eq!(parse_toc(&format_toc(&toc, json).unwrap()).unwrap(), toc);
+        }
+        assert_eq!(
+            format_toc(&toc, false).unwrap(),
+            "1\tIntro\n\t2\tPart one\n\t\t3\tScene\n9\tEnd\n"
+        );
+    }
+
+    #[test]
+    fn toc_range_renumbers_and_lifts_children() {
+        let toc = vec![
+            entry(1, "A", vec![entry(3, "B", vec![]), entry(6, "C", vec![])]),
+            entry(8, "D", vec![]),
+        ];
+        assert_eq!(
+            toc_range(&toc, 3, 5),
+            vec![entry(1, "B", vec![]), entry(4, "C", vec![])]
+        );
+        assert_eq!(toc_range(&toc, 1, 10), toc);
+        assert_eq!(toc_range(&toc, 8, 1), vec![entry(1, "D", vec![])]);
+        assert!(toc_range(&toc, 9, 5).is_empty());
+    }
+}
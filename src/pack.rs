@@ -1,33 +1,93 @@
+use crate::archive::{self, ArchiveFormat, ComicInfo};
 use crate::constants::tick_speed;
+use crate::outline::{self, TocEntry};
+use crate::page_label::PageLabels;
 use crate::pdf_image;
 use crate::progress::{bar, close_bar, update_end_cap};
+use crate::security::Encryption;
+use crate::split;
 use crate::{Run, error::PDFConError};
 use indicatif::ParallelProgressIterator;
-use log::{debug, error};
+use log::{debug, error, warn};
 use lopdf::content::Content;
 use lopdf::{Document, Object, Stream, content::Operation, dictionary};
 use rayon::prelude::*;
-use std::io::BufWriter;
-use std::path::PathBuf;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Pack {
     pub optimize: bool,
+    pub jpeg_options: pdf_image::optimize::JpegOptions,
+    pub resample: pdf_image::resample::Resample,
+    pub color_analysis: pdf_image::optimize::ColorAnalysis,
+    pub cleanup: pdf_image::optimize::ScanCleanup,
+    pub encryption: Option<Encryption>,
     pub threads: usize,
-    pub in_directory: PathBuf,
+    /// Directory or zip, cbz or tar archive of images
+    pub input: PathBuf,
     pub out_file: PathBuf,
+    /// Start a new numbered output file after this many pages
+    pub max_pages: Option<usize>,
+    /// Start a new numbered output file before it would grow past this many bytes
+    pub max_size: Option<u64>,
+    /// Table of contents file to build the outline from. Without one, archives with
+    /// their images in folders get an item for each folder
+    pub toc: Option<PathBuf>,
+    pub page_labels: Option<PageLabels>,
+    pub blank_pages: Option<BlankPages>,
 }
 
+/// Finding nearly blank pages, like the backs of duplex scans
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlankPages {
+    /// Pages with less ink than this, in hundredths of a percent of the page, are blank
+    pub max_ink: u32,
+    /// Leave blank pages out. Otherwise they are only logged
+    pub drop: bool,
+    /// Write a json list of the blank pages here
+    pub report: Option<PathBuf>,
+}
+
+/// A blank page as listed in the report
+#[derive(Debug, Serialize)]
+struct BlankPage {
+    /// Position of the image among the input images, starting at 1
+    position: usize,
+    image: PathBuf,
+    /// Percentage of the page covered in ink
+    ink: f64,
+    dropped: bool,
+}
+
+/// Room for the page, content stream and cross reference entries around every image
+const PAGE_OVERHEAD: u64 = 512;
+
 #[derive(Debug)]
 pub enum ImageType {
     PNG,
     JPG,
 }
 
+impl ImageType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let (_, extension) = name.rsplit_once('.')?;
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(ImageType::PNG),
+            "jpeg" | "jpg" => Some(ImageType::JPG),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ImageFile {
     pub location: PathBuf,
     pub image_type: ImageType,
+    /// Images from archives are already in memory. The rest are read from disk
+    pub contents: Option<Vec<u8>>,
 }
 
 impl ImageFile {
@@ -35,23 +95,68 @@ impl ImageFile {
         Self {
             location,
             image_type,
+            contents: None,
         }
     }
 }
 
+/// Processed images of the whole input and what every document built from them shares
+struct PreparedPages {
+    images: Vec<pdf_image::optimize::PageImage>,
+    comic_info: Option<ComicInfo>,
+    toc: Vec<TocEntry>,
+}
+
 impl Pack {
+    /// Decode an image into page data, along with its ink coverage when blank pages are
+    /// being looked for
     fn process_image(
         &self,
         image_file: &ImageFile,
-    ) -> Result<pdf_image::optimize::ImageData, PDFConError> {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .open(&image_file.location)?;
-        match image_file.image_type {
-            ImageType::PNG => pdf_image::optimize::process_png_optimized(file),
+    ) -> Result<(pdf_image::optimize::PageImage, Option<f64>), PDFConError> {
+        let data = match &image_file.contents {
+            Some(contents) => self.decode_image(Cursor::new(contents), &image_file.image_type)?,
+            None => {
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .open(&image_file.location)?;
+                self.decode_image(file, &image_file.image_type)?
+            }
+        };
+        let ink = match self.blank_pages {
+            Some(_) => Some(pdf_image::optimize::ink_coverage(&data.image)?),
+            None => None,
+        };
+        Ok((data, ink))
+    }
+
+    fn decode_image(
+        &self,
+        file: impl Read + Seek,
+        image_type: &ImageType,
+    ) -> Result<pdf_image::optimize::PageImage, PDFConError> {
+        match image_type {
+            ImageType::PNG => pdf_image::optimize::process_png_optimized(
+                file,
+                &self.cleanup,
+                &self.resample,
+                &self.color_analysis,
+            ),
             ImageType::JPG => {
-                if self.optimize {
-                    pdf_image::optimize::optimize_jpeg(file)
+                if self.cleanup.is_active()
+                    || self.resample.is_active()
+                    || self.color_analysis.is_active()
+                {
+                    pdf_image::optimize::transform_jpeg(
+                        file,
+                        self.optimize,
+                        &self.jpeg_options,
+                        &self.cleanup,
+                        &self.resample,
+                        &self.color_analysis,
+                    )
+                } else if self.optimize {
+                    pdf_image::optimize::optimize_jpeg(file, &self.jpeg_options)
                 } else {
                     pdf_image::optimize::jpeg(file)
                 }
@@ -90,21 +195,99 @@ impl Pack {
 
         let path = unwrapped_entry.path();
 
-        let image_type = match path.extension()?.to_str()? {
-            "png" => ImageType::PNG,
-            "jpeg" | "jpg" => ImageType::JPG,
-            _ => {
-                // File was not a supported image. This should be logged
-                debug!("File type not supported");
-                return None;
-            }
+        let Some(image_type) = ImageType::from_name(path.file_name()?.to_str()?) else {
+            // File was not a supported image. This should be logged
+            debug!("File type not supported");
+            return None;
         };
 
         Some(ImageFile::new(path, image_type))
     }
 
-    fn para_process(&self) -> Result<(), PDFConError> {
-        let directory = std::fs::read_dir(&self.in_directory)?;
+    /// Build a document with one page per image in the input
+    pub fn build_document(&self) -> Result<Document, PDFConError> {
+        let pages = self.prepare_pages()?;
+        self.build_part(pages.images, pages.comic_info.as_ref(), &pages.toc, 1)
+    }
+
+    /// Processed images in page order along with the ComicInfo.xml and table of contents
+    /// of the whole input
+    fn prepare_pages(&self) -> Result<PreparedPages, PDFConError> {
+        // Read the table of contents first so a bad one fails before the slow part
+        let toc = self.read_toc()?;
+        let (files, comic_info) = self.collect_images()?;
+        let images = self.remove_blanks(&files, self.process_images(&files))?;
+        let toc = toc.unwrap_or_else(|| chapter_starts(&files, &images));
+        Ok(PreparedPages {
+            images: images.into_iter().flatten().collect(),
+            comic_info,
+            toc,
+        })
+    }
+
+    /// Build a document from some of the pages of the whole, starting at `first_page`,
+    /// with the metadata, outline and page labels that fall in it
+    fn build_part(
+        &self,
+        images: Vec<pdf_image::optimize::PageImage>,
+        comic_info: Option<&ComicInfo>,
+        toc: &[TocEntry],
+        first_page: u32,
+    ) -> Result<Document, PDFConError> {
+        let count = images.len() as u32;
+        let mut doc = document_from_images(images)?;
+        if let Some(comic_info) = comic_info {
+            let info_id = doc.add_object(comic_info.info_dictionary());
+            doc.trailer.set("Info", info_id);
+        }
+        outline::set_toc(&mut doc, &outline::toc_range(toc, first_page, count))?;
+        if let Some(labels) = &self.page_labels {
+            let tree = labels.from_page(first_page).number_tree(count);
+            doc.catalog_mut()?.set("PageLabels", tree);
+        }
+        Ok(doc)
+    }
+
+    fn read_toc(&self) -> Result<Option<Vec<TocEntry>>, PDFConError> {
+        match &self.toc {
+            Some(path) => Ok(Some(outline::parse_toc(&std::fs::read_to_string(path)?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Images in the order they become pages and the ComicInfo.xml of an archive if it
+    /// has one
+    fn collect_images(&self) -> Result<(Vec<ImageFile>, Option<ComicInfo>), PDFConError> {
+        if let Some(format) = ArchiveFormat::from_path(&self.input)? {
+            let is_comic_info = |name: &str| {
+                name.rsplit('/')
+                    .next()
+                    .unwrap_or(name)
+                    .eq_ignore_ascii_case("ComicInfo.xml")
+            };
+            let entries = archive::read_entries(&self.input, format, |name| {
+                is_comic_info(name) || ImageType::from_name(name).is_some()
+            })?;
+
+            let mut comic_info = None;
+            let mut files = Vec::new();
+            for entry in entries {
+                if is_comic_info(&entry.name) {
+                    comic_info = ComicInfo::parse(&String::from_utf8_lossy(&entry.contents));
+                    continue;
+                }
+                if let Some(image_type) = ImageType::from_name(&entry.name) {
+                    files.push(ImageFile {
+                        location: PathBuf::from(entry.name),
+                        image_type,
+                        contents: Some(entry.contents),
+                    });
+                }
+            }
+            return Ok((files, comic_info));
+        }
+
+        let directory = std::fs::read_dir(&self.input)?;
 
         let mut files: Vec<ImageFile> = directory
             .filter_map(|e| {
@@ -116,13 +299,21 @@ impl Pack {
 
         files.par_sort_by_key(|k| k.location.to_owned());
 
+        Ok((files, None))
+    }
+
+    /// Process every image in order. Images that fail are logged and left as None
+    fn process_images(
+        &self,
+        files: &[ImageFile],
+    ) -> Vec<Option<(pdf_image::optimize::PageImage, Option<f64>)>> {
         // Initialize the progress bar
         let pb = bar("Converting to PDF", files.len() as u64, tick_speed());
 
         let pre_processed = files
             .par_iter()
             .progress_with(pb.clone())
-            .filter_map(|image_file| {
+            .map(|image_file| {
                 let pos = pb.position();
                 let total = pb.length().unwrap();
 
@@ -132,164 +323,146 @@ impl Pack {
                 match self.process_image(image_file) {
                     Ok(bytes) => Some(bytes),
                     Err(e) => {
-                        error!("Failed to process image_file {}", e.to_string());
-                        return None;
+                        error!("Failed to process image_file {}", e);
+                        None
                     }
                 }
             })
-            .collect::<Vec<pdf_image::optimize::ImageData>>();
+            .collect::<Vec<_>>();
 
         // Finish bar and display message
         close_bar(pb, " ● Converting Complete! ");
 
-        // Use the latest PDF version
-        let mut doc = Document::with_version("1.7");
-
-        // Object IDs are used for cross referencing in PDF documents.
-        // lopdf helps keep track of these. They're simple integers.
-        // Calls to doc.new_object_id and doc.add_object produce a new object ID
-        // Pages is the root node of the page tree
-        let pages_id = doc.new_object_id();
-
-        // Content is a wrapper struct around an operations struct that contains a vector of operations
-        // The operations struct contains a vector of operations that match up with a particular PDF operator and
-        // operands
-        // The PDF spec has more details on the operators and operands
-        // Note, the operators and operands are specified in a reverse order than they actually
-        // appear in the PDF file itself
-
-        // Streams are a dictionary followed by a sequence of bytes. What the bytes represent depends on the
-        // context. The stream dictionary is set internally by lopdf and normally doesn't need to be manually
-        // manipulated. It contains keys such as Length, Filter, DecodeParams, etc.
-
-        let mut page_ids = Vec::new();
-        let mut parent = pages_id;
-        for image_data in pre_processed {
-            match image_data {
-                pdf_image::optimize::ImageData::PNG(compressed_data, width, height, color_type) => {
-                    let (color_type, bits) = color_type.to_pdf_format();
-                    let dic = dictionary!(
-                        "Type" => Object::Name(b"XObject".to_vec()),
-                        "Subtype" => Object::Name(b"Image".to_vec()),
-                        "Width" => width as u32,
-                        "Height" => height as u32,
-                        "ColorSpace" => Object::Name(color_type),
-                        "BitsPerComponent" => bits as u32,
-                        "Filter" => Object::Name(b"FlateDecode".to_vec())
-                    );
-                    let img_object = Stream::new(dic, compressed_data);
-                    let img_id = doc.add_object(img_object);
-                    let img_name = format!("X{}", img_id.0);
-
-                    let cm_operation = Operation::new(
-                        "cm",
-                        vec![
-                            (width as u32).into(),
-                            0.into(),
-                            0.into(),
-                            (height as u32).into(),
-                            0.into(),
-                            0.into(),
-                        ],
-                    );
-
-                    let do_operation =
-                        Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
-                    let content = Content {
-                        operations: vec![cm_operation, do_operation],
-                    };
-
-                    let content_id =
-                        doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
-
-                    let page_id = doc.add_object(dictionary! {
-                        "Type" => "Page",
-                        "Parent" => parent,
-                        "Contents" => content_id,
-                        "MediaBox" => vec![0.into(), 0.into(), (width as u32).into(), (height as u32).into()]
-                    });
+        pre_processed
+    }
 
-                    doc.add_xobject(page_id, img_name.as_bytes(), img_id)
-                        .unwrap();
+    /// Log and report the images with too little ink to be anything but blank, leaving
+    /// them out when asked to. Dropped images become None like ones that failed
+    fn remove_blanks(
+        &self,
+        files: &[ImageFile],
+        images: Vec<Option<(pdf_image::optimize::PageImage, Option<f64>)>>,
+    ) -> Result<Vec<Option<pdf_image::optimize::PageImage>>, PDFConError> {
+        let mut blanks = Vec::new();
+        let mut kept = Vec::with_capacity(images.len());
+        for (i, (file, image)) in files.iter().zip(images).enumerate() {
+            let Some((data, ink)) = image else {
+                kept.push(None);
+                continue;
+            };
+            let (Some(blank_pages), Some(ink)) = (&self.blank_pages, ink) else {
+                kept.push(Some(data));
+                continue;
+            };
+            if ink * 100.0 >= blank_pages.max_ink as f64 {
+                kept.push(Some(data));
+                continue;
+            }
 
-                    page_ids.push(page_id);
-                    parent = page_id;
+            warn!(
+                "{} looks blank with {:.2}% ink{}",
+                file.location.display(),
+                ink,
+                if blank_pages.drop {
+                    ". Leaving it out"
+                } else {
+                    ""
                 }
-                pdf_image::optimize::ImageData::JPEG(
-                    compressed_data,
-                    width,
-                    height,
-                    color_type,
-                ) => {
-                    let (color_type, bits) = color_type.to_pdf_format();
-                    let dic = dictionary!(
-                        "Type" => Object::Name(b"XObject".to_vec()),
-                        "Subtype" => Object::Name(b"Image".to_vec()),
-                        "Width" => width as u32,
-                        "Height" => height as u32,
-                        "ColorSpace" => Object::Name(color_type),
-                        "BitsPerComponent" => bits as u32,
-                        "Filter" => Object::Name(b"DCTDecode".to_vec())
-                    );
-                    let img_object = Stream::new(dic, compressed_data);
-                    let img_id = doc.add_object(img_object);
-                    let img_name = format!("X{}", img_id.0);
-
-                    let cm_operation = Operation::new(
-                        "cm",
-                        vec![
-                            (width as u32).into(),
-                            0.into(),
-                            0.into(),
-                            (height as u32).into(),
-                            0.into(),
-                            0.into(),
-                        ],
-                    );
-
-                    let do_operation =
-                        Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
-                    let content = Content {
-                        operations: vec![cm_operation, do_operation],
-                    };
-
-                    let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
-
-                    let page_id = doc.add_object(dictionary! {
-                        "Type" => "Page",
-                        "Parent" => parent,
-                        "Contents" => content_id,
-                        "MediaBox" => vec![0.into(), 0.into(), (width as u32).into(), (height as u32).into()]
-                    });
+            );
+            kept.push((!blank_pages.drop).then_some(data));
+            blanks.push(BlankPage {
+                position: i + 1,
+                image: file.location.clone(),
+                ink,
+                dropped: blank_pages.drop,
+            });
+        }
 
-                    doc.add_xobject(page_id, img_name.as_bytes(), img_id)
-                        .unwrap();
+        if let Some(report) = self.blank_pages.as_ref().and_then(|b| b.report.as_ref()) {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(report)?;
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer_pretty(&mut writer, &blanks)?;
+            writer.flush()?;
+        }
+        if kept.iter().all(Option::is_none) && !blanks.is_empty() {
+            return Err(PDFConError::NoPagesLeft);
+        }
+        Ok(kept)
+    }
 
-                    page_ids.push(page_id);
-                    parent = page_id;
-                }
+    /// Group processed images into the documents they'll be written to
+    fn group_images(
+        &self,
+        images: Vec<pdf_image::optimize::PageImage>,
+    ) -> Vec<Vec<pdf_image::optimize::PageImage>> {
+        let mut groups = Vec::new();
+        let mut current = Vec::new();
+        let mut size = 0;
+        for image in images {
+            let (pdf_image::optimize::ImageData::PNG(data, ..)
+            | pdf_image::optimize::ImageData::JPEG(data, ..)) = &image.image;
+            let image_size = data.len() as u64 + PAGE_OVERHEAD;
+
+            let full = self.max_pages.is_some_and(|max| current.len() >= max)
+                || self.max_size.is_some_and(|max| size + image_size > max);
+            if full && !current.is_empty() {
+                groups.push(std::mem::take(&mut current));
+                size = 0;
+            }
+            if self.max_size.is_some_and(|max| image_size > max) {
+                error!("An image alone is larger than the size limit. It gets its own file");
             }
+            size += image_size;
+            current.push(image);
+        }
+        if !current.is_empty() {
+            groups.push(current);
         }
+        groups
+    }
 
-        let pages_dict = dictionary! {
-            "Type" => "Pages",
-            "Count" => page_ids.len() as u32,
-            "Kids" => page_ids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
-        };
+    fn para_process(&self) -> Result<(), PDFConError> {
+        if self.max_pages.is_none() && self.max_size.is_none() {
+            return self.save(self.build_document()?, &self.out_file);
+        }
 
-        doc.objects.insert(pages_id, Object::Dictionary(pages_dict));
+        let pages = self.prepare_pages()?;
+        let groups = self.group_images(pages.images);
+        let stem = self
+            .out_file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let total_parts = groups.len();
+        let mut first_page = 1;
+        for (i, group) in groups.into_iter().enumerate() {
+            let out_file =
+                self.out_file
+                    .with_file_name(split::part_file_name(&stem, i + 1, total_parts));
+            let page_count = group.len() as u32;
+            let doc = self.build_part(group, pages.comic_info.as_ref(), &pages.toc, first_page)?;
+            first_page += page_count;
+            self.save(doc, &out_file)?;
+        }
 
-        let catalog_id = doc.add_object(dictionary! {
-            "Type" => "Catalog",
-            "Pages" => pages_id,
-        });
+        Ok(())
+    }
 
-        doc.trailer.set("Root", catalog_id);
+    fn save(&self, mut doc: Document, out_file: &Path) -> Result<(), PDFConError> {
+        if let Some(encryption) = &self.encryption {
+            encryption.apply(&mut doc)?;
+        }
 
         let file = std::fs::OpenOptions::new()
             .create(true)
             .write(true)
-            .open(&self.out_file)?;
+            .truncate(true)
+            .open(out_file)?;
         let mut writer = BufWriter::new(file);
 
         doc.save_to(&mut writer)?;
@@ -298,6 +471,225 @@ impl Pack {
     }
 }
 
+/// Title of a chapter folder. The numbers unpack puts in front to keep folders in
+/// order are dropped
+fn chapter_title(folder: &str) -> String {
+    match folder.split_once(' ') {
+        Some((number, title))
+            if !title.is_empty() && number.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            title.to_string()
+        }
+        _ => folder.to_string(),
+    }
+}
+
+/// Outline items for an archive whose images are sorted into folders. Each folder
+/// starts a chapter at its first page
+fn chapter_starts(
+    files: &[ImageFile],
+    images: &[Option<pdf_image::optimize::PageImage>],
+) -> Vec<TocEntry> {
+    let folders: Vec<Option<&str>> = files
+        .iter()
+        .map(|file| {
+            file.contents
+                .as_ref()
+                .and(file.location.parent())
+                .and_then(|parent| parent.file_name())
+                .and_then(|name| name.to_str())
+        })
+        .collect();
+    // A single folder is just somewhere to keep the pages, not a chapter
+    let distinct: HashSet<&str> = folders.iter().flatten().copied().collect();
+    if distinct.len() < 2 {
+        return Vec::new();
+    }
+
+    let mut chapters = Vec::new();
+    let mut current = None;
+    let pages = folders
+        .iter()
+        .zip(images)
+        .filter(|(_, image)| image.is_some());
+    for (page, (folder, _)) in pages.enumerate() {
+        if let Some(name) = folder
+            && *folder != current
+        {
+            chapters.push(TocEntry {
+                page: page as u32 + 1,
+                title: chapter_title(name),
+                children: Vec::new(),
+            });
+        }
+        current = *folder;
+    }
+    chapters
+}
+
+/// Add an image XObject unless an identical one is already in the document. Repeated
+/// blank or separator pages then share a single copy
+fn add_image(
+    doc: &mut Document,
+    added: &mut HashMap<Vec<u8>, lopdf::ObjectId>,
+    dict: lopdf::Dictionary,
+    data: Vec<u8>,
+) -> lopdf::ObjectId {
+    let hash = pdf_image::image_hash(&dict, &data);
+
+    if let Some(id) = added.get(&hash) {
+        debug!("Reusing image {} for a duplicate", id.0);
+        return *id;
+    }
+    let id = doc.add_object(Stream::new(dict, data));
+    added.insert(hash, id);
+    id
+}
+
+/// Build a document with one page per image
+pub fn document_from_images(
+    pre_processed: Vec<pdf_image::optimize::PageImage>,
+) -> Result<Document, PDFConError> {
+    // Use the latest PDF version
+    let mut doc = Document::with_version("1.7");
+
+    // Object IDs are used for cross referencing in PDF documents.
+    // lopdf helps keep track of these. They're simple integers.
+    // Calls to doc.new_object_id and doc.add_object produce a new object ID
+    // Pages is the root node of the page tree
+    let pages_id = doc.new_object_id();
+
+    // Content is a wrapper struct around an operations struct that contains a vector of operations
+    // The operations struct contains a vector of operations that match up with a particular PDF operator and
+    // operands
+    // The PDF spec has more details on the operators and operands
+    // Note, the operators and operands are specified in a reverse order than they actually
+    // appear in the PDF file itself
+
+    // Streams are a dictionary followed by a sequence of bytes. What the bytes represent depends on the
+    // context. The stream dictionary is set internally by lopdf and normally doesn't need to be manually
+    // manipulated. It contains keys such as Length, Filter, DecodeParams, etc.
+
+    let mut page_ids = Vec::new();
+    let mut parent = pages_id;
+    let mut added = HashMap::new();
+    for page in pre_processed {
+        // The page keeps the size of the original image even when it was downsampled
+        let media_box = vec![0.into(), 0.into(), page.width.into(), page.height.into()];
+        let cm_operation = Operation::new(
+            "cm",
+            vec![
+                page.width.into(),
+                0.into(),
+                0.into(),
+                page.height.into(),
+                0.into(),
+                0.into(),
+            ],
+        );
+        match page.image {
+            pdf_image::optimize::ImageData::PNG(compressed_data, width, height, color_type) => {
+                let (color_type, bits) = color_type.to_pdf_format();
+                let dic = dictionary!(
+                    "Type" => Object::Name(b"XObject".to_vec()),
+                    "Subtype" => Object::Name(b"Image".to_vec()),
+                    "Width" => width,
+                    "Height" => height,
+                    "ColorSpace" => Object::Name(color_type),
+                    "BitsPerComponent" => bits,
+                    "Filter" => Object::Name(b"FlateDecode".to_vec())
+                );
+                let img_id = add_image(&mut doc, &mut added, dic, compressed_data);
+                let img_name = format!("X{}", img_id.0);
+
+                let do_operation =
+                    Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
+                let content = Content {
+                    operations: vec![cm_operation, do_operation],
+                };
+
+                let content_id =
+                    doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+
+                let page_id = doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => parent,
+                    "Contents" => content_id,
+                    "MediaBox" => media_box
+                });
+
+                doc.add_xobject(page_id, img_name.as_bytes(), img_id)
+                    .unwrap();
+
+                page_ids.push(page_id);
+                parent = page_id;
+            }
+            pdf_image::optimize::ImageData::JPEG(compressed_data, width, height, color_type) => {
+                // Adobe CMYK jpegs store their samples inverted. Viewers know that from
+                // the marker but a pdf has to say so with a Decode array
+                let inverted = color_type == pdf_image::PDFConColorSpace::CMYK
+                    && pdf_image::has_adobe_marker(&compressed_data);
+                let (color_type, bits) = color_type.to_pdf_format();
+                let mut dic = dictionary!(
+                    "Type" => Object::Name(b"XObject".to_vec()),
+                    "Subtype" => Object::Name(b"Image".to_vec()),
+                    "Width" => width,
+                    "Height" => height,
+                    "ColorSpace" => Object::Name(color_type),
+                    "BitsPerComponent" => bits,
+                    "Filter" => Object::Name(b"DCTDecode".to_vec())
+                );
+                if inverted {
+                    dic.set(
+                        "Decode",
+                        [1, 0, 1, 0, 1, 0, 1, 0].map(Object::Integer).to_vec(),
+                    );
+                }
+                let img_id = add_image(&mut doc, &mut added, dic, compressed_data);
+                let img_name = format!("X{}", img_id.0);
+
+                let do_operation =
+                    Operation::new("Do", vec![Object::Name(img_name.as_bytes().to_vec())]);
+                let content = Content {
+                    operations: vec![cm_operation, do_operation],
+                };
+
+                let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode()?));
+
+                let page_id = doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => parent,
+                    "Contents" => content_id,
+                    "MediaBox" => media_box
+                });
+
+                doc.add_xobject(page_id, img_name.as_bytes(), img_id)
+                    .unwrap();
+
+                page_ids.push(page_id);
+                parent = page_id;
+            }
+        }
+    }
+
+    let pages_dict = dictionary! {
+        "Type" => "Pages",
+        "Count" => page_ids.len() as u32,
+        "Kids" => page_ids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+    };
+
+    doc.objects.insert(pages_id, Object::Dictionary(pages_dict));
+
+    let catalog_id = doc.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+
+    doc.trailer.set("Root", catalog_id);
+
+    Ok(doc)
+}
+
 impl Run for Pack {
     fn run(&self) -> Result<(), PDFConError> {
         rayon::ThreadPoolBuilder::new()
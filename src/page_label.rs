@@ -0,0 +1,509 @@
+use lopdf::{Dictionary, Document, Object, dictionary};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// How the number part of a page label is written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabelStyle {
+    /// 1, 2, 3
+    Decimal,
+    /// I, II, III
+    UpperRoman,
+    /// i, ii, iii
+    LowerRoman,
+    /// A to Z, then AA to ZZ
+    UpperLetters,
+    /// a to z, then aa to zz
+    LowerLetters,
+    /// Only the prefix
+    None,
+}
+
+impl LabelStyle {
+    fn from_name(name: &[u8]) -> Self {
+        match name {
+            b"D" => Self::Decimal,
+            b"R" => Self::UpperRoman,
+            b"r" => Self::LowerRoman,
+            b"A" => Self::UpperLetters,
+            b"a" => Self::LowerLetters,
+            _ => Self::None,
+        }
+    }
+
+    fn name(&self) -> Option<&'static str> {
+        match self {
+            Self::Decimal => Some("D"),
+            Self::UpperRoman => Some("R"),
+            Self::LowerRoman => Some("r"),
+            Self::UpperLetters => Some("A"),
+            Self::LowerLetters => Some("a"),
+            Self::None => None,
+        }
+    }
+
+    fn format(&self, number: u32) -> String {
+        match self {
+            Self::UpperRoman | Self::LowerRoman if number > MAX_ROMAN => number.to_string(),
+            Self::UpperLetters | Self::LowerLetters if number > MAX_LETTERS => number.to_string(),
+            Self::Decimal => number.to_string(),
+            Self::UpperRoman => roman(number),
+            Self::LowerRoman => roman(number).to_lowercase(),
+            Self::UpperLetters => letters(number),
+            Self::LowerLetters => letters(number).to_lowercase(),
+            Self::None => String::new(),
+        }
+    }
+}
+
+/// Larger numbers are written in decimal. Roman numerals past 3999 need a bar over them
+/// and letters past ten repeats stop being readable
+const MAX_ROMAN: u32 = 3999;
+const MAX_LETTERS: u32 = 26 * 10;
+
+fn roman(mut number: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "M"),
+        (900, "CM"),
+        (500, "D"),
+        (400, "CD"),
+        (100, "C"),
+        (90, "XC"),
+        (50, "L"),
+        (40, "XL"),
+        (10, "X"),
+        (9, "IX"),
+        (5, "V"),
+        (4, "IV"),
+        (1, "I"),
+    ];
+    let mut out = String::new();
+    for (value, numeral) in NUMERALS {
+        while number >= value {
+            out.push_str(numeral);
+            number -= value;
+        }
+    }
+    out
+}
+
+/// A to Z for the first 26, then the letter doubled for the next 26 and so on
+fn letters(number: u32) -> String {
+    if number == 0 {
+        return String::new();
+    }
+    let letter = char::from(b'A' + ((number - 1) % 26) as u8);
+    letter.to_string().repeat((number as usize - 1) / 26 + 1)
+}
+
+/// Labelling that applies from one page until the next range starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelRange {
+    /// Page number counted from 1
+    pub first_page: u32,
+    pub style: LabelStyle,
+    /// Number given to the first page of the range
+    pub start: u32,
+    pub prefix: String,
+}
+
+/// The labels viewers show in place of page numbers, given like `1-4:r,5-:D:1`.
+/// Each range takes a style of `D`, `r`, `R`, `a`, `A` or nothing, then optionally the
+/// number to start at and a prefix, e.g. `10-:D:1:A-`. Pages a range doesn't cover are
+/// numbered as they are in the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageLabels {
+    ranges: Vec<LabelRange>,
+}
+
+impl PageLabels {
+    /// Label of a page counted from 1
+    pub fn label(&self, page: u32) -> String {
+        match self.ranges.iter().rfind(|range| range.first_page <= page) {
+            Some(range) => format!(
+                "{}{}",
+                range.prefix,
+                range
+                    .style
+                    .format(range.start.saturating_add(page - range.first_page))
+            ),
+            None => page.to_string(),
+        }
+    }
+
+    /// The labels of the pages from `first_page` on, for a document that starts there
+    pub fn from_page(&self, first_page: u32) -> Self {
+        let mut ranges: Vec<LabelRange> = Vec::new();
+        for range in &self.ranges {
+            if range.first_page <= first_page {
+                // Only the last range starting at or before the new first page reaches it
+                ranges.clear();
+                ranges.push(LabelRange {
+                    first_page: 1,
+                    start: range.start.saturating_add(first_page - range.first_page),
+                    ..range.clone()
+                });
+            } else {
+                ranges.push(LabelRange {
+                    first_page: range.first_page - first_page + 1,
+                    ..range.clone()
+                });
+            }
+        }
+        Self { ranges }
+    }
+
+    /// The `/PageLabels` number tree for a document with this many pages
+    pub fn number_tree(&self, total_pages: u32) -> Dictionary {
+        let mut nums = Vec::new();
+        for range in self.ranges.iter().filter(|r| r.first_page <= total_pages) {
+            let mut label = Dictionary::new();
+            if let Some(name) = range.style.name() {
+                label.set("S", Object::Name(name.as_bytes().to_vec()));
+            }
+            if range.start != 1 {
+                label.set("St", range.start);
+            }
+            if !range.prefix.is_empty() {
+                label.set("P", lopdf::text_string(&range.prefix));
+            }
+            nums.push(Object::Integer(range.first_page as i64 - 1));
+            nums.push(Object::Dictionary(label));
+        }
+        dictionary! { "Nums" => nums }
+    }
+
+    /// Labels from a document's catalog. None when it has none
+    pub fn from_document(doc: &Document) -> Option<Self> {
+        let tree = doc.catalog().ok()?.get(b"PageLabels").ok()?.clone();
+
+        let mut ranges = Vec::new();
+        let mut stack = vec![tree];
+        let mut seen = HashSet::new();
+        while let Some(node) = stack.pop() {
+            if let Ok(id) = node.as_reference()
+                && !seen.insert(id)
+            {
+                continue;
+            }
+            let Ok(node) = doc.dereference(&node).and_then(|(_, n)| n.as_dict()) else {
+                continue;
+            };
+            if let Ok(nums) = node.get(b"Nums").and_then(Object::as_array) {
+                for pair in nums.chunks(2) {
+                    let [index, label] = pair else {
+                        continue;
+                    };
+                    let (Ok(index), Ok((_, label))) = (index.as_i64(), doc.dereference(label))
+                    else {
+                        continue;
+                    };
+                    let Ok(label) = label.as_dict() else {
+                        continue;
+                    };
+                    let Some(first_page) = u32::try_from(index.max(0))
+                        .ok()
+                        .and_then(|index| index.checked_add(1))
+                    else {
+                        continue;
+                    };
+                    ranges.push(LabelRange {
+                        first_page,
+                        style: label
+                            .get(b"S")
+                            .and_then(Object::as_name)
+                            .map_or(LabelStyle::None, LabelStyle::from_name),
+                        start: label
+                            .get(b"St")
+                            .and_then(Object::as_i64)
+                            .map_or(1, |st| st.clamp(1, i64::from(u32::MAX)) as u32),
+                        prefix: label
+                            .get(b"P")
+                            .and_then(lopdf::decode_text_string)
+                            .unwrap_or_default(),
+                    });
+                }
+            }
+            if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+                stack.extend(kids.iter().cloned());
+            }
+        }
+
+        if ranges.is_empty() {
+            return None;
+        }
+        ranges.sort_by_key(|range| range.first_page);
+        Some(Self { ranges })
+    }
+}
+
+impl FromStr for PageLabels {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_number = |n: &str| -> Result<u32, String> {
+            match n.trim().parse::<u32>() {
+                Ok(0) | Err(_) => Err(format!("\"{}\" is not a page number", n.trim())),
+                Ok(n) => Ok(n),
+            }
+        };
+
+        let mut ranges: Vec<LabelRange> = Vec::new();
+        // Where the previous range stopped, if it had an end
+        let mut last_end: Option<Option<u32>> = None;
+        for part in s.split(',') {
+            let mut fields = part.trim_start().splitn(4, ':');
+            let pages = fields.next().unwrap_or_default();
+            let (first_page, end) = match pages.split_once('-') {
+                None => {
+                    let page = parse_number(pages)?;
+                    (page, Some(page))
+                }
+                Some((start, "")) => (parse_number(start)?, None),
+                Some((start, end)) => (parse_number(start)?, Some(parse_number(end)?)),
+            };
+            if end.is_some_and(|end| end < first_page) {
+                return Err(format!("\"{}\" ends before it starts", pages));
+            }
+            let style = match fields.next().map(str::trim) {
+                Some("D") => LabelStyle::Decimal,
+                Some("r") => LabelStyle::LowerRoman,
+                Some("R") => LabelStyle::UpperRoman,
+                Some("a") => LabelStyle::LowerLetters,
+                Some("A") => LabelStyle::UpperLetters,
+                Some("") => LabelStyle::None,
+                Some(other) => {
+                    return Err(format!(
+                        "\"{}\" is not a style. Use D, r, R, a, A or nothing",
+                        other
+                    ));
+                }
+                None => return Err(format!("\"{}\" needs a style, e.g. {}:D", part, pages)),
+            };
+            let start = match fields.next() {
+                Some(start) if !start.trim().is_empty() => parse_number(start)?,
+                _ => 1,
+            };
+            let prefix = fields.next().unwrap_or_default().to_string();
+
+            match last_end {
+                Some(None) => return Err(format!("\"{}\" comes after a range with no end", part)),
+                Some(Some(end)) if first_page <= end => {
+                    return Err(format!("\"{}\" overlaps the range before it", part));
+                }
+                // Pages between two ranges go back to their plain numbers
+                Some(Some(end)) if first_page > end.saturating_add(1) => ranges.push(LabelRange {
+                    first_page: end + 1,
+                    style: LabelStyle::Decimal,
+                    start: end + 1,
+                    prefix: String::new(),
+                }),
+                _ => {}
+            }
+            if ranges.is_empty() && first_page > 1 {
+                ranges.push(LabelRange {
+                    first_page: 1,
+                    style: LabelStyle::Decimal,
+                    start: 1,
+                    prefix: String::new(),
+                });
+            }
+            ranges.push(LabelRange {
+                first_page,
+                style,
+                start,
+                prefix,
+            });
+            last_end = Some(end);
+        }
+        if let Some(Some(end)) = last_end
+            && end < u32::MAX
+        {
+            ranges.push(LabelRange {
+                first_page: end + 1,
+                style: LabelStyle::Decimal,
+                start: end + 1,
+                prefix: String::new(),
+            });
+        }
+
+        Ok(Self { ranges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(spec: &str) -> PageLabels {
+        spec.parse().unwrap()
+    }
+
+    fn all_labels(labels: &PageLabels, pages: u32) -> Vec<String> {
+        (1..=pages).map(|page| labels.label(page)).collect()
+    }
+
+    fn document_with(tree: Dictionary) -> Document {
+        let mut doc = Document::with_version("1.7");
+        let catalog = doc.add_object(dictionary! { "Type" => "Catalog", "PageLabels" => tree });
+        doc.trailer.set("Root", catalog);
+        doc
+    }
+
+    #[test]
+    fn roman_numerals_at_the_boundaries() {
+        let cases = [
+            (1, "I"),
+            (4, "IV"),
+            (9, "IX"),
+            (14, "XIV"),
+            (40, "XL"),
+            (90, "XC"),
+            (400, "CD"),
+            (1994, "MCMXCIV"),
+            (3999, "MMMCMXCIX"),
+        ];
+        for (number, numeral) in cases {
+            assert_eq!(roman(number), numeral);
+        }
+        assert_eq!(LabelStyle::LowerRoman.format(14), "xiv");
+        assert_eq!(LabelStyle::UpperRoman.format(4000), "4000");
+    }
+
+    #[test]
+    fn letters_repeat_after_z() {
+        assert_eq!(letters(0), "");
+        assert_eq!(letters(1), "A");
+        assert_eq!(letters(26), "Z");
+        assert_eq!(letters(27), "AA");
+        assert_eq!(letters(52), "ZZ");
+        assert_eq!(letters(53), "AAA");
+        assert_eq!(LabelStyle::LowerLetters.format(28), "bb");
+        assert_eq!(LabelStyle::UpperLetters.format(260), "Z".repeat(10));
+        assert_eq!(LabelStyle::UpperLetters.format(261), "261");
+    }
+
+    #[test]
+    fn spec_labels_each_range() {
+        assert_eq!(
+            all_labels(&labels("1-4:r,5-:D:1"), 6),
+            ["i", "ii", "iii", "iv", "1", "2"]
+        );
+        assert_eq!(
+            all_labels(&labels("1-2:A::Plate :x,3-:D:10"), 3),
+            ["Plate :xA", "Plate :xB", "10"]
+        );
+        assert_eq!(
+            all_labels(&labels("1-2::1:Cover"), 3),
+            ["Cover", "Cover", "3"]
+        );
+    }
+
+    #[test]
+    fn spec_numbers_uncovered_pages_as_in_the_file() {
+        // Before the first range, in a gap and after a closed last range
+        assert_eq!(
+            all_labels(&labels("3-4:r"), 6),
+            ["1", "2", "i", "ii", "5", "6"]
+        );
+        assert_eq!(
+            all_labels(&labels("1-2:r,5-:D"), 6),
+            ["i", "ii", "3", "4", "1", "2"]
+        );
+        assert_eq!(all_labels(&labels("2:R"), 3), ["1", "I", "3"]);
+    }
+
+    #[test]
+    fn spec_errors() {
+        for spec in [
+            "1-4:r,3-:D",
+            "1-4:r,4:D",
+            "1-:r,5-:D",
+            "4-2:D",
+            "1-:x",
+            "1-",
+            "0-:D",
+            "1-:D:0",
+            "a-:D",
+            "",
+        ] {
+            assert!(spec.parse::<PageLabels>().is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn huge_numbers_saturate_and_fall_back_to_decimal() {
+        let big = labels("1-:R:4294967290");
+        assert_eq!(big.label(1), "4294967290");
+        assert_eq!(big.label(100), "4294967295");
+        assert_eq!(big.from_page(50).label(1), "4294967295");
+        assert!(format!("{}-:D", u32::MAX).parse::<PageLabels>().is_ok());
+    }
+
+    #[test]
+    fn from_page_renumbers_for_a_later_start() {
+        let spec = labels("1-4:r,5-:D:1");
+        assert_eq!(all_labels(&spec.from_page(3), 4), ["iii", "iv", "1", "2"]);
+        assert_eq!(all_labels(&spec.from_page(6), 2), ["2", "3"]);
+        assert_eq!(spec.from_page(1), spec);
+    }
+
+    #[test]
+    fn number_tree_holds_the_ranges_on_the_pages() {
+        let tree = labels("1-4:r::Intro ,5-:D:3").number_tree(10);
+        let nums = tree.get(b"Nums").and_then(Object::as_array).unwrap();
+        assert_eq!(nums.len(), 4);
+        assert_eq!(nums[0].as_i64().unwrap(), 0);
+        let first = nums[1].as_dict().unwrap();
+        assert_eq!(first.get(b"S").and_then(Object::as_name).unwrap(), b"r");
+        assert!(first.get(b"St").is_err());
+        assert_eq!(
+            lopdf::decode_text_string(first.get(b"P").unwrap()).unwrap(),
+            "Intro "
+        );
+        assert_eq!(nums[2].as_i64().unwrap(), 4);
+        assert_eq!(
+            nums[3]
+                .as_dict()
+                .unwrap()
+                .get(b"St")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            3
+        );
+
+        // Ranges past the last page are left out
+        let tree = labels("1-4:r,5-:D:3").number_tree(4);
+        assert_eq!(
+            tree.get(b"Nums").and_then(Object::as_array).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn from_document_reads_back_the_number_tree() {
+        let spec = labels("1-4:r::Intro ,5-:D:3");
+        let doc = document_with(spec.number_tree(10));
+        assert_eq!(PageLabels::from_document(&doc), Some(spec));
+        assert_eq!(
+            PageLabels::from_document(&document_with(Dictionary::new())),
+            None
+        );
+    }
+
+    #[test]
+    fn from_document_bounds_untrusted_numbers() {
+        let doc = document_with(dictionary! {
+            "Nums" => vec![
+                Object::Integer(0),
+                Object::Dictionary(dictionary! { "S" => "R", "St" => i64::MAX }),
+                Object::Integer(i64::from(u32::MAX)),
+                Object::Dictionary(dictionary! { "S" => "D" }),
+            ],
+        });
+        let labels = PageLabels::from_document(&doc).unwrap();
+        assert_eq!(labels.label(1), u32::MAX.to_string());
+        assert_eq!(labels.label(5), u32::MAX.to_string());
+    }
+}
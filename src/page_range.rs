@@ -0,0 +1,114 @@
+use std::str::FromStr;
+
+/// Pages picked with a list like `1-10,15,20-`. Page numbers start at 1 and a
+/// range without an end runs to the last page.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PageRanges {
+    ranges: Vec<(u32, Option<u32>)>,
+}
+
+impl PageRanges {
+    pub fn contains(&self, page: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(start, end)| page >= start && end.is_none_or(|end| page <= end))
+    }
+
+    /// Expand the ranges in the order they were given, dropping pages past the end
+    pub fn pages(&self, total_pages: u32) -> Vec<u32> {
+        self.ranges
+            .iter()
+            .flat_map(|&(start, end)| start..=end.unwrap_or(total_pages).min(total_pages))
+            .collect()
+    }
+
+    /// Expand every range on its own, dropping pages past the end
+    pub fn parts(&self, total_pages: u32) -> Vec<Vec<u32>> {
+        self.ranges
+            .iter()
+            .map(|&(start, end)| (start..=end.unwrap_or(total_pages).min(total_pages)).collect())
+            .collect()
+    }
+}
+
+impl FromStr for PageRanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_page = |p: &str| -> Result<u32, String> {
+            match p.trim().parse::<u32>() {
+                Ok(0) | Err(_) => Err(format!("\"{}\" is not a page number", p.trim())),
+                Ok(n) => Ok(n),
+            }
+        };
+
+        let mut ranges = Vec::new();
+        for part in s.split(',') {
+            let part = part.trim();
+            let range = match part.split_once('-') {
+                None => {
+                    let page = parse_page(part)?;
+                    (page, Some(page))
+                }
+                Some((start, "")) => (parse_page(start)?, None),
+                Some(("", end)) => (1, Some(parse_page(end)?)),
+                Some((start, end)) => (parse_page(start)?, Some(parse_page(end)?)),
+            };
+            if range.1.is_some_and(|end| end < range.0) {
+                return Err(format!("\"{}\" ends before it starts", part));
+            }
+            ranges.push(range);
+        }
+
+        Ok(Self { ranges })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_pages_and_ranges() {
+        let ranges: PageRanges = "1-3, 7,10-".parse().unwrap();
+        assert_eq!(ranges.ranges, vec![(1, Some(3)), (7, Some(7)), (10, None)]);
+    }
+
+    #[test]
+    fn open_start_begins_at_the_first_page() {
+        let ranges: PageRanges = "-3".parse().unwrap();
+        assert_eq!(ranges.ranges, vec![(1, Some(3))]);
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        assert!("0".parse::<PageRanges>().is_err());
+        assert!("5-2".parse::<PageRanges>().is_err());
+        assert!("a-4".parse::<PageRanges>().is_err());
+        assert!("1,,2".parse::<PageRanges>().is_err());
+        assert!("".parse::<PageRanges>().is_err());
+    }
+
+    #[test]
+    fn pages_keep_the_given_order_and_stop_at_the_end() {
+        let ranges: PageRanges = "4-,2,1-2".parse().unwrap();
+        assert_eq!(ranges.pages(6), vec![4, 5, 6, 2, 1, 2]);
+        assert_eq!(ranges.pages(3), vec![2, 1, 2]);
+    }
+
+    #[test]
+    fn contains_checks_every_range() {
+        let ranges: PageRanges = "2-3,8-".parse().unwrap();
+        assert!(!ranges.contains(1));
+        assert!(ranges.contains(3));
+        assert!(!ranges.contains(5));
+        assert!(ranges.contains(100));
+    }
+
+    #[test]
+    fn parts_expand_each_range_on_its_own() {
+        let ranges: PageRanges = "1-2,4,5-".parse().unwrap();
+        assert_eq!(ranges.parts(6), vec![vec![1, 2], vec![4], vec![5, 6]]);
+        assert_eq!(ranges.parts(3), vec![vec![1, 2], vec![], vec![]]);
+    }
+}
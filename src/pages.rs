@@ -0,0 +1,138 @@
+use crate::Run;
+use crate::error::PDFConError;
+use crate::merge::flatten_inherited;
+use crate::page_range::PageRanges;
+use crate::security;
+use lopdf::{Document, Object, ObjectId, dictionary};
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+/// Rewrite the page tree of a pdf. Page numbers always refer to the input document so
+/// options can be combined without thinking about the order they're applied in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pages {
+    pub in_file: PathBuf,
+    pub out_file: PathBuf,
+    pub password: Option<String>,
+    /// New page order. Pages can be listed more than once
+    pub order: Option<PageRanges>,
+    pub delete: Option<PageRanges>,
+    /// Pages that get a copy right after themselves
+    pub duplicate: Option<PageRanges>,
+    /// Degrees to turn pages clockwise and which pages. None means every page
+    pub rotate: Vec<(i64, Option<PageRanges>)>,
+}
+
+impl Pages {
+    /// Original page numbers in their new order
+    fn sequence(&self, total: u32) -> Vec<u32> {
+        let mut sequence = match &self.order {
+            Some(order) => order.pages(total),
+            None => (1..=total).collect(),
+        };
+        if let Some(delete) = &self.delete {
+            sequence.retain(|page| !delete.contains(*page));
+        }
+        if let Some(duplicate) = &self.duplicate {
+            sequence = sequence
+                .into_iter()
+                .flat_map(|page| {
+                    let copies = if duplicate.contains(page) { 2 } else { 1 };
+                    std::iter::repeat_n(page, copies)
+                })
+                .collect();
+        }
+        sequence
+    }
+
+    fn rotation(&self, page: u32) -> i64 {
+        self.rotate
+            .iter()
+            .filter(|(_, pages)| pages.as_ref().is_none_or(|p| p.contains(page)))
+            .map(|(degrees, _)| degrees)
+            .sum()
+    }
+
+    fn rewrite(&self, doc: &mut Document) -> Result<(), PDFConError> {
+        let pages = doc.get_pages();
+        for page_id in pages.values() {
+            flatten_inherited(doc, *page_id)?;
+        }
+
+        // Turn the original pages first so that copies come out the same way
+        for (page, page_id) in &pages {
+            let turn = self.rotation(*page);
+            if turn != 0 {
+                let page_dict = doc.get_dictionary_mut(*page_id)?;
+                let current = page_dict
+                    .get(b"Rotate")
+                    .and_then(Object::as_i64)
+                    .unwrap_or(0);
+                page_dict.set("Rotate", (current + turn).rem_euclid(360));
+            }
+        }
+
+        let mut kids: Vec<ObjectId> = Vec::new();
+        for page in self.sequence(pages.len() as u32) {
+            let page_id = pages[&page];
+            // A page object can only sit in the tree once. Repeats get a copy of the page
+            // dictionary that shares its contents and resources
+            if kids.contains(&page_id) {
+                let copy = doc.get_dictionary(page_id)?.clone();
+                kids.push(doc.add_object(copy));
+            } else {
+                kids.push(page_id);
+            }
+        }
+        if kids.is_empty() {
+            return Err(PDFConError::NoPagesLeft);
+        }
+
+        let pages_id = doc.new_object_id();
+        for page_id in &kids {
+            doc.get_dictionary_mut(*page_id)?.set("Parent", pages_id);
+        }
+        let pages_dict = dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as u32,
+            "Kids" => kids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+        };
+        doc.objects.insert(pages_id, Object::Dictionary(pages_dict));
+        doc.catalog_mut()?.set("Pages", pages_id);
+
+        // Deleted pages and the images only they used go with the old page tree
+        doc.prune_objects();
+
+        Ok(())
+    }
+}
+
+impl Run for Pages {
+    fn run(&self) -> Result<(), PDFConError> {
+        let mut document = Document::load(&self.in_file)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
+        // Encrypted input is written back with the same keys and permissions
+        let encryption_state = document.encryption_state.clone();
+
+        self.rewrite(&mut document)?;
+
+        if let Some(state) = encryption_state {
+            document.encrypt(&state)?;
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.out_file)?;
+        let mut writer = BufWriter::new(file);
+
+        document.save_to(&mut writer)?;
+
+        Ok(())
+    }
+}
@@ -1,14 +1,17 @@
-use std::io::{BufWriter, Write};
+use std::io::{BufWriter, Cursor, Write};
 
 use crate::error::PDFConError;
 use flate2::write::ZlibEncoder;
 use image::{ImageEncoder, codecs::png};
+use lopdf::Dictionary;
 use oxipng;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PDFConColorSpace {
     RGB8,
     RGB16,
+    L1,
     L8,
     L16,
     CMYK,
@@ -19,25 +22,30 @@ impl PDFConColorSpace {
         match *self {
             Self::RGB8 => (b"DeviceRGB".to_vec(), 8),
             Self::RGB16 => (b"DeviceRGB".to_vec(), 16),
+            Self::L1 => (b"DeviceGray".to_vec(), 1),
             Self::L8 => (b"DeviceGray".to_vec(), 8),
             Self::L16 => (b"DeviceGray".to_vec(), 16),
             Self::CMYK => (b"DeviceCMYK".to_vec(), 8),
         }
     }
 
-    pub fn from_pdf_format(info: (&[u8], u8)) -> Self {
+    /// None for colour spaces and bit depths the encoders can't take as they are.
+    /// [`normalize_samples`] brings those to one they can
+    pub fn from_pdf_format(info: (&[u8], u8)) -> Option<Self> {
         match info {
-            (b"DeviceRGB", 8) => Self::RGB8,
-            (b"DeviceRGB", 16) => Self::RGB16,
-            (b"DeviceGray", 8) => Self::L8,
-            (b"DeviceGray", 16) => Self::L16,
-            (b"DeviceCMYK", 8) => Self::CMYK,
-            _ => unreachable!(),
+            (b"DeviceRGB", 8) => Some(Self::RGB8),
+            (b"DeviceRGB", 16) => Some(Self::RGB16),
+            (b"DeviceGray", 1) => Some(Self::L1),
+            (b"DeviceGray", 8) => Some(Self::L8),
+            (b"DeviceGray", 16) => Some(Self::L16),
+            (b"DeviceCMYK", 8) => Some(Self::CMYK),
+            _ => None,
         }
     }
 
     pub fn into_extended(&self) -> image::ExtendedColorType {
         match self {
+            Self::L1 => image::ExtendedColorType::L1,
             Self::L8 => image::ExtendedColorType::L8,
             Self::L16 => image::ExtendedColorType::L16,
             Self::CMYK => image::ExtendedColorType::Cmyk8,
@@ -48,6 +56,7 @@ impl PDFConColorSpace {
 
     pub fn into_bits(&self) -> u8 {
         match self {
+            Self::L1 => 1,
             Self::L8 => 8,
             Self::L16 => 16,
             Self::CMYK => 8,
@@ -57,6 +66,59 @@ impl PDFConColorSpace {
     }
 }
 
+/// How an image stream is encoded, judged by its filter chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageEncoding {
+    Jpeg,
+    Png,
+    Ccitt,
+    Jbig2,
+    Jpx,
+}
+
+impl ImageEncoding {
+    pub fn from_filters(filters: &[&[u8]]) -> Self {
+        // Image filters always come last in the chain so the first one found wins
+        for filter in filters.iter().rev() {
+            match *filter {
+                b"DCTDecode" => return Self::Jpeg,
+                b"CCITTFaxDecode" => return Self::Ccitt,
+                b"JBIG2Decode" => return Self::Jbig2,
+                b"JPXDecode" => return Self::Jpx,
+                _ => {}
+            }
+        }
+        Self::Png
+    }
+}
+
+/// Image format unpack writes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Jpegs stay jpegs and everything else becomes a png
+    #[default]
+    Keep,
+    Png,
+    Jpeg,
+    /// Lossless webp
+    Webp,
+    Tiff,
+}
+
+impl OutputFormat {
+    /// Extension of the file an image with this encoding is written to
+    pub fn extension(&self, encoding: ImageEncoding) -> &'static str {
+        match (self, encoding) {
+            (Self::Keep, ImageEncoding::Jpeg) => "jpg",
+            (Self::Keep, _) => "png",
+            (Self::Png, _) => "png",
+            (Self::Jpeg, _) => "jpg",
+            (Self::Webp, _) => "webp",
+            (Self::Tiff, _) => "tif",
+        }
+    }
+}
+
 pub fn decompress(content: &[u8]) -> Result<Vec<u8>, PDFConError> {
     let mut output = Vec::new();
     let out_writer = BufWriter::new(&mut output);
@@ -64,21 +126,122 @@ pub fn decompress(content: &[u8]) -> Result<Vec<u8>, PDFConError> {
     let mut decompress =
         flate2::write::ZlibDecoder::new_with_decompress(out_writer, flate2::Decompress::new(true));
 
-    decompress.write_all(&content)?;
+    decompress.write_all(content)?;
     decompress.flush()?;
     let _ = decompress.finish()?;
 
     Ok(output)
 }
 
-pub fn encode_and_save_png(
+fn paeth(left: u8, above: u8, upper_left: u8) -> u8 {
+    let p = left as i16 + above as i16 - upper_left as i16;
+    let (pa, pb, pc) = (
+        (p - left as i16).abs(),
+        (p - above as i16).abs(),
+        (p - upper_left as i16).abs(),
+    );
+    if pa <= pb && pa <= pc {
+        left
+    } else if pb <= pc {
+        above
+    } else {
+        upper_left
+    }
+}
+
+/// Undo the predictor named in a FlateDecode stream's DecodeParms. lopdf can do this too
+/// but its Average filter halves only the byte above, which breaks the rows that use it
+pub fn unpredict(content: Vec<u8>, parms: Option<&Dictionary>) -> Result<Vec<u8>, PDFConError> {
+    let Some(parms) = parms else {
+        return Ok(content);
+    };
+    let int = |key: &[u8], default: i64| {
+        parms
+            .get(key)
+            .and_then(|v| v.as_i64())
+            .unwrap_or(default)
+            .max(1) as usize
+    };
+    let predictor = parms
+        .get(b"Predictor")
+        .and_then(|p| p.as_i64())
+        .unwrap_or(1);
+    let (colors, bits, columns) = (
+        int(b"Colors", 1),
+        int(b"BitsPerComponent", 8),
+        int(b"Columns", 1),
+    );
+    let bpp = (colors * bits).div_ceil(8);
+    let row_len = (colors * bits * columns).div_ceil(8);
+
+    match predictor {
+        1 => Ok(content),
+        // TIFF predictor 2 stores each sample as the difference from the one to its left
+        2 if bits == 8 => {
+            let mut content = content;
+            for row in content.chunks_mut(row_len) {
+                for i in bpp..row.len() {
+                    row[i] = row[i].wrapping_add(row[i - bpp]);
+                }
+            }
+            Ok(content)
+        }
+        // PNG predictors prefix every row with the filter it was written with
+        10..=15 => {
+            let mut output = Vec::with_capacity(content.len() / (row_len + 1) * row_len);
+            let mut previous = vec![0u8; row_len];
+            for row in content.chunks(row_len + 1) {
+                let (filter, data) = row
+                    .split_first()
+                    .ok_or(PDFConError::PredictorError(predictor))?;
+                let mut current = data.to_vec();
+                // A short last row is padded as if the missing bytes were zero
+                current.resize(row_len, 0);
+                for i in 0..row_len {
+                    let left = if i >= bpp { current[i - bpp] } else { 0 };
+                    let upper_left = if i >= bpp { previous[i - bpp] } else { 0 };
+                    current[i] = current[i].wrapping_add(match filter {
+                        0 => 0,
+                        1 => left,
+                        2 => previous[i],
+                        3 => ((left as u16 + previous[i] as u16) / 2) as u8,
+                        4 => paeth(left, previous[i], upper_left),
+                        _ => return Err(PDFConError::PredictorError(predictor)),
+                    });
+                }
+                output.extend_from_slice(&current);
+                previous = current;
+            }
+            Ok(output)
+        }
+        _ => Err(PDFConError::PredictorError(predictor)),
+    }
+}
+
+pub fn encode_png(
     content: &[u8],
     width: u32,
     height: u32,
     color_space: &PDFConColorSpace,
-    out_path: &PathBuf,
     optimize: bool,
-) -> Result<(), PDFConError> {
+    resample: &resample::Resample,
+) -> Result<Vec<u8>, PDFConError> {
+    let resized = resample.resize_pixels(content, width, height, color_space, None);
+    let (content, width, height) = match &resized {
+        Some((pixels, w, h)) => (&pixels[..], *w, *h),
+        None => (content, width, height),
+    };
+
+    // The png encoder only takes whole bytes so 1 bit images are expanded to 8 bit gray
+    let expanded;
+    let (content, color_space) = match color_space {
+        PDFConColorSpace::L1 => {
+            expanded = expand_bilevel(content, width, height);
+            (&expanded[..], &PDFConColorSpace::L8)
+        }
+        _ => (content, color_space),
+    };
+
     let mut encoded = Vec::new();
     let encoder_writer = BufWriter::new(&mut encoded);
 
@@ -90,100 +253,1215 @@ pub fn encode_and_save_png(
 
     encoder.write_image(content, width, height, color_space.into_extended())?;
 
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(out_path)?;
-    let mut writer = BufWriter::new(file);
-
     if optimize {
-        let mut options = oxipng::Options::default();
-        options.strip = oxipng::StripChunks::All;
-        writer.write_all(&oxipng::optimize_from_memory(&encoded, &options)?)?;
-        writer.flush()?;
+        let options = oxipng::Options {
+            strip: oxipng::StripChunks::All,
+            ..Default::default()
+        };
+        Ok(oxipng::optimize_from_memory(&encoded, &options)?)
     } else {
-        writer.write_all(&encoded)?;
-        writer.flush()?;
+        Ok(encoded)
+    }
+}
+
+/// Encode raw pixels in any output format. Png output goes through [`encode_png`]
+#[allow(clippy::too_many_arguments)]
+pub fn encode_as(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    color_space: &PDFConColorSpace,
+    format: OutputFormat,
+    optimize: bool,
+    options: &optimize::JpegOptions,
+    resample: &resample::Resample,
+) -> Result<Vec<u8>, PDFConError> {
+    // None of the other formats are written as CMYK
+    let rgb;
+    let (content, color_space) = match color_space {
+        PDFConColorSpace::CMYK => {
+            rgb = cmyk_to_rgb(content);
+            (&rgb[..], &PDFConColorSpace::RGB8)
+        }
+        _ => (content, color_space),
+    };
+
+    if matches!(format, OutputFormat::Keep | OutputFormat::Png) {
+        return encode_png(content, width, height, color_space, optimize, resample);
+    }
+
+    let resized = resample.resize_pixels(content, width, height, color_space, None);
+    let (content, width, height) = match &resized {
+        Some((pixels, w, h)) => (&pixels[..], *w, *h),
+        None => (content, width, height),
+    };
+
+    let image =
+        to_dynamic(content, width, height, color_space).ok_or(PDFConError::BufferInnerError)?;
+
+    Ok(match format {
+        OutputFormat::Jpeg => optimize::encode_dynamic_jpeg(&image, options)?,
+        OutputFormat::Webp => {
+            // The webp encoder only takes 8 bit samples
+            let image = match image {
+                image::DynamicImage::ImageLuma16(_) => image::DynamicImage::from(image.to_luma8()),
+                image::DynamicImage::ImageRgb16(_) => image::DynamicImage::from(image.to_rgb8()),
+                image => image,
+            };
+            let mut cursor = Cursor::new(Vec::new());
+            image.write_to(&mut cursor, image::ImageFormat::WebP)?;
+            cursor.into_inner()
+        }
+        _ => {
+            let mut cursor = Cursor::new(Vec::new());
+            image.write_to(&mut cursor, image::ImageFormat::Tiff)?;
+            cursor.into_inner()
+        }
+    })
+}
+
+pub fn to_dynamic(
+    content: &[u8],
+    width: u32,
+    height: u32,
+    color_space: &PDFConColorSpace,
+) -> Option<image::DynamicImage> {
+    use image::{DynamicImage, ImageBuffer};
+
+    let be16 = |bytes: &[u8]| -> Vec<u16> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect()
+    };
+
+    match color_space {
+        PDFConColorSpace::L1 => {
+            ImageBuffer::from_raw(width, height, expand_bilevel(content, width, height))
+                .map(DynamicImage::ImageLuma8)
+        }
+        PDFConColorSpace::L8 => {
+            ImageBuffer::from_raw(width, height, content.to_vec()).map(DynamicImage::ImageLuma8)
+        }
+        PDFConColorSpace::L16 => {
+            ImageBuffer::from_raw(width, height, be16(content)).map(DynamicImage::ImageLuma16)
+        }
+        PDFConColorSpace::RGB8 => {
+            ImageBuffer::from_raw(width, height, content.to_vec()).map(DynamicImage::ImageRgb8)
+        }
+        PDFConColorSpace::RGB16 => {
+            ImageBuffer::from_raw(width, height, be16(content)).map(DynamicImage::ImageRgb16)
+        }
+        PDFConColorSpace::CMYK => {
+            ImageBuffer::from_raw(width, height, cmyk_to_rgb(content)).map(DynamicImage::ImageRgb8)
+        }
+    }
+}
+
+/// Naive CMYK to RGB conversion. Good enough for viewing without a colour profile
+pub fn cmyk_to_rgb(content: &[u8]) -> Vec<u8> {
+    content
+        .chunks_exact(4)
+        .flat_map(|p| {
+            let k = 255 - p[3] as u32;
+            [0, 1, 2].map(|i| ((255 - p[i] as u32) * k / 255) as u8)
+        })
+        .collect()
+}
+
+/// Whether jpeg data has Adobe's APP14 marker. CMYK jpegs with one store their samples
+/// inverted, the way Photoshop writes them, and viewers invert them back
+pub fn has_adobe_marker(content: &[u8]) -> bool {
+    if !content.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let mut pos = 2;
+    while pos + 4 <= content.len() {
+        if content[pos] != 0xFF {
+            return false;
+        }
+        match content[pos + 1] {
+            // Fill byte before a marker
+            0xFF => pos += 1,
+            // Start of scan or end of image. The markers are all before the scan
+            0xDA | 0xD9 => return false,
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => pos += 2,
+            marker => {
+                if marker == 0xEE && content[pos + 4..].starts_with(b"Adobe") {
+                    return true;
+                }
+                pos += 2 + u16::from_be_bytes([content[pos + 2], content[pos + 3]]) as usize;
+            }
+        }
+    }
+    false
+}
+
+fn expand_bilevel(content: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut expanded = Vec::with_capacity(width as usize * height as usize);
+    for row in content.chunks(row_bytes).take(height as usize) {
+        for x in 0..width as usize {
+            let bit = row.get(x / 8).map_or(0, |b| (b >> (7 - x % 8)) & 1);
+            expanded.push(if bit == 1 { 255 } else { 0 });
+        }
+    }
+    expanded
+}
+
+/// Bring raw image samples to a layout the encoders take. 1 bit gray stays packed, other
+/// depths are scaled to 8 bits and 16 bit samples stay 16 bit. A Decode array, given as
+/// a low and high value per component, is applied to the samples
+pub fn normalize_samples(
+    content: Vec<u8>,
+    width: u32,
+    height: u32,
+    color_space: &[u8],
+    bits: u8,
+    decode: Option<&[f32]>,
+) -> Result<(Vec<u8>, PDFConColorSpace), PDFConError> {
+    let components = match color_space {
+        b"DeviceGray" => 1,
+        b"DeviceRGB" => 3,
+        b"DeviceCMYK" => 4,
+        other => {
+            return Err(PDFConError::UnsupportedImage(format!(
+                "{} colour space",
+                String::from_utf8_lossy(other)
+            )));
+        }
+    };
+    if !matches!(bits, 1 | 2 | 4 | 8 | 16) {
+        return Err(PDFConError::UnsupportedImage(format!(
+            "{} bit samples",
+            bits
+        )));
+    }
+    // Only ranges that differ from the default [0 1] change anything
+    let decode = decode.filter(|d| {
+        d.len() >= 2 * components && d.chunks(2).take(components).any(|r| r != [0.0, 1.0])
+    });
+
+    let unsupported = || {
+        PDFConError::UnsupportedImage(format!(
+            "{} bit {}",
+            bits,
+            String::from_utf8_lossy(color_space)
+        ))
+    };
+    if bits == 1 && components == 1 {
+        match decode {
+            None => return Ok((content, PDFConColorSpace::L1)),
+            // Stencil masks and bilevel scans are often stored the other way round
+            Some([1.0, 0.0, ..]) => {
+                let flipped = content.into_iter().map(|b| !b).collect();
+                return Ok((flipped, PDFConColorSpace::L1));
+            }
+            Some(_) => {}
+        }
+    }
+    let out_bits = if bits == 16 { 16 } else { 8 };
+    let out_color_space =
+        PDFConColorSpace::from_pdf_format((color_space, out_bits)).ok_or_else(unsupported)?;
+    if bits == out_bits && decode.is_none() {
+        return Ok((content, out_color_space));
+    }
+
+    let max = ((1u32 << bits) - 1) as f32;
+    let out_max = ((1u32 << out_bits) - 1) as f32;
+    let samples_per_row = width as usize * components;
+    // Rows start on a byte boundary
+    let row_bytes = (samples_per_row * bits as usize).div_ceil(8);
+    let mut output =
+        Vec::with_capacity(samples_per_row * height as usize * (out_bits as usize / 8));
+    for row in content.chunks(row_bytes).take(height as usize) {
+        for i in 0..samples_per_row {
+            let value = match bits {
+                16 => row
+                    .get(2 * i..2 * i + 2)
+                    .map_or(0, |b| u16::from_be_bytes([b[0], b[1]]) as u32),
+                8 => row.get(i).copied().unwrap_or(0) as u32,
+                _ => {
+                    let offset = i * bits as usize;
+                    let byte = row.get(offset / 8).copied().unwrap_or(0);
+                    let shift = 8 - bits as usize - offset % 8;
+                    (byte as u32 >> shift) & max as u32
+                }
+            };
+            let mut level = value as f32 / max;
+            if let Some(decode) = decode {
+                let c = i % components;
+                level = decode[2 * c] + level * (decode[2 * c + 1] - decode[2 * c]);
+            }
+            let level = (level.clamp(0.0, 1.0) * out_max).round() as u16;
+            match out_bits {
+                16 => output.extend(level.to_be_bytes()),
+                _ => output.push(level as u8),
+            }
+        }
     }
+    // Short streams are padded so the image still has every row
+    output.resize(
+        samples_per_row * height as usize * (out_bits as usize / 8),
+        0,
+    );
+    Ok((output, out_color_space))
+}
 
-    Ok(())
+/// Parameters of a CCITTFaxDecode stream needed to decode it
+pub struct CcittParams {
+    pub k: i64,
+    pub columns: u32,
+    pub rows: u32,
+    pub byte_align: bool,
+    pub inverted: bool,
 }
 
-pub fn save_jpeg(content: &[u8], out_path: &PathBuf, optimize: bool) -> Result<(), PDFConError> {
-    let file = std::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        .open(out_path)?;
-    let mut writer = BufWriter::new(file);
+/// Decode CCITT fax data to 8 bit gray. Mixed one and two dimensional Group 3 data
+/// and byte aligned rows aren't supported
+fn decode_ccitt(content: &[u8], params: &CcittParams) -> Option<Vec<u8>> {
+    if params.byte_align {
+        return None;
+    }
+    let width = u16::try_from(params.columns).ok()?;
+    let (white, black) = if params.inverted { (0, 255) } else { (255, 0) };
 
-    let content = if optimize {
-        &optimize::optimize_jpeg_mem(content)?
+    let mut pixels = Vec::with_capacity(params.columns as usize * params.rows as usize);
+    let line_cb = |transitions: &[u16]| {
+        pixels.extend(fax::decoder::pels(transitions, width).map(|c| match c {
+            fax::Color::White => white,
+            fax::Color::Black => black,
+        }))
+    };
+    match params.k {
+        k if k < 0 => fax::decoder::decode_g4(
+            content.iter().copied(),
+            width,
+            u16::try_from(params.rows).ok(),
+            line_cb,
+        )?,
+        0 => fax::decoder::decode_g3(content.iter().copied(), line_cb)?,
+        _ => return None,
+    }
+
+    // Streams may stop early or run past the declared height
+    pixels.resize(params.columns as usize * params.rows as usize, white);
+    Some(pixels)
+}
+
+/// Decode CCITT fax data and encode it in any output format
+pub fn encode_ccitt_as(
+    content: &[u8],
+    params: &CcittParams,
+    format: OutputFormat,
+    optimize: bool,
+    options: &optimize::JpegOptions,
+    resample: &resample::Resample,
+) -> Result<Vec<u8>, PDFConError> {
+    let pixels = decode_ccitt(content, params).ok_or(PDFConError::CcittDecodeError)?;
+    encode_as(
+        &pixels,
+        params.columns,
+        params.rows,
+        &PDFConColorSpace::L8,
+        format,
+        optimize,
+        options,
+        resample,
+    )
+}
+
+pub fn reencode_jpeg(
+    content: &[u8],
+    optimize: bool,
+    options: &optimize::JpegOptions,
+    resample: &resample::Resample,
+) -> Result<Vec<u8>, PDFConError> {
+    let resized = if resample.is_active() {
+        optimize::resample_jpeg_mem(content, options, resample)?
     } else {
-        &content.to_vec()
+        None
     };
-    writer.write_all(&content)?;
-    writer.flush()?;
 
-    Ok(())
+    Ok(match resized {
+        Some(optimize::ImageData::JPEG(resized, ..) | optimize::ImageData::PNG(resized, ..)) => {
+            resized
+        }
+        None if optimize => optimize::optimize_jpeg_mem(content, options)?,
+        None => content.to_vec(),
+    })
+}
+
+/// Encode jpeg data in any output format. `inverted` is whether the pdf's Decode array
+/// flips the samples. Jpegs that stay jpegs keep their data unless they are resampled or
+/// optimized, or a viewer would show their colours differently than the pdf does
+pub fn encode_jpeg_as(
+    content: &[u8],
+    format: OutputFormat,
+    optimize: bool,
+    options: &optimize::JpegOptions,
+    resample: &resample::Resample,
+    inverted: bool,
+    cmyk_to_rgb: bool,
+) -> Result<Vec<u8>, PDFConError> {
+    let result = std::panic::catch_unwind(|| -> Result<Vec<u8>, PDFConError> {
+        let (_, _, color_space) = optimize::jpeg_header(content)?;
+        let cmyk = PDFConColorSpace::from(color_space) == PDFConColorSpace::CMYK;
+        // Viewers invert CMYK jpegs with an Adobe marker and show everything else as is
+        let same_colors = match cmyk {
+            true => has_adobe_marker(content) == inverted && !cmyk_to_rgb,
+            false => !inverted,
+        };
+        let as_jpeg = matches!(format, OutputFormat::Keep | OutputFormat::Jpeg);
+        if as_jpeg && same_colors {
+            return reencode_jpeg(content, optimize, options, resample);
+        }
+
+        let mut decoded = optimize::decode_jpeg(content)?;
+        if inverted {
+            decoded.pixels.iter_mut().for_each(|p| *p = 255 - *p);
+        }
+        let mut color_space = PDFConColorSpace::from(decoded.color_space);
+        if cmyk && cmyk_to_rgb {
+            decoded.pixels = self::cmyk_to_rgb(&decoded.pixels);
+            decoded.color_space = mozjpeg::ColorSpace::JCS_RGB;
+            color_space = PDFConColorSpace::RGB8;
+        }
+
+        if as_jpeg && color_space == PDFConColorSpace::CMYK {
+            // Written back with an Adobe marker so the samples are stored inverted
+            if let Some((pixels, width, height)) = resample.resize_pixels(
+                &decoded.pixels,
+                decoded.width as u32,
+                decoded.height as u32,
+                &color_space,
+                decoded.dpi(),
+            ) {
+                decoded.pixels = pixels;
+                decoded.width = width as usize;
+                decoded.height = height as usize;
+            }
+            decoded.pixels.iter_mut().for_each(|p| *p = 255 - *p);
+            return optimize::encode_jpeg(&decoded, options);
+        }
+
+        encode_as(
+            &decoded.pixels,
+            decoded.width as u32,
+            decoded.height as u32,
+            &color_space,
+            if as_jpeg { OutputFormat::Jpeg } else { format },
+            optimize,
+            options,
+            resample,
+        )
+    });
+
+    match result {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("MozJpeg failed: {:?}", e);
+            Err(PDFConError::MozUnwindError)
+        }
+    }
+}
+
+// Unless
+impl From<mozjpeg::ColorSpace> for PDFConColorSpace {
+    fn from(c: mozjpeg::ColorSpace) -> Self {
+        match c {
+            mozjpeg::ColorSpace::JCS_CMYK | mozjpeg::ColorSpace::JCS_YCCK => Self::CMYK,
+            mozjpeg::ColorSpace::JCS_RGB => Self::RGB8,
+            mozjpeg::ColorSpace::JCS_GRAYSCALE => Self::L8,
+            _ => Self::RGB8,
+        }
+    }
 }
 
-// Unless
-impl From<mozjpeg::ColorSpace> for PDFConColorSpace {
-    fn from(c: mozjpeg::ColorSpace) -> Self {
-        match c {
-            mozjpeg::ColorSpace::JCS_CMYK => Self::CMYK,
-            mozjpeg::ColorSpace::JCS_RGB => Self::RGB8,
-            mozjpeg::ColorSpace::JCS_GRAYSCALE => Self::L8,
-            _ => Self::RGB8,
+impl From<image::ColorType> for PDFConColorSpace {
+    fn from(c: image::ColorType) -> Self {
+        match c {
+            image::ColorType::L8 | image::ColorType::La8 => Self::L8,
+            image::ColorType::Rgb8 | image::ColorType::Rgba8 => Self::RGB8,
+            _ => Self::RGB8,
+        }
+    }
+}
+
+pub fn compress_zlib(
+    v: Vec<u8>,
+    compression_method: flate2::Compression,
+) -> Result<Vec<u8>, PDFConError> {
+    let writer: BufWriter<Vec<u8>> = BufWriter::new(Vec::new());
+    let mut compressor = ZlibEncoder::new(writer, compression_method);
+    compressor.write_all(&v)?;
+
+    let compressed_bytes = compressor.finish()?;
+
+    let inner = compressed_bytes
+        .into_inner()
+        .map_err(|_| PDFConError::BufferInnerError)?;
+    Ok(inner)
+}
+
+/// Hash of everything that decides what an image stream decodes to. Other entries such as
+/// Length or the order of the keys don't change the image
+pub fn image_hash(dict: &Dictionary, content: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for key in [
+        b"Width".as_slice(),
+        b"Height",
+        b"BitsPerComponent",
+        b"ColorSpace",
+        b"Filter",
+        b"DecodeParms",
+        b"Decode",
+        b"ImageMask",
+    ] {
+        hasher.update(key);
+        hasher.update(format!("{:?}", dict.get(key).ok()));
+    }
+    hasher.update(content);
+    hasher.finalize().to_vec()
+}
+
+pub mod resample {
+    use super::PDFConColorSpace;
+    use image::imageops::{self, FilterType};
+    use image::{DynamicImage, ImageBuffer, Luma, Rgb, Rgba};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ResampleFilter {
+        Nearest,
+        Triangle,
+        CatmullRom,
+        Gaussian,
+        Lanczos3,
+    }
+
+    impl From<ResampleFilter> for FilterType {
+        fn from(f: ResampleFilter) -> Self {
+            match f {
+                ResampleFilter::Nearest => FilterType::Nearest,
+                ResampleFilter::Triangle => FilterType::Triangle,
+                ResampleFilter::CatmullRom => FilterType::CatmullRom,
+                ResampleFilter::Gaussian => FilterType::Gaussian,
+                ResampleFilter::Lanczos3 => FilterType::Lanczos3,
+            }
+        }
+    }
+
+    /// Limits images get downsampled to. When several are set the smallest result wins.
+    /// Images are never upscaled.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Resample {
+        /// Compared against the resolution recorded in the image file
+        pub max_dpi: Option<u32>,
+        pub max_width: Option<u32>,
+        pub max_height: Option<u32>,
+        /// Percentage of the original size
+        pub scale: Option<u32>,
+        pub filter: ResampleFilter,
+    }
+
+    impl Default for Resample {
+        fn default() -> Self {
+            Self {
+                max_dpi: None,
+                max_width: None,
+                max_height: None,
+                scale: None,
+                filter: ResampleFilter::Lanczos3,
+            }
+        }
+    }
+
+    impl Resample {
+        pub fn is_active(&self) -> bool {
+            self.max_dpi.is_some()
+                || self.max_width.is_some()
+                || self.max_height.is_some()
+                || self.scale.is_some()
+        }
+
+        /// Size the image should be resampled to. None if it already fits
+        pub fn target_size(
+            &self,
+            width: u32,
+            height: u32,
+            dpi: Option<(f32, f32)>,
+        ) -> Option<(u32, u32)> {
+            let mut factor = 1f64;
+            if let Some(scale) = self.scale {
+                factor = factor.min(scale as f64 / 100.0);
+            }
+            if let Some(max_width) = self.max_width {
+                factor = factor.min(max_width as f64 / width as f64);
+            }
+            if let Some(max_height) = self.max_height {
+                factor = factor.min(max_height as f64 / height as f64);
+            }
+            if let (Some(max_dpi), Some((x, y))) = (self.max_dpi, dpi) {
+                let dpi = x.max(y) as f64;
+                if dpi > 0.0 {
+                    factor = factor.min(max_dpi as f64 / dpi);
+                }
+            }
+
+            if factor >= 1.0 {
+                return None;
+            }
+
+            let target_width = ((width as f64 * factor).round() as u32).max(1);
+            let target_height = ((height as f64 * factor).round() as u32).max(1);
+            if (target_width, target_height) == (width, height) {
+                return None;
+            }
+            Some((target_width, target_height))
+        }
+
+        pub fn resize(
+            &self,
+            image: &DynamicImage,
+            dpi: Option<(f32, f32)>,
+        ) -> Option<DynamicImage> {
+            let (width, height) = self.target_size(image.width(), image.height(), dpi)?;
+            Some(image.resize_exact(width, height, self.filter.into()))
+        }
+
+        /// Resample raw PDF samples. 16 bit samples are big endian, the same as in a PDF stream
+        pub fn resize_pixels(
+            &self,
+            pixels: &[u8],
+            width: u32,
+            height: u32,
+            color_space: &PDFConColorSpace,
+            dpi: Option<(f32, f32)>,
+        ) -> Option<(Vec<u8>, u32, u32)> {
+            let (target_width, target_height) = self.target_size(width, height, dpi)?;
+            let filter: FilterType = self.filter.into();
+
+            let resized = match color_space {
+                // Filtering would just turn these back into gray
+                PDFConColorSpace::L1 => return None,
+                PDFConColorSpace::L8 => {
+                    let buffer =
+                        ImageBuffer::<Luma<u8>, _>::from_raw(width, height, pixels.to_vec())?;
+                    imageops::resize(&buffer, target_width, target_height, filter).into_raw()
+                }
+                PDFConColorSpace::RGB8 => {
+                    let buffer =
+                        ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, pixels.to_vec())?;
+                    imageops::resize(&buffer, target_width, target_height, filter).into_raw()
+                }
+                PDFConColorSpace::CMYK => {
+                    // image has no CMYK buffer but every channel is filtered the same way
+                    let buffer =
+                        ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, pixels.to_vec())?;
+                    imageops::resize(&buffer, target_width, target_height, filter).into_raw()
+                }
+                PDFConColorSpace::L16 => {
+                    let buffer =
+                        ImageBuffer::<Luma<u16>, _>::from_raw(width, height, from_be16(pixels))?;
+                    to_be16(
+                        imageops::resize(&buffer, target_width, target_height, filter).into_raw(),
+                    )
+                }
+                PDFConColorSpace::RGB16 => {
+                    let buffer =
+                        ImageBuffer::<Rgb<u16>, _>::from_raw(width, height, from_be16(pixels))?;
+                    to_be16(
+                        imageops::resize(&buffer, target_width, target_height, filter).into_raw(),
+                    )
+                }
+            };
+
+            Some((resized, target_width, target_height))
+        }
+    }
+
+    fn from_be16(bytes: &[u8]) -> Vec<u16> {
+        bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect()
+    }
+
+    fn to_be16(samples: Vec<u16>) -> Vec<u8> {
+        samples.iter().flat_map(|&x| x.to_be_bytes()).collect()
+    }
+
+    /// Resolution stored in a png's pHYs chunk in dots per inch
+    pub fn png_dpi(content: &[u8]) -> Option<(f32, f32)> {
+        // Skip the signature and walk the chunks until the image data starts
+        let mut pos = 8;
+        while pos + 8 <= content.len() {
+            let length = u32::from_be_bytes(content[pos..pos + 4].try_into().ok()?) as usize;
+            let data = content.get(pos + 8..pos + 8 + length)?;
+            match &content[pos + 4..pos + 8] {
+                b"pHYs" if length == 9 => {
+                    // Only a unit of metres gives an absolute resolution
+                    if data[8] != 1 {
+                        return None;
+                    }
+                    let x = u32::from_be_bytes(data[0..4].try_into().ok()?) as f32 * 0.0254;
+                    let y = u32::from_be_bytes(data[4..8].try_into().ok()?) as f32 * 0.0254;
+                    return Some((x, y));
+                }
+                b"IDAT" | b"IEND" => return None,
+                _ => {}
+            }
+            pos += length + 12;
+        }
+        None
+    }
+}
+
+pub mod optimize {
+    use super::resample::{Resample, png_dpi};
+    use super::{PDFConColorSpace, compress_zlib, decompress};
+    use crate::error::PDFConError;
+    use flate2::Compression;
+    use image::{
+        self, ColorType, DynamicImage, GrayImage, ImageBuffer, ImageEncoder, Pixel, Primitive,
+        RgbImage,
+    };
+    use log::{debug, error};
+    use mozjpeg;
+    use mozjpeg_sys as ffi;
+    use std::borrow::Cow;
+    use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom};
+    use std::mem;
+    use std::os::raw::{c_int, c_ulong};
+
+    pub enum ImageData {
+        PNG(Vec<u8>, u32, u32, PDFConColorSpace),
+        JPEG(Vec<u8>, u32, u32, PDFConColorSpace),
+    }
+
+    /// An image and the size in points of the page it fills
+    pub struct PageImage {
+        pub image: ImageData,
+        pub width: f64,
+        pub height: f64,
+    }
+
+    impl PageImage {
+        /// Size the page from the image's pixels at the resolution it records, or a
+        /// point per pixel when it records none. Pass the size from before resampling
+        /// so a downsampled image still prints as large as the original
+        pub fn new(image: ImageData, width: u32, height: u32, dpi: Option<(f32, f32)>) -> Self {
+            let points = |pixels: u32, dpi: f32| {
+                if dpi > 0.0 {
+                    pixels as f64 * 72.0 / dpi as f64
+                } else {
+                    pixels as f64
+                }
+            };
+            let (x, y) = dpi.unwrap_or((0.0, 0.0));
+            Self {
+                image,
+                width: points(width, x),
+                height: points(height, y),
+            }
+        }
+    }
+
+    /// Threshold used to turn gray pixels into black and white
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Threshold {
+        /// Pixels at or above this value become white
+        Fixed(u8),
+        /// Pick the threshold from the image histogram with Otsu's method
+        Otsu,
+    }
+
+    /// Opt-in checks that store images with fewer channels or bits when their pixels allow it
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+    pub struct ColorAnalysis {
+        /// RGB images whose channels never differ by more than this are stored as DeviceGray
+        pub gray_tolerance: Option<u8>,
+        /// Gray images that are almost entirely black and white are thresholded to 1 bit
+        pub bilevel: Option<Threshold>,
+    }
+
+    impl ColorAnalysis {
+        pub fn is_active(&self) -> bool {
+            self.gray_tolerance.is_some() || self.bilevel.is_some()
+        }
+    }
+
+    // Channel difference allowed for an RGB image to count as gray when bilevel is
+    // asked for without a gray tolerance. The same as the --gray default
+    const DEFAULT_GRAY_TOLERANCE: u8 = 8;
+
+    // Share of pixels allowed between the dark and light ends of the histogram for an
+    // image to still count as black and white. Leaves room for anti-aliased text edges
+    const BILEVEL_MAX_MIDTONES: f64 = 0.05;
+    const MIDTONE_LOW: usize = 64;
+    const MIDTONE_HIGH: usize = 192;
+
+    pub enum ReducedImage {
+        Gray(GrayImage),
+        /// Rows of packed 1 bit samples, each padded to a whole byte
+        Bilevel(Vec<u8>),
+    }
+
+    fn is_near_gray(image: &RgbImage, tolerance: u8) -> bool {
+        image.pixels().all(|p| {
+            let [r, g, b] = p.0;
+            r.max(g).max(b) - r.min(g).min(b) <= tolerance
+        })
+    }
+
+    fn histogram(image: &GrayImage) -> [u64; 256] {
+        let mut histogram = [0u64; 256];
+        for p in image.pixels() {
+            histogram[p.0[0] as usize] += 1;
+        }
+        histogram
+    }
+
+    fn is_near_bilevel(histogram: &[u64; 256]) -> bool {
+        let total: u64 = histogram.iter().sum();
+        let midtones: u64 = histogram[MIDTONE_LOW..MIDTONE_HIGH].iter().sum();
+        total > 0 && (midtones as f64 / total as f64) <= BILEVEL_MAX_MIDTONES
+    }
+
+    pub fn otsu_threshold(histogram: &[u64; 256]) -> u8 {
+        let total: u64 = histogram.iter().sum();
+        let weighted_total: f64 = histogram
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| i as f64 * count as f64)
+            .sum();
+
+        let mut background_weight = 0u64;
+        let mut background_sum = 0f64;
+        let mut best_variance = 0f64;
+        let mut threshold = 128u8;
+        for (i, &count) in histogram.iter().enumerate() {
+            background_weight += count;
+            if background_weight == 0 {
+                continue;
+            }
+            let foreground_weight = total - background_weight;
+            if foreground_weight == 0 {
+                break;
+            }
+            background_sum += i as f64 * count as f64;
+            let background_mean = background_sum / background_weight as f64;
+            let foreground_mean = (weighted_total - background_sum) / foreground_weight as f64;
+            let variance = background_weight as f64
+                * foreground_weight as f64
+                * (background_mean - foreground_mean).powi(2);
+            if variance > best_variance {
+                best_variance = variance;
+                // Pixels above the class boundary are the light ones
+                threshold = (i + 1).min(255) as u8;
+            }
+        }
+        threshold
+    }
+
+    /// Pack gray pixels into 1 bit rows. In DeviceGray a set bit is white
+    pub fn pack_bilevel(image: &GrayImage, threshold: u8) -> Vec<u8> {
+        let row_bytes = (image.width() as usize).div_ceil(8);
+        let mut packed = vec![0u8; row_bytes * image.height() as usize];
+        for (x, y, p) in image.enumerate_pixels() {
+            if p.0[0] >= threshold {
+                packed[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        packed
+    }
+
+    // Pixels darker than this count as ink. Show-through from the other side of a
+    // scanned sheet is usually lighter
+    const INK_LEVEL: u8 = 128;
+    // Share of each edge left out when measuring ink. Scanner shadows and punch holes
+    // end up there
+    const INK_MARGIN: f64 = 0.05;
+    // Pages are measured at this width. Averaging the pixels down hides dust and noise
+    const INK_SAMPLE_WIDTH: u32 = 600;
+
+    /// Percentage of a page covered in ink. Nearly blank pages score close to 0
+    pub fn ink_coverage(image: &ImageData) -> Result<f64, PDFConError> {
+        let dynamic = match image {
+            ImageData::PNG(data, width, height, color_space) => {
+                super::to_dynamic(&decompress(data)?, *width, *height, color_space)
+            }
+            ImageData::JPEG(data, ..) => {
+                let mut decoded = decode_jpeg(data)?;
+                let color_space = PDFConColorSpace::from(decoded.color_space);
+                if color_space == PDFConColorSpace::CMYK && super::has_adobe_marker(data) {
+                    decoded.pixels.iter_mut().for_each(|p| *p = 255 - *p);
+                }
+                super::to_dynamic(
+                    &decoded.pixels,
+                    decoded.width as u32,
+                    decoded.height as u32,
+                    &color_space,
+                )
+            }
+        };
+        let dynamic = dynamic.ok_or(PDFConError::BufferInnerError)?;
+
+        let mut gray = dynamic.to_luma8();
+        if gray.width() > INK_SAMPLE_WIDTH {
+            let height = (gray.height() as u64 * INK_SAMPLE_WIDTH as u64 / gray.width() as u64)
+                .max(1) as u32;
+            gray = image::imageops::thumbnail(&gray, INK_SAMPLE_WIDTH, height);
+        }
+
+        let (width, height) = gray.dimensions();
+        let margin_x = (width as f64 * INK_MARGIN) as u32;
+        let margin_y = (height as f64 * INK_MARGIN) as u32;
+        let inner = image::imageops::crop_imm(
+            &gray,
+            margin_x,
+            margin_y,
+            width - 2 * margin_x,
+            height - 2 * margin_y,
+        )
+        .to_image();
+
+        let total = inner.width() as u64 * inner.height() as u64;
+        if total == 0 {
+            return Ok(0.0);
+        }
+        let ink = inner.pixels().filter(|p| p.0[0] < INK_LEVEL).count() as u64;
+        Ok(ink as f64 * 100.0 / total as f64)
+    }
+
+    /// Clean up for scanned pages. It runs on the pixels before anything else
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+    pub struct ScanCleanup {
+        /// Trim flat borders that differ from the page, like the black edges of a scanner bed
+        pub auto_crop: bool,
+        /// Straighten pages scanned a few degrees off
+        pub deskew: bool,
+        /// Crop to what is on the page, keeping this many pixels of margin around it
+        pub crop_to_content: Option<u32>,
+    }
+
+    // Pages are measured for skew at this width
+    const DESKEW_SAMPLE_WIDTH: u32 = 1000;
+    // Largest skew looked for, in tenths of a degree
+    const DESKEW_MAX_ANGLE: i32 = 50;
+    // How much sharper than straight the best angle's profile has to be. Pages without
+    // lines of text score about the same at every angle
+    const DESKEW_MIN_GAIN: f64 = 1.05;
+    // Pixels within this of a border's colour are part of the border
+    const BORDER_TOLERANCE: u8 = 32;
+    // Share of a row or column that has to match for it to count as border
+    const BORDER_MIN_SHARE: f64 = 0.95;
+    // Rows and columns need this many ink pixels to count as content. Ignores dust
+    const CONTENT_MIN_PIXELS: u32 = 3;
+
+    impl ScanCleanup {
+        pub fn is_active(&self) -> bool {
+            self.auto_crop || self.deskew || self.crop_to_content.is_some()
+        }
+
+        /// Crop borders, deskew and crop to content, as enabled. None when nothing changed
+        pub fn apply(&self, image: &DynamicImage) -> Option<DynamicImage> {
+            let mut image = Cow::Borrowed(image);
+            if self.auto_crop
+                && let Some((x, y, width, height)) = border_box(&image.to_luma8())
+            {
+                debug!("Cropping borders to {}x{} at {},{}", width, height, x, y);
+                image = Cow::Owned(image.crop_imm(x, y, width, height));
+            }
+            if self.deskew {
+                let angle = skew_angle(&image.to_luma8());
+                if angle != 0.0 {
+                    debug!("Straightening a skew of {} degrees", angle);
+                    image = Cow::Owned(rotate_dynamic(&image, angle));
+                }
+            }
+            if let Some(margin) = self.crop_to_content
+                && let Some((x, y, width, height)) = content_box(&image.to_luma8(), margin)
+            {
+                debug!("Cropping to content at {}x{} at {},{}", width, height, x, y);
+                image = Cow::Owned(image.crop_imm(x, y, width, height));
+            }
+
+            match image {
+                Cow::Owned(image) => Some(image),
+                Cow::Borrowed(_) => None,
+            }
+        }
+    }
+
+    fn median(histogram: &[u64; 256]) -> u8 {
+        let half = histogram.iter().sum::<u64>().div_ceil(2);
+        let mut seen = 0;
+        for (value, &count) in histogram.iter().enumerate() {
+            seen += count;
+            if seen >= half {
+                return value as u8;
+            }
+        }
+        255
+    }
+
+    /// How many lines from an edge inward are one flat colour that isn't the page's
+    fn border_depth(mut line: impl FnMut(u32) -> Vec<u8>, lines: u32, background: u8) -> u32 {
+        let mut outer = line(0);
+        outer.sort_unstable();
+        let color = outer[outer.len() / 2];
+        if color.abs_diff(background) <= BORDER_TOLERANCE {
+            return 0;
+        }
+
+        // Stop well short of the middle so a dark page is never cropped away
+        let limit = lines / 4;
+        (0..limit)
+            .find(|&i| {
+                let pixels = line(i);
+                let matching = pixels
+                    .iter()
+                    .filter(|p| p.abs_diff(color) <= BORDER_TOLERANCE)
+                    .count();
+                (matching as f64) < pixels.len() as f64 * BORDER_MIN_SHARE
+            })
+            .unwrap_or(limit)
+    }
+
+    /// The part of a page inside its borders as x, y, width and height. None when it
+    /// has no borders
+    fn border_box(gray: &GrayImage) -> Option<(u32, u32, u32, u32)> {
+        let (width, height) = gray.dimensions();
+        if width == 0 || height == 0 {
+            return None;
+        }
+        let background = median(&histogram(gray));
+        let row = |y: u32| (0..width).map(|x| gray.get_pixel(x, y).0[0]).collect();
+        let column = |x: u32| (0..height).map(|y| gray.get_pixel(x, y).0[0]).collect();
+
+        let top = border_depth(row, height, background);
+        let bottom = border_depth(|i| row(height - 1 - i), height, background);
+        let left = border_depth(column, width, background);
+        let right = border_depth(|i| column(width - 1 - i), width, background);
+        if top + bottom + left + right == 0 {
+            return None;
+        }
+        Some((left, top, width - left - right, height - top - bottom))
+    }
+
+    /// Skew of the text lines on a page in degrees, found with a projection profile.
+    /// Rows of ink summed along the right angle give the sharpest peaks
+    fn skew_angle(gray: &GrayImage) -> f32 {
+        let sample = if gray.width() > DESKEW_SAMPLE_WIDTH {
+            let height = (gray.height() as u64 * DESKEW_SAMPLE_WIDTH as u64 / gray.width() as u64)
+                .max(1) as u32;
+            image::imageops::thumbnail(gray, DESKEW_SAMPLE_WIDTH, height)
+        } else {
+            gray.clone()
+        };
+        let (width, height) = sample.dimensions();
+        let ink: Vec<(f32, f32)> = sample
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p.0[0] < INK_LEVEL)
+            .map(|(x, y, _)| (x as f32, y as f32))
+            .collect();
+        if ink.is_empty() {
+            return 0.0;
+        }
+
+        let score = |tenths: i32| -> u64 {
+            let (sin, cos) = (tenths as f32 / 10.0).to_radians().sin_cos();
+            let mut bins = vec![0u64; (height + 2 * width + 1) as usize];
+            for (x, y) in &ink {
+                let bin = (y * cos - x * sin + width as f32) as usize;
+                if let Some(count) = bins.get_mut(bin) {
+                    *count += 1;
+                }
+            }
+            bins.iter().map(|count| count * count).sum()
+        };
+        let best = |angles: &mut dyn Iterator<Item = i32>| {
+            angles.max_by_key(|&tenths| (score(tenths), -tenths.abs()))
+        };
+
+        // Whole half degrees first, then tenths around the best of those
+        let coarse = best(&mut (-DESKEW_MAX_ANGLE..=DESKEW_MAX_ANGLE).step_by(5)).unwrap_or(0);
+        let fine = best(&mut ((coarse - 4)..=(coarse + 4))).unwrap_or(coarse);
+        if (score(fine) as f64) < score(0) as f64 * DESKEW_MIN_GAIN {
+            return 0.0;
+        }
+        fine as f32 / 10.0
+    }
+
+    /// Turn an image by this many degrees around its centre. Corners the turn uncovers
+    /// are filled with white
+    fn rotate<P: Pixel>(
+        image: &ImageBuffer<P, Vec<P::Subpixel>>,
+        degrees: f32,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let (width, height) = image.dimensions();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (cx, cy) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+        let mut white = *image.get_pixel(0, 0);
+        white.apply(|_| P::Subpixel::DEFAULT_MAX_VALUE);
+        ImageBuffer::from_fn(width, height, |x, y| {
+            let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+            image::imageops::interpolate_bilinear(
+                image,
+                dx * cos - dy * sin + cx,
+                dx * sin + dy * cos + cy,
+            )
+            .unwrap_or(white)
+        })
+    }
+
+    /// Rotate without changing whether the image is gray or colour. Alpha is dropped
+    fn rotate_dynamic(image: &DynamicImage, degrees: f32) -> DynamicImage {
+        match image {
+            DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => {
+                DynamicImage::ImageLuma8(rotate(&image.to_luma8(), degrees))
+            }
+            DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) => {
+                DynamicImage::ImageLuma16(rotate(&image.to_luma16(), degrees))
+            }
+            DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_)
+            | DynamicImage::ImageRgb32F(_)
+            | DynamicImage::ImageRgba32F(_) => {
+                DynamicImage::ImageRgb16(rotate(&image.to_rgb16(), degrees))
+            }
+            _ => DynamicImage::ImageRgb8(rotate(&image.to_rgb8(), degrees)),
         }
     }
-}
 
-impl From<image::ColorType> for PDFConColorSpace {
-    fn from(c: image::ColorType) -> Self {
-        match c {
-            image::ColorType::L8 | image::ColorType::La8 => Self::L8,
-            image::ColorType::Rgb8 | image::ColorType::Rgba8 => Self::RGB8,
-            _ => Self::RGB8,
+    /// The smallest box around the ink on a page, grown by `margin` pixels, as x, y,
+    /// width and height. None when the page has no ink or the box is the whole page
+    fn content_box(gray: &GrayImage, margin: u32) -> Option<(u32, u32, u32, u32)> {
+        let (width, height) = gray.dimensions();
+        let mut rows = vec![0u32; height as usize];
+        let mut columns = vec![0u32; width as usize];
+        for (x, y, p) in gray.enumerate_pixels() {
+            if p.0[0] < INK_LEVEL {
+                rows[y as usize] += 1;
+                columns[x as usize] += 1;
+            }
+        }
+
+        let span = |counts: &[u32], size: u32| -> Option<(u32, u32)> {
+            let first = counts.iter().position(|&c| c >= CONTENT_MIN_PIXELS)? as u32;
+            let last = counts.iter().rposition(|&c| c >= CONTENT_MIN_PIXELS)? as u32;
+            let start = first.saturating_sub(margin);
+            let end = (last + margin + 1).min(size);
+            Some((start, end - start))
+        };
+        let (x, crop_width) = span(&columns, width)?;
+        let (y, crop_height) = span(&rows, height)?;
+        if (crop_width, crop_height) == (width, height) {
+            return None;
         }
+        Some((x, y, crop_width, crop_height))
     }
-}
 
-pub fn compress_zlib(
-    v: Vec<u8>,
-    compression_method: flate2::Compression,
-) -> Result<Vec<u8>, PDFConError> {
-    let writer: BufWriter<Vec<u8>> = BufWriter::new(Vec::new());
-    let mut compressor = ZlibEncoder::new(writer, compression_method);
-    compressor.write_all(&v)?;
+    /// Run the colour analysis on an 8 bit image. None means the image should be kept as is
+    pub fn reduce_colors(image: &DynamicImage, analysis: &ColorAnalysis) -> Option<ReducedImage> {
+        let gray = match image.color() {
+            ColorType::L8 | ColorType::La8 => image.to_luma8(),
+            ColorType::Rgb8 | ColorType::Rgba8 => {
+                // Scanners rarely save black and white pages as exact gray, so bilevel
+                // checks RGB images with the default tolerance when none was given
+                let tolerance = analysis
+                    .gray_tolerance
+                    .or(analysis.bilevel.map(|_| DEFAULT_GRAY_TOLERANCE))?;
+                if !is_near_gray(&image.to_rgb8(), tolerance) {
+                    return None;
+                }
+                debug!("Image is near gray");
+                image.to_luma8()
+            }
+            _ => return None,
+        };
 
-    let compressed_bytes = compressor.finish()?;
+        if let Some(threshold) = analysis.bilevel {
+            let histogram = histogram(&gray);
+            if is_near_bilevel(&histogram) {
+                let threshold = match threshold {
+                    Threshold::Fixed(t) => t,
+                    Threshold::Otsu => otsu_threshold(&histogram),
+                };
+                debug!("Image is near bilevel. Thresholding at {}", threshold);
+                return Some(ReducedImage::Bilevel(pack_bilevel(&gray, threshold)));
+            }
+        }
 
-    let inner = compressed_bytes
-        .into_inner()
-        .map_err(|_| PDFConError::BufferInnerError)?;
-    Ok(inner)
-}
+        match image.color() {
+            // Already gray. Nothing gained
+            ColorType::L8 | ColorType::La8 => None,
+            _ if analysis.gray_tolerance.is_some() => Some(ReducedImage::Gray(gray)),
+            _ => None,
+        }
+    }
 
-pub mod optimize {
-    use super::{PDFConColorSpace, compress_zlib};
-    use crate::error::PDFConError;
-    use flate2::Compression;
-    use image::{self, ColorType};
-    use log::error;
-    use mozjpeg;
-    use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom};
+    pub fn process_png_optimized(
+        file: impl Read,
+        cleanup: &ScanCleanup,
+        resample: &Resample,
+        analysis: &ColorAnalysis,
+    ) -> Result<PageImage, PDFConError> {
+        let mut reader = BufReader::new(file);
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
 
-    pub enum ImageData {
-        PNG(Vec<u8>, u32, u32, PDFConColorSpace),
-        JPEG(Vec<u8>, u32, u32, PDFConColorSpace),
+        let png_reader =
+            image::ImageReader::with_format(Cursor::new(&contents), image::ImageFormat::Png);
+        let mut decoder = png_reader.decode()?;
+        if let Some(cleaned) = cleanup.apply(&decoder) {
+            decoder = cleaned;
+        }
+        let dpi = png_dpi(&contents);
+        let (page_width, page_height) = (decoder.width(), decoder.height());
+        if let Some(resized) = resample.resize(&decoder, dpi) {
+            decoder = resized;
+        }
+
+        Ok(PageImage::new(
+            png_data(&decoder, analysis)?,
+            page_width,
+            page_height,
+            dpi,
+        ))
     }
 
-    pub fn process_png_optimized(file: std::fs::File) -> Result<ImageData, PDFConError> {
-        let reader = BufReader::new(file);
+    /// Compress decoded png pixels, reducing their colours when asked to
+    fn png_data(
+        decoder: &DynamicImage,
+        analysis: &ColorAnalysis,
+    ) -> Result<ImageData, PDFConError> {
+        let width = decoder.width();
+        let height = decoder.height();
+        match reduce_colors(decoder, analysis) {
+            Some(ReducedImage::Gray(gray)) => {
+                let compressed = compress_zlib(gray.into_raw(), Compression::best())?;
+                return Ok(ImageData::PNG(
+                    compressed,
+                    width,
+                    height,
+                    PDFConColorSpace::L8,
+                ));
+            }
+            Some(ReducedImage::Bilevel(packed)) => {
+                let compressed = compress_zlib(packed, Compression::best())?;
+                return Ok(ImageData::PNG(
+                    compressed,
+                    width,
+                    height,
+                    PDFConColorSpace::L1,
+                ));
+            }
+            None => {}
+        }
 
-        let png_reader = image::ImageReader::with_format(reader, image::ImageFormat::Png);
-        let decoder = png_reader.decode()?;
         let colorspace = decoder.color();
 
         match colorspace {
@@ -275,7 +1553,161 @@ pub mod optimize {
         }
     }
 
-    pub fn optimize_jpeg_mem(content: &[u8]) -> Result<Vec<u8>, PDFConError> {
+    /// Deflate 8 bit samples with the png row filters oxipng picks. The result is what
+    /// FlateDecode reads back with predictor 15. Returns None for unsupported layouts
+    pub fn deflate_predicted(
+        pixels: &[u8],
+        width: u32,
+        height: u32,
+        colors: u8,
+    ) -> Result<Option<Vec<u8>>, PDFConError> {
+        let color_type = match colors {
+            1 => image::ExtendedColorType::L8,
+            3 => image::ExtendedColorType::Rgb8,
+            // Predictors don't care what the channels mean so CMYK goes in as RGBA
+            4 => image::ExtendedColorType::Rgba8,
+            _ => return Ok(None),
+        };
+
+        let mut encoded = Vec::new();
+        image::codecs::png::PngEncoder::new(&mut encoded)
+            .write_image(pixels, width, height, color_type)?;
+
+        // The samples have to come back exactly as they went in
+        let options = oxipng::Options {
+            strip: oxipng::StripChunks::All,
+            bit_depth_reduction: false,
+            color_type_reduction: false,
+            palette_reduction: false,
+            grayscale_reduction: false,
+            ..Default::default()
+        };
+        let optimized = oxipng::optimize_from_memory(&encoded, &options)?;
+
+        // Walk the chunks after the signature and join the image data
+        let mut idat = Vec::new();
+        let mut pos = 8;
+        while pos + 8 <= optimized.len() {
+            let len = u32::from_be_bytes(optimized[pos..pos + 4].try_into().unwrap()) as usize;
+            let kind = &optimized[pos + 4..pos + 8];
+            let Some(data) = optimized.get(pos + 8..pos + 8 + len) else {
+                return Ok(None);
+            };
+            match kind {
+                // Bit depth, colour type and interlacing must not have changed
+                b"IHDR" if data[8..10] != encoded[24..26] || data[12] != 0 => return Ok(None),
+                b"IDAT" => idat.extend_from_slice(data),
+                _ => {}
+            }
+            pos += 12 + len;
+        }
+
+        Ok(Some(idat))
+    }
+
+    /// Chroma subsampling used when re-encoding colour JPEGs
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ChromaSubsampling {
+        S444,
+        S422,
+        S420,
+    }
+
+    impl ChromaSubsampling {
+        // Chroma "pixel" sizes per luma pixel for the Cb and Cr channels
+        fn pixel_sizes(&self) -> ((u8, u8), (u8, u8)) {
+            match self {
+                Self::S444 => ((1, 1), (1, 1)),
+                Self::S422 => ((2, 1), (2, 1)),
+                Self::S420 => ((2, 2), (2, 2)),
+            }
+        }
+    }
+
+    /// Trellis quantization mode used when re-encoding JPEGs
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Trellis {
+        /// No trellis quantization. Uses libjpeg-turbo compatible defaults
+        Off,
+        /// mozjpeg's default trellis quantization
+        On,
+        /// Trellis quantization that also considers progressive scans
+        Scans,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct JpegOptions {
+        pub quality: u8,
+        pub progressive: bool,
+        pub subsampling: ChromaSubsampling,
+        pub trellis: Trellis,
+        /// Only rebuild the Huffman tables. Pixel data is left untouched
+        pub lossless: bool,
+    }
+
+    impl Default for JpegOptions {
+        fn default() -> Self {
+            Self {
+                quality: 92,
+                progressive: true,
+                subsampling: ChromaSubsampling::S420,
+                trellis: Trellis::On,
+                lossless: false,
+            }
+        }
+    }
+
+    pub fn jpeg_header(content: &[u8]) -> Result<(u32, u32, mozjpeg::ColorSpace), PDFConError> {
+        let decompress = match mozjpeg::decompress::Decompress::builder().from_mem(content) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Decompress err: {}", e);
+                return Err(PDFConError::MozDecompressBufferError);
+            }
+        };
+
+        Ok((
+            decompress.width() as u32,
+            decompress.height() as u32,
+            decompress.color_space(),
+        ))
+    }
+
+    /// Pixels decoded from a jpeg along with what's needed to encode them again
+    pub struct DecodedJpeg {
+        pub pixels: Vec<u8>,
+        pub width: usize,
+        pub height: usize,
+        pub color_space: mozjpeg::ColorSpace,
+        pub pixel_density: mozjpeg::PixelDensity,
+    }
+
+    impl DecodedJpeg {
+        /// Horizontal and vertical resolution in dots per inch, if the file records one
+        pub fn dpi(&self) -> Option<(f32, f32)> {
+            density_dpi(&self.pixel_density)
+        }
+    }
+
+    fn density_dpi(density: &mozjpeg::PixelDensity) -> Option<(f32, f32)> {
+        match density.unit {
+            mozjpeg::PixelDensityUnit::Inches => Some((density.x as f32, density.y as f32)),
+            mozjpeg::PixelDensityUnit::Centimeters => {
+                Some((density.x as f32 * 2.54, density.y as f32 * 2.54))
+            }
+            mozjpeg::PixelDensityUnit::PixelAspectRatio => None,
+        }
+    }
+
+    /// Resolution recorded in a jpeg's JFIF header in dots per inch
+    fn jpeg_dpi(content: &[u8]) -> Option<(f32, f32)> {
+        let mut decompress = mozjpeg::decompress::Decompress::builder()
+            .from_mem(content)
+            .ok()?;
+        density_dpi(&decompress.pixel_density()?)
+    }
+
+    pub fn decode_jpeg(content: &[u8]) -> Result<DecodedJpeg, PDFConError> {
         let reader = BufReader::new(content);
         let mut decompress = match mozjpeg::decompress::Decompress::builder()
             .with_markers(mozjpeg::ALL_MARKERS)
@@ -283,7 +1715,7 @@ pub mod optimize {
         {
             Ok(d) => d,
             Err(e) => {
-                error!("Decompress err: {}", e.to_string());
+                error!("Decompress err: {}", e);
                 return Err(PDFConError::MozDecompressBufferError);
             }
         };
@@ -291,14 +1723,12 @@ pub mod optimize {
         let height = decompress.height();
         let width = decompress.width();
 
-        let pixel_density = decompress
-            .pixel_density()
-            .unwrap_or(mozjpeg::PixelDensity::default());
+        let pixel_density = decompress.pixel_density().unwrap_or_default();
 
         decompress.dct_method(mozjpeg::DctMethod::IntegerSlow);
         decompress.do_block_smoothing(true);
 
-        let (pixels, output_color_space) = match decompress.color_space() {
+        let (pixels, color_space) = match decompress.color_space() {
             mozjpeg::ColorSpace::JCS_GRAYSCALE => {
                 let mut gray_buff = decompress.grayscale()?;
                 let pixels = gray_buff.read_scanlines()?;
@@ -311,7 +1741,8 @@ pub mod optimize {
                 rgb_buff.finish()?;
                 (pixels, mozjpeg::ColorSpace::JCS_RGB)
             }
-            mozjpeg::ColorSpace::JCS_CMYK => {
+            // libjpeg turns YCCK back into CMYK itself
+            mozjpeg::ColorSpace::JCS_CMYK | mozjpeg::ColorSpace::JCS_YCCK => {
                 let mut cmyk_buffer = decompress.to_colorspace(mozjpeg::ColorSpace::JCS_CMYK)?;
                 let pixels = cmyk_buffer.read_scanlines()?;
                 cmyk_buffer.finish()?;
@@ -320,18 +1751,49 @@ pub mod optimize {
             _ => return Err(PDFConError::MozDecompressBufferError),
         };
 
+        Ok(DecodedJpeg {
+            pixels,
+            width,
+            height,
+            color_space,
+            pixel_density,
+        })
+    }
+
+    pub fn encode_jpeg(
+        decoded: &DecodedJpeg,
+        options: &JpegOptions,
+    ) -> Result<Vec<u8>, PDFConError> {
         let writer: BufWriter<Vec<u8>> = BufWriter::new(Vec::new());
-        let mut compress = mozjpeg::compress::Compress::new(output_color_space);
-        compress.set_pixel_density(pixel_density);
-        compress.set_size(width, height);
-        compress.set_optimize_scans(true);
+        let mut compress = mozjpeg::compress::Compress::new(decoded.color_space);
+        // Resets every parameter so it has to happen before anything else is set
+        if options.trellis == Trellis::Off {
+            compress.set_fastest_defaults();
+        }
+        compress.set_pixel_density(mozjpeg::PixelDensity {
+            unit: decoded.pixel_density.unit,
+            x: decoded.pixel_density.x,
+            y: decoded.pixel_density.y,
+        });
+        compress.set_size(decoded.width, decoded.height);
+        compress.set_quality(options.quality as f32);
+        if decoded.color_space == mozjpeg::ColorSpace::JCS_RGB {
+            let (cb, cr) = options.subsampling.pixel_sizes();
+            compress.set_chroma_sampling_pixel_sizes(cb, cr);
+        }
         compress.set_optimize_coding(true);
-        compress.set_progressive_mode();
-        compress.set_quality(92.0);
+        // Scan optimization has to be set before progressive mode is turned on
+        compress.set_optimize_scans(options.progressive);
+        if options.progressive {
+            compress.set_progressive_mode();
+        }
+        if options.trellis == Trellis::Scans {
+            compress.set_use_scans_in_trellis(true);
+        }
 
         let mut compress_start = compress.start_compress(writer)?;
 
-        compress_start.write_scanlines(&pixels[..])?;
+        compress_start.write_scanlines(&decoded.pixels[..])?;
 
         let finished_writer = compress_start.finish()?;
         let content = finished_writer
@@ -340,77 +1802,191 @@ pub mod optimize {
         Ok(content)
     }
 
-    pub fn optimize_jpeg(file: std::fs::File) -> Result<ImageData, PDFConError> {
-        let result = std::panic::catch_unwind(|| -> Result<ImageData, PDFConError> {
-            let reader = BufReader::new(&file);
-            let mut decompress = match mozjpeg::decompress::Decompress::builder()
-                .with_markers(mozjpeg::ALL_MARKERS)
-                .from_reader(reader)
-            {
-                Ok(d) => d,
-                Err(e) => {
-                    error!("Decompress err: {}", e.to_string());
-                    return Err(PDFConError::MozDecompressBufferError);
+    /// Encode an 8 or 16 bit gray or RGB image. 16 bit samples are cut down to 8
+    pub fn encode_dynamic_jpeg(
+        image: &DynamicImage,
+        options: &JpegOptions,
+    ) -> Result<Vec<u8>, PDFConError> {
+        let (pixels, color_space) = match image {
+            DynamicImage::ImageLuma8(_) | DynamicImage::ImageLuma16(_) => (
+                image.to_luma8().into_raw(),
+                mozjpeg::ColorSpace::JCS_GRAYSCALE,
+            ),
+            _ => (image.to_rgb8().into_raw(), mozjpeg::ColorSpace::JCS_RGB),
+        };
+        let decoded = DecodedJpeg {
+            pixels,
+            width: image.width() as usize,
+            height: image.height() as usize,
+            color_space,
+            pixel_density: mozjpeg::PixelDensity::default(),
+        };
+
+        match std::panic::catch_unwind(|| encode_jpeg(&decoded, options)) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("MozJpeg failed: {:?}", e);
+                Err(PDFConError::MozUnwindError)
+            }
+        }
+    }
+
+    extern "C-unwind" fn unwind_error_exit(_cinfo: &mut ffi::jpeg_common_struct) {
+        std::panic::resume_unwind(Box::new("libjpeg fatal error"));
+    }
+
+    extern "C-unwind" fn silence_message(_cinfo: &mut ffi::jpeg_common_struct, _level: c_int) {}
+
+    // Copies the DCT coefficients into a new file. Only the entropy coding is rebuilt so the
+    // output decodes to exactly the same pixels as the input. The safe mozjpeg API doesn't
+    // expose coefficient access so this goes through mozjpeg_sys directly.
+    fn transcode_jpeg(content: &[u8], options: &JpegOptions) -> Result<Vec<u8>, PDFConError> {
+        unsafe {
+            let mut src_err: ffi::jpeg_error_mgr = mem::zeroed();
+            let mut dst_err: ffi::jpeg_error_mgr = mem::zeroed();
+            let mut src: ffi::jpeg_decompress_struct = mem::zeroed();
+            let mut dst: ffi::jpeg_compress_struct = mem::zeroed();
+
+            for err in [&mut src_err, &mut dst_err] {
+                ffi::jpeg_std_error(err);
+                err.error_exit = Some(unwind_error_exit);
+                err.emit_message = Some(silence_message);
+            }
+            src.common.err = &mut src_err;
+            dst.common.err = &mut dst_err;
+
+            ffi::jpeg_create_decompress(&mut src);
+            ffi::jpeg_create_compress(&mut dst);
+
+            let mut out_buffer: *mut u8 = std::ptr::null_mut();
+            let mut out_size: c_ulong = 0;
+
+            // libjpeg reports errors by unwinding. Catch it here so both structs always get destroyed
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                ffi::jpeg_mem_src(&mut src, content.as_ptr(), content.len() as c_ulong);
+                ffi::jpeg_read_header(&mut src, 1);
+                let coefficients = ffi::jpeg_read_coefficients(&mut src);
+
+                ffi::jpeg_copy_critical_parameters(&src, &mut dst);
+                dst.optimize_coding = 1;
+                if !options.progressive {
+                    // mozjpeg's defaults are progressive. Remove the scan script for a baseline file
+                    ffi::jpeg_c_set_bool_param(
+                        &mut dst,
+                        ffi::J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS,
+                        0,
+                    );
+                    dst.num_scans = 0;
+                    dst.scan_info = std::ptr::null();
                 }
+
+                ffi::jpeg_mem_dest(&mut dst, &mut out_buffer, &mut out_size);
+                ffi::jpeg_write_coefficients(&mut dst, coefficients);
+                ffi::jpeg_finish_compress(&mut dst);
+                ffi::jpeg_finish_decompress(&mut src);
+            }));
+
+            ffi::jpeg_destroy_compress(&mut dst);
+            ffi::jpeg_destroy_decompress(&mut src);
+
+            let transcoded = if result.is_ok() && !out_buffer.is_null() {
+                Some(std::slice::from_raw_parts(out_buffer, out_size as usize).to_vec())
+            } else {
+                None
             };
+            if !out_buffer.is_null() {
+                libc::free(out_buffer as *mut libc::c_void);
+            }
 
-            let height = decompress.height();
-            let width = decompress.width();
+            transcoded.ok_or(PDFConError::MozUnwindError)
+        }
+    }
 
-            let pixel_density = decompress
-                .pixel_density()
-                .unwrap_or(mozjpeg::PixelDensity::default());
+    /// Re-encode a jpeg with the given options. The original bytes are returned if the
+    /// re-encoded file isn't any smaller.
+    pub fn optimize_jpeg_mem(
+        content: &[u8],
+        options: &JpegOptions,
+    ) -> Result<Vec<u8>, PDFConError> {
+        let result = std::panic::catch_unwind(|| {
+            if options.lossless {
+                transcode_jpeg(content, options)
+            } else {
+                encode_jpeg(&decode_jpeg(content)?, options)
+            }
+        });
 
-            decompress.dct_method(mozjpeg::DctMethod::IntegerSlow);
-            decompress.do_block_smoothing(true);
+        let optimized = match result {
+            Ok(r) => r?,
+            Err(e) => {
+                error!("MozJpeg failed: {:?}", e);
+                return Err(PDFConError::MozUnwindError);
+            }
+        };
 
-            let (pixels, output_color_space) = match decompress.color_space() {
-                mozjpeg::ColorSpace::JCS_GRAYSCALE => {
-                    let mut gray_buff = decompress.grayscale()?;
-                    let pixels = gray_buff.read_scanlines()?;
-                    gray_buff.finish()?;
-                    (pixels, mozjpeg::ColorSpace::JCS_GRAYSCALE)
-                }
-                mozjpeg::ColorSpace::JCS_RGB | mozjpeg::ColorSpace::JCS_YCbCr => {
-                    let mut rgb_buff = decompress.rgb()?;
-                    let pixels = rgb_buff.read_scanlines()?;
-                    rgb_buff.finish()?;
-                    (pixels, mozjpeg::ColorSpace::JCS_RGB)
-                }
-                mozjpeg::ColorSpace::JCS_CMYK => {
-                    let mut cmyk_buffer =
-                        decompress.to_colorspace(mozjpeg::ColorSpace::JCS_CMYK)?;
-                    let pixels = cmyk_buffer.read_scanlines()?;
-                    cmyk_buffer.finish()?;
-                    (pixels, mozjpeg::ColorSpace::JCS_CMYK)
+        if optimized.len() < content.len() {
+            Ok(optimized)
+        } else {
+            debug!("Re-encoded jpeg is not smaller. Keeping the original");
+            Ok(content.to_vec())
+        }
+    }
+
+    pub fn optimize_jpeg(file: impl Read, options: &JpegOptions) -> Result<PageImage, PDFConError> {
+        let mut reader = BufReader::new(file);
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+
+        let ((width, height, color_space), dpi) =
+            match std::panic::catch_unwind(|| (jpeg_header(&contents), jpeg_dpi(&contents))) {
+                Ok((r, dpi)) => (r?, dpi),
+                Err(e) => {
+                    error!("MozJpeg failed: {:?}", e);
+                    return Err(PDFConError::MozUnwindError);
                 }
-                _ => return Err(PDFConError::MozDecompressBufferError),
             };
 
-            let writer: BufWriter<Vec<u8>> = BufWriter::new(Vec::new());
-            let mut compress = mozjpeg::compress::Compress::new(output_color_space);
-            compress.set_pixel_density(pixel_density);
-            compress.set_size(width, height);
-            compress.set_optimize_scans(true);
-            compress.set_optimize_coding(true);
-            compress.set_progressive_mode();
-            compress.set_quality(92.0);
-
-            let mut compress_start = compress.start_compress(writer)?;
+        let content = optimize_jpeg_mem(&contents, options)?;
 
-            compress_start.write_scanlines(&pixels[..])?;
+        Ok(PageImage::new(
+            ImageData::JPEG(content, width, height, PDFConColorSpace::from(color_space)),
+            width,
+            height,
+            dpi,
+        ))
+    }
 
-            let finished_writer = compress_start.finish()?;
-            let content = finished_writer
-                .into_inner()
-                .map_err(|_| PDFConError::BufferInnerError)?;
+    /// Decode, downsample and re-encode a jpeg. Returns None when the image already fits
+    pub fn resample_jpeg_mem(
+        content: &[u8],
+        options: &JpegOptions,
+        resample: &Resample,
+    ) -> Result<Option<ImageData>, PDFConError> {
+        let result = std::panic::catch_unwind(|| {
+            let decoded = decode_jpeg(content)?;
+            let color_space = PDFConColorSpace::from(decoded.color_space);
+            let Some((pixels, width, height)) = resample.resize_pixels(
+                &decoded.pixels,
+                decoded.width as u32,
+                decoded.height as u32,
+                &color_space,
+                decoded.dpi(),
+            ) else {
+                return Ok(None);
+            };
 
-            Ok(ImageData::JPEG(
-                content,
-                width as u32,
-                height as u32,
-                PDFConColorSpace::from(output_color_space),
-            ))
+            let resized = DecodedJpeg {
+                pixels,
+                width: width as usize,
+                height: height as usize,
+                ..decoded
+            };
+            Ok(Some(ImageData::JPEG(
+                encode_jpeg(&resized, options)?,
+                width,
+                height,
+                color_space,
+            )))
         });
 
         match result {
@@ -422,28 +1998,360 @@ pub mod optimize {
         }
     }
 
-    pub fn jpeg(mut file: std::fs::File) -> Result<ImageData, PDFConError> {
-        let reader = BufReader::new(&file);
+    fn to_dynamic(decoded: &DecodedJpeg) -> Option<DynamicImage> {
+        let width = decoded.width as u32;
+        let height = decoded.height as u32;
+        match decoded.color_space {
+            mozjpeg::ColorSpace::JCS_GRAYSCALE => Some(DynamicImage::ImageLuma8(
+                GrayImage::from_raw(width, height, decoded.pixels.clone())?,
+            )),
+            mozjpeg::ColorSpace::JCS_RGB => Some(DynamicImage::ImageRgb8(RgbImage::from_raw(
+                width,
+                height,
+                decoded.pixels.clone(),
+            )?)),
+            _ => None,
+        }
+    }
+
+    /// Clean up, downsample and reduce the colours of jpeg data. Returns None when none of
+    /// them change the image
+    pub fn transform_jpeg_mem(
+        contents: &[u8],
+        options: &JpegOptions,
+        cleanup: &ScanCleanup,
+        resample: &Resample,
+        analysis: &ColorAnalysis,
+    ) -> Result<Option<PageImage>, PDFConError> {
+        let result = std::panic::catch_unwind(|| -> Result<Option<PageImage>, PDFConError> {
+            let mut decoded = decode_jpeg(contents)?;
+            let color_space = PDFConColorSpace::from(decoded.color_space);
 
-        let image = image::ImageReader::with_format(reader, image::ImageFormat::Jpeg);
-        let decoder = image.decode()?;
-        let width = decoder.width();
-        let height = decoder.height();
-        let color = decoder.color();
+            let mut changed = false;
+            // CMYK jpegs have no DynamicImage to clean up and are left as they are
+            if cleanup.is_active()
+                && let Some(cleaned) = to_dynamic(&decoded).and_then(|i| cleanup.apply(&i))
+            {
+                decoded.width = cleaned.width() as usize;
+                decoded.height = cleaned.height() as usize;
+                decoded.pixels = cleaned.into_bytes();
+                changed = true;
+            }
+            let dpi = decoded.dpi();
+            let (page_width, page_height) = (decoded.width as u32, decoded.height as u32);
+            if let Some((pixels, width, height)) = resample.resize_pixels(
+                &decoded.pixels,
+                decoded.width as u32,
+                decoded.height as u32,
+                &color_space,
+                dpi,
+            ) {
+                decoded.pixels = pixels;
+                decoded.width = width as usize;
+                decoded.height = height as usize;
+                changed = true;
+            }
+
+            let width = decoded.width as u32;
+            let height = decoded.height as u32;
+            let reduced = if analysis.is_active() {
+                to_dynamic(&decoded).and_then(|image| reduce_colors(&image, analysis))
+            } else {
+                None
+            };
+
+            let image = match reduced {
+                Some(ReducedImage::Bilevel(packed)) => {
+                    let compressed = compress_zlib(packed, Compression::best())?;
+                    Some(ImageData::PNG(
+                        compressed,
+                        width,
+                        height,
+                        PDFConColorSpace::L1,
+                    ))
+                }
+                Some(ReducedImage::Gray(gray)) => {
+                    decoded.pixels = gray.into_raw();
+                    decoded.color_space = mozjpeg::ColorSpace::JCS_GRAYSCALE;
+                    let encoded = encode_jpeg(&decoded, options)?;
+                    if changed || encoded.len() < contents.len() {
+                        Some(ImageData::JPEG(
+                            encoded,
+                            width,
+                            height,
+                            PDFConColorSpace::L8,
+                        ))
+                    } else {
+                        None
+                    }
+                }
+                None if changed => Some(ImageData::JPEG(
+                    encode_jpeg(&decoded, options)?,
+                    width,
+                    height,
+                    color_space,
+                )),
+                None => None,
+            };
+            Ok(image.map(|image| PageImage::new(image, page_width, page_height, dpi)))
+        });
 
-        drop(decoder);
+        match result {
+            Ok(r) => r,
+            Err(e) => {
+                error!("MozJpeg failed: {:?}", e);
+                Err(PDFConError::MozUnwindError)
+            }
+        }
+    }
 
-        file.seek(SeekFrom::Start(0))?;
+    /// Clean up, downsample and reduce the colours of a jpeg. Falls back to the plain or
+    /// optimized jpeg when none of them change the image
+    pub fn transform_jpeg(
+        mut file: impl Read + Seek,
+        optimize: bool,
+        options: &JpegOptions,
+        cleanup: &ScanCleanup,
+        resample: &Resample,
+        analysis: &ColorAnalysis,
+    ) -> Result<PageImage, PDFConError> {
+        let mut reader = BufReader::new(&mut file);
+        let mut contents = Vec::new();
+        reader.read_to_end(&mut contents)?;
+
+        match transform_jpeg_mem(&contents, options, cleanup, resample, analysis)? {
+            Some(page_image) => Ok(page_image),
+            None => {
+                file.seek(SeekFrom::Start(0))?;
+                if optimize {
+                    optimize_jpeg(file, options)
+                } else {
+                    jpeg(file)
+                }
+            }
+        }
+    }
 
-        let mut reader = BufReader::new(&file);
+    pub fn jpeg(file: impl Read) -> Result<PageImage, PDFConError> {
+        let mut reader = BufReader::new(file);
         let mut contents = Vec::new();
         reader.read_to_end(&mut contents)?;
 
-        Ok(ImageData::JPEG(
-            contents,
+        // The header has the jpeg's own colour space. Decoders turn CMYK into RGB
+        let ((width, height, color_space), dpi) =
+            match std::panic::catch_unwind(|| (jpeg_header(&contents), jpeg_dpi(&contents))) {
+                Ok((r, dpi)) => (r?, dpi),
+                Err(e) => {
+                    error!("MozJpeg failed: {:?}", e);
+                    return Err(PDFConError::MozUnwindError);
+                }
+            };
+
+        Ok(PageImage::new(
+            ImageData::JPEG(contents, width, height, PDFConColorSpace::from(color_space)),
             width,
             height,
-            PDFConColorSpace::from(color),
+            dpi,
         ))
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn otsu_threshold_splits_two_peaks() {
+            let mut histogram = [0u64; 256];
+            histogram[20] = 500;
+            histogram[230] = 700;
+            let threshold = otsu_threshold(&histogram);
+            assert!(threshold > 20 && threshold <= 230);
+        }
+
+        #[test]
+        fn otsu_threshold_of_one_value_is_the_default() {
+            let mut histogram = [0u64; 256];
+            histogram[100] = 10;
+            assert_eq!(otsu_threshold(&histogram), 128);
+        }
+
+        #[test]
+        fn pack_bilevel_pads_rows_to_whole_bytes() {
+            // 10 pixels wide needs 2 bytes a row. Light pixels are set bits
+            let image = GrayImage::from_fn(10, 2, |x, y| {
+                image::Luma([if (x + y) % 2 == 0 { 255 } else { 0 }])
+            });
+            assert_eq!(
+                pack_bilevel(&image, 128),
+                vec![0b1010_1010, 0b1000_0000, 0b0101_0101, 0b0100_0000]
+            );
+        }
+
+        #[test]
+        fn pack_bilevel_threshold_is_inclusive() {
+            let image = GrayImage::from_raw(3, 1, vec![99, 100, 101]).unwrap();
+            assert_eq!(pack_bilevel(&image, 100), vec![0b0110_0000]);
+        }
+
+        #[test]
+        fn bilevel_alone_thresholds_near_gray_rgb() {
+            let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, _| {
+                if x < 8 {
+                    image::Rgb([2, 0, 4])
+                } else {
+                    image::Rgb([250, 255, 252])
+                }
+            }));
+            let analysis = ColorAnalysis {
+                gray_tolerance: None,
+                bilevel: Some(Threshold::Otsu),
+            };
+            assert!(matches!(
+                reduce_colors(&image, &analysis),
+                Some(ReducedImage::Bilevel(_))
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    #[test]
+    fn normalize_samples_keeps_packed_bilevel() {
+        let (data, color) =
+            normalize_samples(vec![0b1010_0000], 3, 1, b"DeviceGray", 1, None).unwrap();
+        assert_eq!((data, color), (vec![0b1010_0000], PDFConColorSpace::L1));
+
+        let (data, color) =
+            normalize_samples(vec![0b1010_0000], 3, 1, b"DeviceGray", 1, Some(&[1.0, 0.0]))
+                .unwrap();
+        assert_eq!((data, color), (vec![0b0101_1111], PDFConColorSpace::L1));
+    }
+
+    #[test]
+    fn normalize_samples_scales_sub_byte_depths_to_8_bits() {
+        let (data, color) =
+            normalize_samples(vec![0b00_01_10_11], 4, 1, b"DeviceGray", 2, None).unwrap();
+        assert_eq!((data, color), (vec![0, 85, 170, 255], PDFConColorSpace::L8));
+
+        // Each row of three 4 bit samples is padded to two bytes
+        let (data, color) =
+            normalize_samples(vec![0x0F, 0x80, 0xF0, 0x00], 3, 2, b"DeviceGray", 4, None).unwrap();
+        assert_eq!(
+            (data, color),
+            (vec![0, 255, 136, 255, 0, 0], PDFConColorSpace::L8)
+        );
+
+        // 1 bit RGB has no packed layout so it is scaled as well
+        let (data, color) =
+            normalize_samples(vec![0b1010_0000], 2, 1, b"DeviceRGB", 1, None).unwrap();
+        assert_eq!(
+            (data, color),
+            (vec![255, 0, 255, 0, 0, 0], PDFConColorSpace::RGB8)
+        );
+    }
+
+    #[test]
+    fn normalize_samples_keeps_16_bits() {
+        let data = vec![0x12, 0x34, 0xFF, 0xFF];
+        let (out, color) = normalize_samples(data.clone(), 2, 1, b"DeviceGray", 16, None).unwrap();
+        assert_eq!((out, color), (data, PDFConColorSpace::L16));
+
+        let (out, _) = normalize_samples(
+            vec![0x00, 0x00, 0xFF, 0xFF],
+            2,
+            1,
+            b"DeviceGray",
+            16,
+            Some(&[1.0, 0.0]),
+        )
+        .unwrap();
+        assert_eq!(out, vec![0xFF, 0xFF, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn normalize_samples_applies_decode_per_component() {
+        let decode = [0.0, 1.0, 1.0, 0.0, 0.0, 1.0];
+        let (data, color) =
+            normalize_samples(vec![10, 20, 30], 1, 1, b"DeviceRGB", 8, Some(&decode)).unwrap();
+        assert_eq!((data, color), (vec![10, 235, 30], PDFConColorSpace::RGB8));
+
+        // The default range changes nothing
+        let (data, _) =
+            normalize_samples(vec![10, 20], 2, 1, b"DeviceGray", 8, Some(&[0.0, 1.0])).unwrap();
+        assert_eq!(data, vec![10, 20]);
+    }
+
+    #[test]
+    fn normalize_samples_pads_short_streams_and_rejects_unsupported_images() {
+        let (data, _) = normalize_samples(vec![0xF0], 2, 2, b"DeviceGray", 4, None).unwrap();
+        assert_eq!(data, vec![255, 0, 0, 0]);
+
+        assert!(normalize_samples(vec![0], 1, 1, b"DeviceGray", 3, None).is_err());
+        assert!(normalize_samples(vec![0], 1, 1, b"Indexed", 8, None).is_err());
+    }
+
+    #[test]
+    fn image_hash_ignores_key_order_and_length() {
+        let a = dictionary! {
+            "Width" => 2,
+            "Height" => 1,
+            "ColorSpace" => "DeviceGray",
+            "BitsPerComponent" => 8,
+        };
+        let b = dictionary! {
+            "Length" => 2,
+            "BitsPerComponent" => 8,
+            "ColorSpace" => "DeviceGray",
+            "Height" => 1,
+            "Width" => 2,
+        };
+        assert_eq!(image_hash(&a, &[0, 255]), image_hash(&b, &[0, 255]));
+        assert_ne!(image_hash(&a, &[0, 255]), image_hash(&a, &[255, 0]));
+
+        let mut inverted = a.clone();
+        inverted.set("Decode", vec![1.into(), 0.into()]);
+        assert_ne!(image_hash(&a, &[0, 255]), image_hash(&inverted, &[0, 255]));
+    }
+
+    #[test]
+    fn unpredict_without_parms_keeps_the_data() {
+        assert_eq!(unpredict(vec![1, 2, 3], None).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn unpredict_tiff_adds_the_sample_to_the_left() {
+        let parms = dictionary! { "Predictor" => 2, "Columns" => 3 };
+        assert_eq!(
+            unpredict(vec![1, 1, 1, 5, 0, 0], Some(&parms)).unwrap(),
+            vec![1, 2, 3, 5, 5, 5]
+        );
+    }
+
+    #[test]
+    fn unpredict_png_undoes_every_row_filter() {
+        let parms = dictionary! { "Predictor" => 15, "Columns" => 2 };
+        let content = vec![
+            0, 10, 20, // None
+            2, 1, 1, // Up
+            1, 3, 4, // Sub
+            3, 4, 4, // Average of left and up
+            4, 0, 0, // Paeth
+        ];
+        assert_eq!(
+            unpredict(content, Some(&parms)).unwrap(),
+            vec![10, 20, 11, 21, 3, 7, 5, 10, 5, 10]
+        );
+    }
+
+    #[test]
+    fn unpredict_rejects_unknown_filters() {
+        let parms = dictionary! { "Predictor" => 12, "Columns" => 1 };
+        assert!(unpredict(vec![5, 0], Some(&parms)).is_err());
+        let parms = dictionary! { "Predictor" => 7 };
+        assert!(unpredict(vec![0], Some(&parms)).is_err());
+    }
 }
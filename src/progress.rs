@@ -10,19 +10,19 @@ pub fn bar(prefix: &str, total_progress: u64, tick_speed: u64) -> ProgressBar {
                 .tick_strings(&["∙∙∙", "●∙∙", "∙●∙", "∙∙●", "∙∙●"])
                 .template(format!(
                 " {{spinner:.yellow.bold}} {{prefix:.yellow.bold}}{} {}{{wide_bar:.2.bold/:.65.bold}}{{msg}} {{percent:.green.bold}}{} {}{{pos:.8}}{}{{len:.8}}{} ",
-                bold().apply_to(":").to_string(),
-                bc_lgt_green().apply_to("").to_string(),
-                bc_lgt_green().apply_to("%").to_string(),
-                c_gray().apply_to("(").to_string(),
-                c_gray().apply_to("/").to_string(),
-                c_gray().apply_to(")").to_string()
+                bold().apply_to(":"),
+                bc_lgt_green().apply_to(""),
+                bc_lgt_green().apply_to("%"),
+                c_gray().apply_to("("),
+                c_gray().apply_to("/"),
+                c_gray().apply_to(")")
             ).as_str())
         .unwrap_or(ProgressStyle::default_bar()));
     pb.set_prefix(prefix.to_string());
     pb.set_message(bc_drk_green().apply_to("").to_string());
     pb.enable_steady_tick(std::time::Duration::from_millis(tick_speed));
 
-    return pb;
+    pb
 }
 
 pub fn spinner(prefix: &str, tick_speed: u64) -> ProgressBar {
@@ -32,18 +32,18 @@ pub fn spinner(prefix: &str, tick_speed: u64) -> ProgressBar {
                 .tick_strings(&["∙∙∙", "●∙∙", "∙●∙", "∙∙●", "∙∙●"])
                 .template(format!(
                 " {{spinner:.yellow.bold}} {{prefix:.yellow.bold}} {{wide_msg}} {}{{elapsed:.8}}{} ",
-                c_gray().apply_to("(").to_string(),
-                c_gray().apply_to(")").to_string()
+                c_gray().apply_to("("),
+                c_gray().apply_to(")")
             ).as_str())
         .unwrap_or(ProgressStyle::default_spinner()));
     spnr.set_prefix(prefix.to_string());
     spnr.enable_steady_tick(std::time::Duration::from_millis(tick_speed));
 
-    return spnr;
+    spnr
 }
 
 pub fn update_end_cap(bar: &ProgressBar, pos: u64, total: u64) {
-    if pos >= total - 2 && pos < total {
+    if pos >= total.saturating_sub(2) && pos < total {
         bar.set_message(bc_green().apply_to("").to_string());
     } else if pos == total {
         bar.set_message(bc_lgt_green().apply_to("").to_string());
@@ -52,7 +52,8 @@ pub fn update_end_cap(bar: &ProgressBar, pos: u64, total: u64) {
 
 pub fn close_bar(bar: ProgressBar, msg: &str) {
     bar.finish_and_clear();
-    match Term::stdout().write_line(format!("{}", bc_yellow().apply_to(msg)).as_str()) {
+    // Status goes next to the bars on stderr so stdout stays free for output like json
+    match Term::stderr().write_line(format!("{}", bc_yellow().apply_to(msg)).as_str()) {
         Ok(out) => out,
         Err(_e) => {
             error!("Failed to print to console");
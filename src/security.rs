@@ -0,0 +1,267 @@
+use crate::error::PDFConError;
+use log::error;
+use lopdf::encryption::crypt_filters::{Aes128CryptFilter, Aes256CryptFilter, CryptFilter};
+use lopdf::{Document, EncryptionState, EncryptionVersion, Object, Permissions, StringFormat};
+use md5::{Digest, Md5};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionMethod {
+    /// AES-128 with the revision 4 standard security handler
+    Aes128,
+    /// AES-256 with the revision 6 standard security handler
+    Aes256,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Encryption {
+    pub method: EncryptionMethod,
+    pub user_password: String,
+    pub owner_password: String,
+    pub permissions: Permissions,
+}
+
+/// Build a permission set from names given on the command line
+pub fn permissions_from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Permissions {
+    // Accessibility extraction must always be allowed since PDF 2.0
+    let mut permissions = Permissions::COPYABLE_FOR_ACCESSIBILITY;
+    for name in names {
+        permissions |= match name {
+            "print" => Permissions::PRINTABLE | Permissions::PRINTABLE_IN_HIGH_QUALITY,
+            "copy" => Permissions::COPYABLE,
+            "modify" => Permissions::MODIFIABLE | Permissions::ASSEMBLABLE,
+            "annotate" => Permissions::ANNOTABLE | Permissions::FILLABLE,
+            _ => Permissions::empty(),
+        };
+    }
+    permissions
+}
+
+/// A password nobody knows. Used when only a user password is given so the
+/// permissions can't be lifted by opening the file as the owner
+pub fn random_password() -> String {
+    rand::random::<[u8; 16]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Encryption {
+    /// Encrypt every string and stream in the document with the standard security handler
+    pub fn apply(&self, doc: &mut Document) -> Result<(), PDFConError> {
+        // Revision 4 mixes the file identifier into the key so it has to exist first
+        if doc.trailer.get(b"ID").is_err() {
+            let id = rand::random::<[u8; 16]>().to_vec();
+            doc.trailer.set(
+                "ID",
+                vec![
+                    Object::String(id.clone(), StringFormat::Hexadecimal),
+                    Object::String(id, StringFormat::Hexadecimal),
+                ],
+            );
+        }
+
+        let file_encryption_key = rand::random::<[u8; 32]>();
+        let version = match self.method {
+            EncryptionMethod::Aes128 => {
+                let crypt_filter: Arc<dyn CryptFilter> = Arc::new(Aes128CryptFilter);
+                EncryptionVersion::V4 {
+                    document: doc,
+                    encrypt_metadata: true,
+                    crypt_filters: BTreeMap::from([(b"StdCF".to_vec(), crypt_filter)]),
+                    stream_filter: b"StdCF".to_vec(),
+                    string_filter: b"StdCF".to_vec(),
+                    owner_password: &self.owner_password,
+                    user_password: &self.user_password,
+                    permissions: self.permissions,
+                }
+            }
+            EncryptionMethod::Aes256 => {
+                let crypt_filter: Arc<dyn CryptFilter> = Arc::new(Aes256CryptFilter);
+                EncryptionVersion::V5 {
+                    encrypt_metadata: true,
+                    crypt_filters: BTreeMap::from([(b"StdCF".to_vec(), crypt_filter)]),
+                    file_encryption_key: &file_encryption_key,
+                    stream_filter: b"StdCF".to_vec(),
+                    string_filter: b"StdCF".to_vec(),
+                    owner_password: &self.owner_password,
+                    user_password: &self.user_password,
+                    permissions: self.permissions,
+                }
+            }
+        };
+
+        let state = EncryptionState::try_from(version)?;
+        doc.encrypt(&state)?;
+
+        Ok(())
+    }
+}
+
+const PAD_BYTES: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A,
+];
+
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j = 0u8;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let mut i = 0u8;
+    let mut j = 0u8;
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            byte ^ state[state[i as usize].wrapping_add(state[j as usize]) as usize]
+        })
+        .collect()
+}
+
+// Algorithm 7 from the PDF spec. Revisions 2 to 4 store the padded user password
+// RC4 encrypted with a key derived from the owner password in the O entry.
+fn user_password_from_owner(doc: &Document, owner_password: &[u8]) -> Result<Vec<u8>, PDFConError> {
+    let encrypt = doc.get_encrypted()?;
+    let version = encrypt.get(b"V")?.as_i64()?;
+    let revision = encrypt.get(b"R")?.as_i64()?;
+    let owner_value = encrypt.get(b"O")?.as_str()?;
+
+    let key_length = match (version, revision) {
+        (_, 2) => 5,
+        (4, _) => 16,
+        _ => encrypt
+            .get(b"Length")
+            .and_then(|l| l.as_i64())
+            // The spec allows 40 to 128 bits. Anything else would leave RC4 without a key
+            .map(|l| (l.clamp(40, 128) / 8) as usize)
+            .unwrap_or(5),
+    };
+
+    let len = owner_password.len().min(32);
+    let mut hash = Md5::new()
+        .chain_update(&owner_password[..len])
+        .chain_update(&PAD_BYTES[..32 - len])
+        .finalize();
+    if revision >= 3 {
+        for _ in 0..50 {
+            hash = Md5::digest(hash);
+        }
+    }
+    let key = &hash[..key_length.min(16)];
+
+    if revision == 2 {
+        return Ok(rc4(key, owner_value));
+    }
+
+    let mut user_password = owner_value.to_vec();
+    for i in (0..20u8).rev() {
+        let round_key: Vec<u8> = key.iter().map(|b| b ^ i).collect();
+        user_password = rc4(&round_key, &user_password);
+    }
+    Ok(user_password)
+}
+
+/// Authenticate against the standard security handler and decrypt every string and stream
+pub fn decrypt(doc: &mut Document, password: &str) -> Result<(), PDFConError> {
+    if doc.authenticate_password(password).is_err() {
+        return Err(PDFConError::InvalidPassword);
+    }
+
+    let revision = doc.get_encrypted()?.get(b"R")?.as_i64()?;
+    // lopdf derives the key as if it was always given the user password. Owner passwords
+    // before AES-256 have to be turned back into the user password first
+    let result = if revision <= 4 && doc.authenticate_owner_password(password).is_ok() {
+        let user_password = user_password_from_owner(doc, password.as_bytes())?;
+        doc.decrypt_raw(user_password)
+    } else {
+        doc.decrypt(password)
+    };
+
+    result.map_err(|e| {
+        error!("Failed to decrypt pdf: {}", e);
+        PDFConError::InvalidPassword
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::dictionary;
+
+    fn padded_user() -> Vec<u8> {
+        [b"user".as_slice(), &PAD_BYTES[..28]].concat()
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn encrypted(encrypt: lopdf::Dictionary) -> Document {
+        let mut doc = Document::with_version("1.7");
+        let id = doc.add_object(encrypt);
+        doc.trailer.set("Encrypt", id);
+        doc
+    }
+
+    #[test]
+    fn rc4_matches_known_vector() {
+        assert_eq!(rc4(b"Key", b"Plaintext"), hex("bbf316e8d940af0ad3"));
+    }
+
+    #[test]
+    fn rc4_decrypts_what_it_encrypts() {
+        let data = b"some stream data";
+        assert_eq!(rc4(b"k", &rc4(b"k", data)), data);
+    }
+
+    #[test]
+    fn user_password_from_owner_revision_2() {
+        let doc = encrypted(dictionary! {
+            "V" => 1,
+            "R" => 2,
+            "O" => Object::string_literal(hex(
+                "94e8094419662a774442fb072e3d9f19e9d130ec09a4d0061e78fe920f7ab62f",
+            )),
+        });
+        assert_eq!(
+            user_password_from_owner(&doc, b"owner").unwrap(),
+            padded_user()
+        );
+    }
+
+    #[test]
+    fn user_password_from_owner_revision_3() {
+        let doc = encrypted(dictionary! {
+            "V" => 2,
+            "R" => 3,
+            "Length" => 128,
+            "O" => Object::string_literal(hex(
+                "0ba3835f88f90388e74e54584125ce142be0de24c6b0d37746e075b891756671",
+            )),
+        });
+        assert_eq!(
+            user_password_from_owner(&doc, b"owner").unwrap(),
+            padded_user()
+        );
+    }
+
+    #[test]
+    fn user_password_from_owner_survives_a_short_length() {
+        let doc = encrypted(dictionary! {
+            "V" => 2,
+            "R" => 3,
+            "Length" => 0,
+            "O" => Object::string_literal(vec![0u8; 32]),
+        });
+        assert_eq!(user_password_from_owner(&doc, b"owner").unwrap().len(), 32);
+    }
+}
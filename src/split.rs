@@ -0,0 +1,274 @@
+use crate::Run;
+use crate::constants::tick_speed;
+use crate::error::PDFConError;
+use crate::info::human_bytes;
+use crate::merge::flatten_inherited;
+use crate::outline;
+use crate::page_range::PageRanges;
+use crate::progress::{close_bar, spinner};
+use crate::security;
+use log::warn;
+use lopdf::{Document, Object, ObjectId, dictionary};
+use std::collections::{BTreeMap, HashSet};
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+/// How the pages of a pdf are divided between the output files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SplitMode {
+    /// A new file every this many pages
+    Every(u32),
+    /// A new file at the page of every top level outline item
+    Outline,
+    /// One file for each range
+    Ranges(PageRanges),
+    /// As many pages as fit under this many bytes
+    MaxSize(u64),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Split {
+    pub in_file: PathBuf,
+    pub out_directory: PathBuf,
+    pub password: Option<String>,
+    pub mode: SplitMode,
+}
+
+fn is_page_tree_node(object: &Object) -> bool {
+    object
+        .as_dict()
+        .and_then(|d| d.get(b"Type"))
+        .and_then(Object::as_name)
+        .is_ok_and(|t| t == b"Page" || t == b"Pages")
+}
+
+fn collect_references(object: &Object, refs: &mut Vec<ObjectId>) {
+    match object {
+        Object::Reference(id) => refs.push(*id),
+        Object::Array(array) => array.iter().for_each(|o| collect_references(o, refs)),
+        Object::Dictionary(dict) => dict
+            .iter()
+            .filter(|(key, _)| key.as_slice() != b"Parent")
+            .for_each(|(_, o)| collect_references(o, refs)),
+        Object::Stream(stream) => {
+            collect_references(&Object::Dictionary(stream.dict.clone()), refs)
+        }
+        _ => {}
+    }
+}
+
+/// Every object the given pages need. Links to pages outside the part are not followed
+/// so a part never picks up another part's images
+fn part_objects(doc: &Document, pages: &[ObjectId]) -> BTreeMap<ObjectId, Object> {
+    let page_set: HashSet<&ObjectId> = pages.iter().collect();
+    let mut objects = BTreeMap::new();
+    let mut stack = pages.to_vec();
+    while let Some(id) = stack.pop() {
+        if objects.contains_key(&id) {
+            continue;
+        }
+        let Ok(object) = doc.get_object(id) else {
+            continue;
+        };
+        if is_page_tree_node(object) && !page_set.contains(&id) {
+            continue;
+        }
+        collect_references(object, &mut stack);
+        objects.insert(id, object.clone());
+    }
+    objects
+}
+
+/// Rough size an object takes up in a saved file
+fn object_size(object: &Object) -> u64 {
+    match object {
+        Object::Stream(stream) => stream.content.len() as u64 + 40 * stream.dict.len() as u64,
+        Object::Dictionary(dict) => 40 * dict.len() as u64,
+        Object::Array(array) => 10 * array.len() as u64,
+        _ => 20,
+    }
+}
+
+/// File name of one part out of several, counted from 1. The number is padded to at
+/// least three digits so parts sort in order
+pub fn part_file_name(stem: &str, part: usize, total_parts: usize) -> String {
+    let padding_width = ((total_parts.max(1).ilog10() + 1) as usize).max(3);
+    format!("{}-{:0width$}.pdf", stem, part, width = padding_width)
+}
+
+impl Split {
+    /// Page numbers of each part in order
+    fn parts(&self, doc: &Document) -> Vec<Vec<u32>> {
+        let pages = doc.get_pages();
+        let total = pages.len() as u32;
+        match &self.mode {
+            SplitMode::Every(n) => (1..=total)
+                .collect::<Vec<_>>()
+                .chunks(*n as usize)
+                .map(|c| c.to_vec())
+                .collect(),
+            SplitMode::Ranges(ranges) => ranges
+                .parts(total)
+                .into_iter()
+                .filter(|p| !p.is_empty())
+                .collect(),
+            SplitMode::Outline => {
+                let mut starts: Vec<u32> = outline::chapters(doc)
+                    .into_iter()
+                    .map(|(num, _)| num)
+                    .collect();
+                if starts.is_empty() {
+                    warn!("No outline items point at a page. Writing a single part");
+                }
+                if starts.first() != Some(&1) {
+                    starts.insert(0, 1);
+                }
+                starts
+                    .iter()
+                    .zip(starts.iter().skip(1).map(|s| s - 1).chain([total]))
+                    .map(|(&start, end)| (start..=end).collect())
+                    .collect()
+            }
+            SplitMode::MaxSize(max) => {
+                let mut parts: Vec<Vec<u32>> = Vec::new();
+                let mut current = Vec::new();
+                let mut counted = HashSet::new();
+                let mut size = 0;
+                for (num, page_id) in &pages {
+                    let objects = part_objects(doc, &[*page_id]);
+                    // Objects shared with earlier pages of the part are only stored once
+                    let added: u64 = objects
+                        .iter()
+                        .filter(|(id, _)| !counted.contains(*id))
+                        .map(|(_, o)| object_size(o))
+                        .sum();
+                    if !current.is_empty() && size + added > *max {
+                        parts.push(std::mem::take(&mut current));
+                        counted.clear();
+                        size = objects.values().map(object_size).sum();
+                    } else {
+                        size += added;
+                    }
+                    if size > *max {
+                        warn!("Page {} alone is larger than {}", num, human_bytes(*max));
+                    }
+                    counted.extend(objects.into_keys());
+                    current.push(*num);
+                }
+                if !current.is_empty() {
+                    parts.push(current);
+                }
+                parts
+            }
+        }
+    }
+
+    fn build_part(&self, doc: &Document, page_nums: &[u32]) -> Result<Document, PDFConError> {
+        let pages = doc.get_pages();
+        let page_ids: Vec<ObjectId> = page_nums
+            .iter()
+            .filter_map(|n| pages.get(n))
+            .copied()
+            .collect();
+
+        let mut part = Document::with_version(doc.version.clone());
+        part.objects = part_objects(doc, &page_ids);
+        part.max_id = doc.max_id;
+
+        let pages_id = part.new_object_id();
+        for page_id in &page_ids {
+            part.get_dictionary_mut(*page_id)?.set("Parent", pages_id);
+        }
+        let pages_dict = dictionary! {
+            "Type" => "Pages",
+            "Count" => page_ids.len() as u32,
+            "Kids" => page_ids.into_iter().map(Object::Reference).collect::<Vec<_>>(),
+        };
+        part.objects
+            .insert(pages_id, Object::Dictionary(pages_dict));
+
+        let catalog_id = part.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        part.trailer.set("Root", catalog_id);
+        // Encrypted parts reuse the original's keys which are tied to its file id
+        if let Ok(id) = doc.trailer.get(b"ID") {
+            part.trailer.set("ID", id.clone());
+        }
+        part.renumber_objects();
+
+        Ok(part)
+    }
+}
+
+impl Run for Split {
+    fn run(&self) -> Result<(), PDFConError> {
+        // Add spinner to show program is doing something
+        let spnr = spinner("Parsing PDF", tick_speed());
+
+        let mut document = Document::load(&self.in_file)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
+        // Encrypted input is split into parts with the same keys and permissions
+        let encryption_state = document.encryption_state.clone();
+
+        // Pages lose the page tree they inherited from
+        for page_id in document.get_pages().into_values() {
+            flatten_inherited(&mut document, page_id)?;
+        }
+
+        // Finish bar and display message
+        close_bar(spnr, " ● Parsing Complete! ");
+
+        std::fs::create_dir_all(&self.out_directory)?;
+        let parts = self.parts(&document);
+        let stem = self
+            .in_file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        for (i, page_nums) in parts.iter().enumerate() {
+            let mut part = self.build_part(&document, page_nums)?;
+            if let Some(state) = &encryption_state {
+                part.encrypt(state)?;
+            }
+
+            let path = self
+                .out_directory
+                .join(part_file_name(&stem, i + 1, parts.len()));
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(&path)?;
+            let mut writer = BufWriter::new(file);
+            part.save_to(&mut writer)?;
+
+            println!(
+                "{}  pages {}-{}",
+                path.display(),
+                page_nums.first().unwrap_or(&0),
+                page_nums.last().unwrap_or(&0)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn part_file_name_pads_to_at_least_three_digits() {
+        assert_eq!(part_file_name("book", 1, 4), "book-001.pdf");
+        assert_eq!(part_file_name("book", 12, 999), "book-012.pdf");
+        assert_eq!(part_file_name("book", 12, 1000), "book-0012.pdf");
+    }
+}
@@ -1,13 +1,24 @@
 use crate::Run;
+use crate::archive::{ArchiveFormat, ArchiveWriter, ComicInfo};
 use crate::constants::{IGNORE_LIST, tick_speed};
 use crate::error::PDFConError;
-use crate::pdf_image::{self, PDFConColorSpace};
+use crate::manifest::{self, ManifestEntry, ManifestOutput};
+use crate::name_template::NameTemplate;
+use crate::outline;
+use crate::page_label::PageLabels;
+use crate::page_range::PageRanges;
+use crate::pdf_image::optimize::JpegOptions;
+use crate::pdf_image::resample::Resample;
+use crate::pdf_image::{self, CcittParams, ImageEncoding, OutputFormat, PDFConColorSpace};
 use crate::progress::{bar, close_bar, spinner, update_end_cap};
+use crate::security;
 use indicatif::ParallelProgressIterator;
-use log::{debug, error};
-use lopdf::{Dictionary, Document, Object};
+use log::{debug, error, warn};
+use lopdf::{Dictionary, Document, Object, ObjectId};
 use rayon::prelude::*;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Unpack {
@@ -15,6 +26,118 @@ pub struct Unpack {
     pub out_directory: PathBuf,
     pub in_file: PathBuf,
     pub optimize: bool,
+    pub jpeg_options: JpegOptions,
+    pub resample: Resample,
+    pub password: Option<String>,
+    pub selection: ImageSelection,
+    pub format: OutputFormat,
+    pub name_template: NameTemplate,
+    pub manifest: Option<ManifestOutput>,
+    /// Write the images into this zip, cbz or tar file instead of the output directory
+    pub archive: Option<PathBuf>,
+    /// Write each repeated image once instead of once per page it is on
+    pub dedupe: Option<Dedupe>,
+    /// Turn CMYK jpegs into RGB ones for viewers that can't show CMYK
+    pub cmyk_to_rgb: bool,
+}
+
+/// What unpack does with an image it has already written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dedupe {
+    /// Leave it out. Its manifest entry points at the first copy
+    Skip,
+    /// Hard link it to the first copy. Archives leave it out instead
+    Link,
+}
+
+/// Page number and position on the page of an image
+type ImageKey = (u32, usize);
+
+/// An encoded image waiting to be written out
+struct Extracted {
+    name: String,
+    contents: Vec<u8>,
+    entry: Option<ManifestEntry>,
+    key: ImageKey,
+    /// The first copy of the image when this one is a duplicate. Nothing was encoded
+    duplicate_of: Option<ImageKey>,
+}
+
+/// Characters that can't go in a file name
+fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// A page label as a file name. Labels that are empty or only dots, which would name the
+/// current or parent folder, fall back to the page number
+fn label_file_name(label: &str, page_num: u32) -> String {
+    let label = file_name_safe(label);
+    if label.trim().chars().all(|c| c == '.') {
+        page_num.to_string()
+    } else {
+        label
+    }
+}
+
+/// Folder names for the chapters of a document, each paired with the page it starts
+/// on. Numbering the folders keeps them in reading order
+fn chapter_folders(doc: &Document) -> Vec<(u32, String)> {
+    let chapters = outline::chapters(doc);
+    let padding_width = (chapters.len().max(1).ilog10() + 1) as usize;
+    chapters
+        .into_iter()
+        .enumerate()
+        .map(|(i, (page_num, title))| {
+            let title = file_name_safe(&title);
+            let title = title.trim().trim_end_matches('.');
+            let title = if title.is_empty() { "Chapter" } else { title };
+            (
+                page_num,
+                format!("{:0width$} {}", i + 1, title, width = padding_width),
+            )
+        })
+        .collect()
+}
+
+/// Which images unpack extracts. Everything is extracted by default
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImageSelection {
+    pub pages: Option<PageRanges>,
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    /// Compared against the stream as stored in the pdf
+    pub min_bytes: Option<usize>,
+    pub only: Option<Vec<ImageEncoding>>,
+}
+
+impl ImageSelection {
+    pub fn includes_page(&self, page_num: u32) -> bool {
+        self.pages
+            .as_ref()
+            .is_none_or(|pages| pages.contains(page_num))
+    }
+
+    pub fn includes_image(
+        &self,
+        width: u32,
+        height: u32,
+        bytes: usize,
+        encoding: ImageEncoding,
+    ) -> bool {
+        self.min_width.is_none_or(|min| width >= min)
+            && self.min_height.is_none_or(|min| height >= min)
+            && self.min_bytes.is_none_or(|min| bytes >= min)
+            && self
+                .only
+                .as_ref()
+                .is_none_or(|only| only.contains(&encoding))
+    }
 }
 
 pub fn filter_func(object_id: (u32, u16), object: &mut Object) -> Option<((u32, u16), Object)> {
@@ -38,14 +161,132 @@ pub fn filter_func(object_id: (u32, u16), object: &mut Object) -> Option<((u32,
     Some((object_id, object.to_owned()))
 }
 
+/// Name and object id of every image XObject in a page's resources, including ones
+/// inherited from the page tree. Pages without any give an empty list
+pub fn page_images(
+    doc: &Document,
+    page_id: ObjectId,
+) -> Result<Vec<(&[u8], ObjectId)>, PDFConError> {
+    // The nearest Resources entry replaces any further up the tree
+    let mut node = doc.get_dictionary(page_id)?;
+    let mut seen = HashSet::new();
+    let resources = loop {
+        if let Ok(resources) = node.get(b"Resources") {
+            break resources;
+        }
+        match node.get(b"Parent").and_then(Object::as_reference) {
+            Ok(parent) if seen.insert(parent) => node = doc.get_dictionary(parent)?,
+            _ => return Ok(Vec::new()),
+        }
+    };
+    let resources_dict = doc.dereference(resources)?.1.as_dict()?;
+    let Ok(x_objects) = resources_dict.get(b"XObject") else {
+        return Ok(Vec::new());
+    };
+    let x_obj_dict = doc.dereference(x_objects)?.1.as_dict()?;
+
+    Ok(x_obj_dict
+        .iter()
+        .filter_map(|(name, x_ref)| {
+            let id = x_ref.as_reference().ok()?;
+            doc.get_object(id)
+                .and_then(|object| object.as_stream())
+                .and_then(|stream| stream.dict.get(b"Subtype"))
+                .and_then(|subtype| subtype.as_name())
+                .is_ok_and(|subtype| subtype == b"Image")
+                .then_some((name.as_slice(), id))
+        })
+        .collect())
+}
+
+/// Whether an image's Decode array flips its samples, as `[1 0]` per component does
+fn decode_inverted(dict: &Dictionary) -> bool {
+    dict.get(b"Decode")
+        .and_then(|d| d.as_array())
+        .is_ok_and(|d| d.first().and_then(|v| v.as_float().ok()) == Some(1.0))
+}
+
+/// Samples of an unfiltered or flate image in a layout the encoders take, with its
+/// Decode array applied
+fn normalized_samples(
+    dict: &Dictionary,
+    content: Vec<u8>,
+    width: u32,
+    height: u32,
+) -> Result<(Vec<u8>, PDFConColorSpace), PDFConError> {
+    // Stencil masks are 1 bit and paint where a sample is 0, the same as black in gray
+    let image_mask = dict
+        .get(b"ImageMask")
+        .and_then(Object::as_bool)
+        .unwrap_or(false);
+    let (color_space, bits) = match image_mask {
+        true => (b"DeviceGray".as_slice(), 1),
+        false => (
+            dict.get(b"ColorSpace")?.as_name()?,
+            dict.get(b"BitsPerComponent")?.as_i64()? as u8,
+        ),
+    };
+    let decode: Option<Vec<f32>> = dict
+        .get(b"Decode")
+        .and_then(Object::as_array)
+        .ok()
+        .map(|d| d.iter().filter_map(|v| v.as_float().ok()).collect());
+    pdf_image::normalize_samples(content, width, height, color_space, bits, decode.as_deref())
+}
+
+/// Map every image that repeats one earlier in the pages to that first copy. Images
+/// repeat when pages share an object or when two objects hold the same data
+fn find_duplicates(doc: &Document, pages: &[(u32, ObjectId)]) -> HashMap<ImageKey, ImageKey> {
+    let mut occurrences = Vec::new();
+    for (page_num, page_id) in pages {
+        let images = page_images(doc, *page_id).unwrap_or_default();
+        for (index, (_, ref_id)) in images.into_iter().enumerate() {
+            occurrences.push(((*page_num, index + 1), ref_id));
+        }
+    }
+
+    let ids: HashSet<ObjectId> = occurrences.iter().map(|(_, id)| *id).collect();
+    let hashes: HashMap<ObjectId, Vec<u8>> = ids
+        .into_par_iter()
+        .filter_map(|id| {
+            let stream = doc.get_object(id).and_then(Object::as_stream).ok()?;
+            Some((id, pdf_image::image_hash(&stream.dict, &stream.content)))
+        })
+        .collect();
+
+    let mut first_by_id = HashMap::new();
+    let mut first_by_hash = HashMap::new();
+    let mut duplicates = HashMap::new();
+    for (key, id) in occurrences {
+        if let Some(first) = first_by_id.get(&id) {
+            duplicates.insert(key, *first);
+            continue;
+        }
+        let first = match hashes.get(&id) {
+            Some(hash) => *first_by_hash.entry(hash).or_insert(key),
+            None => key,
+        };
+        first_by_id.insert(id, first);
+        if first != key {
+            duplicates.insert(key, first);
+        }
+    }
+    duplicates
+}
+
 impl Unpack {
+    #[allow(clippy::too_many_arguments)]
     fn process_xobject(
         &self,
         doc: &Document,
         page_num: u32,
         total_pages: usize,
+        label: &str,
+        index: usize,
+        chapter: &str,
         reference: &Object,
-    ) -> Result<(), PDFConError> {
+        duplicate: bool,
+    ) -> Result<Option<(String, Vec<u8>)>, PDFConError> {
         debug!("Getting xobject information");
         let ref_id = reference.as_reference()?;
 
@@ -58,32 +299,66 @@ impl Unpack {
         debug!("Checking image");
         if subtype != b"Image" {
             // Not an image. No need to continue
-            return Ok(());
+            return Ok(None);
         }
 
         debug!("Grabbing filter");
         let filters = match stream.dict.get(b"Filter") {
             Ok(f) => {
-                let first = f.as_name();
-                if first.is_ok() {
-                    Some(vec![first.unwrap()])
+                if let Ok(first) = f.as_name() {
+                    Some(vec![first])
+                } else if let Ok(second) = f.as_str() {
+                    Some(vec![second])
                 } else {
-                    let second = f.as_str();
-                    if second.is_ok() {
-                        Some(vec![second.unwrap()])
-                    } else {
-                        let mut vec = Vec::new();
-                        for filter in f.as_array()? {
-                            vec.push(filter.as_name()?);
-                        }
-                        Some(vec)
+                    let mut vec = Vec::new();
+                    for filter in f.as_array()? {
+                        vec.push(filter.as_name()?);
                     }
+                    Some(vec)
                 }
             }
             Err(_) => None,
         };
 
-        match filters {
+        let width = stream.dict.get(b"Width")?.as_i64()? as u32;
+        let height = stream.dict.get(b"Height")?.as_i64()? as u32;
+        let encoding = ImageEncoding::from_filters(filters.as_deref().unwrap_or_default());
+        if matches!(encoding, ImageEncoding::Jbig2 | ImageEncoding::Jpx) {
+            warn!(
+                "Skipping {:?} image {} on page {}. It can't be decoded",
+                encoding, ref_id.0, page_num
+            );
+            return Ok(None);
+        }
+        if !self
+            .selection
+            .includes_image(width, height, stream.content.len(), encoding)
+        {
+            debug!("Skipping image filtered out by selection");
+            return Ok(None);
+        }
+
+        let stem = self
+            .in_file
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let name = self.name_template.render(
+            &stem,
+            page_num,
+            total_pages,
+            label,
+            index,
+            ref_id.0,
+            chapter,
+            self.format.extension(encoding),
+        );
+        if duplicate {
+            // Only the name is needed. The first copy has the contents
+            return Ok(Some((name, Vec::new())));
+        }
+
+        let contents = match filters {
             Some(filter_list) => {
                 // Filters are applied in reverse order from how they appear so
                 // we're going to reverse this and apply the filters as the appear.
@@ -91,134 +366,347 @@ impl Unpack {
                 // present then that means we're likely dealing with a png and we'll treat it as a png.
                 // If no filter is present then that means some pdf builder sharted out raw pixel data into the
                 // document. They shouldn't do this ( ImageMagick ) but we probably aught to handle this it.
-                let mut is_jpeg = false;
+                // CCITT fax data is decoded to gray
                 // I'd prefer not to clone but we may have to do that here. We should see if it's possible not to
                 // duplicate the stream contents to process it
                 let mut content = stream.content.clone();
                 for filter in filter_list.into_iter().rev() {
-                    if filter == b"DCTDecode" {
-                        is_jpeg = true;
-                    } else if filter == b"FlateDecode" {
+                    if filter == b"FlateDecode" {
                         content = pdf_image::decompress(&content)?;
                     }
                 }
+                // Predictors only come up on plain FlateDecode images, optimize writes them too
+                if encoding == ImageEncoding::Png {
+                    let parms = stream
+                        .dict
+                        .get(b"DecodeParms")
+                        .and_then(|p| p.as_dict())
+                        .ok();
+                    content = pdf_image::unpredict(content, parms)?;
+                }
 
-                // Calculate needed zero padding for page names
-                let padding_width = (total_pages.ilog10() + 1) as usize;
-                let path = self.out_directory.join(format!(
-                    "{:0width$}.{}",
-                    page_num,
-                    if is_jpeg { "jpg" } else { "png" },
-                    width = padding_width
-                ));
-
-                if is_jpeg {
-                    pdf_image::save_jpeg(&content, &path, self.optimize)?
+                if encoding == ImageEncoding::Ccitt {
+                    // The fax filter is the last in the chain so its parameters are too
+                    let parms = match stream.dict.get(b"DecodeParms") {
+                        Ok(Object::Array(a)) => a.last(),
+                        Ok(p) => Some(p),
+                        Err(_) => None,
+                    }
+                    .and_then(|p| p.as_dict().ok());
+                    let parm = |key: &[u8]| parms.and_then(|p| p.get(key).ok());
+                    let params = CcittParams {
+                        k: parm(b"K").and_then(|k| k.as_i64().ok()).unwrap_or(0),
+                        columns: parm(b"Columns")
+                            .and_then(|c| c.as_i64().ok())
+                            .map_or(width, |c| c as u32),
+                        rows: height,
+                        byte_align: parm(b"EncodedByteAlign")
+                            .and_then(|b| b.as_bool().ok())
+                            .unwrap_or(false),
+                        // BlackIs1 and an inverting Decode array each flip how the runs are shown
+                        inverted: parm(b"BlackIs1")
+                            .and_then(|b| b.as_bool().ok())
+                            .unwrap_or(false)
+                            != decode_inverted(&stream.dict),
+                    };
+                    pdf_image::encode_ccitt_as(
+                        &content,
+                        &params,
+                        self.format,
+                        self.optimize,
+                        &self.jpeg_options,
+                        &self.resample,
+                    )?
+                } else if encoding == ImageEncoding::Jpeg {
+                    pdf_image::encode_jpeg_as(
+                        &content,
+                        self.format,
+                        self.optimize,
+                        &self.jpeg_options,
+                        &self.resample,
+                        decode_inverted(&stream.dict),
+                        self.cmyk_to_rgb,
+                    )?
                 } else {
-                    let width = stream.dict.get(b"Width")?.as_i64()? as u32;
-                    let height = stream.dict.get(b"Height")?.as_i64()? as u32;
-                    let bits = stream.dict.get(b"BitsPerComponent")?.as_i64()? as u8;
-                    let color_enum = PDFConColorSpace::from_pdf_format((
-                        stream.dict.get(b"ColorSpace")?.as_name()?,
-                        bits,
-                    ));
-
-                    pdf_image::encode_and_save_png(
+                    let (content, color_enum) =
+                        normalized_samples(&stream.dict, content, width, height)?;
+
+                    pdf_image::encode_as(
                         &content,
                         width,
                         height,
                         &color_enum,
-                        &path,
+                        self.format,
                         self.optimize,
+                        &self.jpeg_options,
+                        &self.resample,
                     )?
                 }
             }
             None => {
                 // This is a raw pixel buffer. We can encode this in any format we'd like
-                // Treat it like its a png
                 debug!("Raw pixel buffer");
-                let width = stream.dict.get(b"Width")?.as_i64()? as u32;
-                let height = stream.dict.get(b"Height")?.as_i64()? as u32;
-                let bits = stream.dict.get(b"BitsPerComponent")?.as_i64()? as u8;
-                let color_enum = PDFConColorSpace::from_pdf_format((
-                    stream.dict.get(b"ColorSpace")?.as_name()?,
-                    bits,
-                ));
-
-                let path = self.out_directory.join(format!("{:0>5}.png", page_num));
-
-                pdf_image::encode_and_save_png(
-                    &stream.content,
+                let (content, color_enum) =
+                    normalized_samples(&stream.dict, stream.content.clone(), width, height)?;
+
+                pdf_image::encode_as(
+                    &content,
                     width,
                     height,
                     &color_enum,
-                    &path,
+                    self.format,
                     self.optimize,
+                    &self.jpeg_options,
+                    &self.resample,
                 )?
             }
-        }
+        };
 
-        Ok(())
+        Ok(Some((name, contents)))
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn find_xobject_images_in_page(
         &self,
         doc: &Document,
         page_num: u32,
-        page_dict: &Dictionary,
+        page_id: ObjectId,
         total_pages: usize,
-    ) -> Result<(), PDFConError> {
+        label: &str,
+        chapter: &str,
+        duplicates: &HashMap<ImageKey, ImageKey>,
+    ) -> Result<Vec<Extracted>, PDFConError> {
         debug!("Getting resources and xobjects");
-        let resources_dict = page_dict.get(b"Resources")?.as_dict()?;
-        let x_obj_dict = resources_dict.get(b"XObject")?.as_dict()?;
-        for (_name, x_ref) in x_obj_dict.iter() {
-            self.process_xobject(&doc, page_num, total_pages, &x_ref)?;
+        let images = page_images(doc, page_id)?;
+        let placements = match self.manifest {
+            Some(_) => manifest::placements(doc, page_id),
+            None => Default::default(),
+        };
+
+        let mut extracted = Vec::new();
+        for (index, (x_name, ref_id)) in images.into_iter().enumerate() {
+            let x_ref = Object::Reference(ref_id);
+            let key = (page_num, index + 1);
+            let duplicate_of = duplicates.get(&key).copied();
+            let Some((name, contents)) = self.process_xobject(
+                doc,
+                page_num,
+                total_pages,
+                label,
+                index + 1,
+                chapter,
+                &x_ref,
+                duplicate_of.is_some(),
+            )?
+            else {
+                continue;
+            };
+            let entry = match &self.manifest {
+                Some(_) => Some(ManifestEntry::new(
+                    page_num,
+                    index + 1,
+                    ref_id,
+                    x_name,
+                    &doc.get_object(ref_id)?.as_stream()?.dict,
+                    placements.get(x_name).copied(),
+                    self.output_path(&name),
+                    &contents,
+                )?),
+                None => None,
+            };
+            extracted.push(Extracted {
+                name,
+                contents,
+                entry,
+                key,
+                duplicate_of,
+            });
+        }
+        Ok(extracted)
+    }
+
+    /// Where an image ends up. Images in an archive are named by their path inside it
+    fn output_path(&self, name: &str) -> PathBuf {
+        match self.archive {
+            Some(_) => PathBuf::from(name),
+            None => self.out_directory.join(name),
+        }
+    }
+
+    fn write_file(path: &Path, contents: &[u8]) -> Result<(), PDFConError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(contents)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Replace whatever is at `link` with a hard link to `original`
+    fn link_file(original: &Path, link: &Path) -> Result<(), PDFConError> {
+        if original == link {
+            return Ok(());
+        }
+        if let Some(parent) = link.parent() {
+            std::fs::create_dir_all(parent)?;
         }
+        if link.exists() {
+            std::fs::remove_file(link)?;
+        }
+        std::fs::hard_link(original, link)?;
         Ok(())
     }
 
-    fn extract_images(&self, doc: &Document) -> Result<(), PDFConError> {
-        let pages = doc.get_pages().into_par_iter().collect::<Vec<_>>();
-        let total_pages = pages.len();
+    /// Extract every selected image and return the manifest entries of those written.
+    /// Images go to the archive when one is given and to the output directory otherwise
+    fn extract_images(
+        &self,
+        doc: &Document,
+        mut archive: Option<&mut ArchiveWriter>,
+    ) -> Result<Vec<ManifestEntry>, PDFConError> {
+        let all_pages = doc.get_pages();
+        // Keep padding based on the whole document so names match a full unpack
+        let total_pages = all_pages.len();
+        let pages = all_pages
+            .into_par_iter()
+            .filter(|(page_num, _)| self.selection.includes_page(*page_num))
+            .collect::<Vec<_>>();
+        let labels = if self.name_template.uses("label") {
+            PageLabels::from_document(doc)
+        } else {
+            None
+        };
+        let chapters = if self.name_template.uses("chapter") {
+            chapter_folders(doc)
+        } else {
+            Vec::new()
+        };
+        let duplicates = match self.dedupe {
+            Some(_) => find_duplicates(doc, &pages),
+            None => HashMap::new(),
+        };
+        let first_copies: HashSet<ImageKey> = duplicates.values().copied().collect();
+        let link = self.dedupe == Some(Dedupe::Link) && archive.is_none();
+        if self.dedupe == Some(Dedupe::Link) && !link {
+            warn!("Archives can't hold hard links. Leaving duplicate images out instead");
+        }
 
         // Initialize the progress bar
-        let pb = bar("Processing Images", total_pages as u64, tick_speed());
+        let pb = bar("Processing Images", pages.len() as u64, tick_speed());
 
-        let results: Vec<Result<(), PDFConError>> = pages
-            .par_iter()
-            .progress_with(pb.clone())
-            .map(|(page_num, page_id)| {
-                let pos = pb.position();
-                let total = pb.length().unwrap();
+        // Archives are written in page order so their pages are extracted a batch at a
+        // time. That keeps only one batch of encoded images in memory
+        let batch_size = match archive {
+            Some(_) => self.threads * 4,
+            None => pages.len(),
+        };
+        let to_directory = archive.is_none();
 
-                // Update bars end cap based on current progress
-                update_end_cap(&pb, pos, total);
+        let mut error_encountered = false;
+        let mut entries = Vec::new();
+        // Where each first copy of a repeated image went and its hash
+        let mut firsts: HashMap<ImageKey, (PathBuf, String)> = HashMap::new();
+        for batch in pages.chunks(batch_size.max(1)) {
+            let results: Vec<Result<Vec<Extracted>, PDFConError>> = batch
+                .par_iter()
+                .progress_with(pb.clone())
+                .map(|(page_num, page_id)| {
+                    let pos = pb.position();
+                    let total = pb.length().unwrap();
 
-                debug!("Getting page dict");
-                let page_dict = doc.get_object(*page_id)?.as_dict()?;
-                self.find_xobject_images_in_page(&doc, *page_num, &page_dict, total_pages)?;
-                Ok(())
-            })
-            .collect();
+                    // Update bars end cap based on current progress
+                    update_end_cap(&pb, pos, total);
 
-        // Finish bar and display message
-        close_bar(pb, " ● Processing Complete! ");
+                    let chapter = chapters
+                        .iter()
+                        .rfind(|(start, _)| start <= page_num)
+                        .map_or("", |(_, folder)| folder.as_str());
+                    let label = match &labels {
+                        Some(labels) => label_file_name(&labels.label(*page_num), *page_num),
+                        None => page_num.to_string(),
+                    };
+                    let mut extracted = self.find_xobject_images_in_page(
+                        doc,
+                        *page_num,
+                        *page_id,
+                        total_pages,
+                        &label,
+                        chapter,
+                        &duplicates,
+                    )?;
+                    if to_directory {
+                        for image in extracted.iter_mut().filter(|i| i.duplicate_of.is_none()) {
+                            Self::write_file(
+                                &self.out_directory.join(&image.name),
+                                &image.contents,
+                            )?;
+                            image.contents = Vec::new();
+                        }
+                    }
+                    Ok(extracted)
+                })
+                .collect();
 
-        // Log any errors and return a general error
-        let mut error_encountered = false;
-        for result in results {
-            match result {
-                Ok(()) => {}
-                Err(e) => {
-                    error_encountered = true;
-                    error!("Failed to extract image from page: {{{}}}", e.to_string())
+            // Log any errors and return a general error once every page is done
+            for result in results {
+                match result {
+                    Ok(extracted) => {
+                        for image in extracted {
+                            let path = self.output_path(&image.name);
+                            let Some(first) = image.duplicate_of else {
+                                if let Some(archive) = archive.as_deref_mut() {
+                                    archive.add(&image.name, &image.contents)?;
+                                }
+                                if first_copies.contains(&image.key) {
+                                    let sha256 = image
+                                        .entry
+                                        .as_ref()
+                                        .map(|e| e.sha256.clone())
+                                        .unwrap_or_default();
+                                    firsts.insert(image.key, (path, sha256));
+                                }
+                                entries.extend(image.entry);
+                                continue;
+                            };
+
+                            // The first copy's page failed when it isn't here
+                            let Some((first_path, sha256)) = firsts.get(&first) else {
+                                continue;
+                            };
+                            if link {
+                                Self::link_file(first_path, &path)?;
+                            }
+                            if let Some(mut entry) = image.entry {
+                                if !link {
+                                    entry.path = first_path.clone();
+                                }
+                                entry.sha256 = sha256.clone();
+                                entry.duplicate_of = Some(first_path.clone());
+                                entries.push(entry);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error_encountered = true;
+                        error!("Failed to extract image from page: {{{}}}", e)
+                    }
                 }
             }
         }
+
+        // Finish bar and display message
+        close_bar(pb, " ● Processing Complete! ");
+
         if error_encountered {
             return Err(PDFConError::UnpackError);
         }
-        Ok(())
+        Ok(entries)
     }
 }
 
@@ -233,13 +721,51 @@ impl Run for Unpack {
         // Add spinner to show program is doing something
         let spnr = spinner("Parsing PDF", tick_speed());
 
-        let document = Document::load_filtered(&self.in_file, filter_func)?;
+        let mut document = Document::load_filtered(&self.in_file, filter_func)?;
+
+        // lopdf already opens documents with an empty user password
+        if document.is_encrypted() {
+            security::decrypt(&mut document, self.password.as_deref().unwrap_or(""))?;
+        }
 
         // Finish bar and display message
         close_bar(spnr, " ● Parsing Complete! ");
 
-        self.extract_images(&document)?;
+        let entries = match &self.archive {
+            Some(path) => {
+                let format = ArchiveFormat::from_path(path)?.unwrap_or(ArchiveFormat::Zip);
+                let mut archive = ArchiveWriter::create(path, format)?;
+                let entries = self.extract_images(&document, Some(&mut archive))?;
+                // Comic readers take the title and credits from here
+                if let Some(comic_info) = ComicInfo::from_document(&document) {
+                    let xml = comic_info.to_xml(archive.len());
+                    archive.add("ComicInfo.xml", xml.as_bytes())?;
+                }
+                archive.finish()?;
+                entries
+            }
+            None => self.extract_images(&document, None)?,
+        };
+
+        if let Some(output) = &self.manifest {
+            manifest::write(&entries, output)?;
+        }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_file_name_falls_back_to_the_page_number() {
+        assert_eq!(label_file_name("iv", 4), "iv");
+        assert_eq!(label_file_name("A/1", 4), "A_1");
+        assert_eq!(label_file_name("", 4), "4");
+        assert_eq!(label_file_name(".", 5), "5");
+        assert_eq!(label_file_name("..", 6), "6");
+        assert_eq!(label_file_name(" ... ", 7), "7");
+    }
+}